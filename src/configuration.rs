@@ -1,6 +1,8 @@
 use lazy_static::lazy_static;
-use std::env::args;
+use std::str::FromStr;
 use log::{info};
+use clap::{Arg, ArgMatches};
+use parking_lot::{Mutex};
 
 lazy_static! {
     // Global configuration variable.
@@ -12,6 +14,31 @@ lazy_static! {
     // (e.g. Debug) only the wrapped type does. Hence to print using the Debug
     // format, use `*CONFIGURATION`.
     pub static ref CONFIGURATION: Configuration = Configuration::new();
+    // set by Configuration::install, taken when CONFIGURATION is first read
+    static ref INSTALLED: Mutex<Option<Configuration>> = Mutex::new(None);
+}
+
+/// How DiskFiles are read: positional reads into fresh buffers, or searched
+/// in place through a memory map created when the file is written.
+#[derive(Debug, PartialEq)]
+pub enum ReadMode {
+    PREAD,
+    MMAP,
+}
+
+/// Implementation backing the in-memory buffer.
+#[derive(Debug, PartialEq)]
+pub enum MemtableType {
+    HASH,
+    SKIPLIST,
+}
+
+/// Codec used for the data blocks of a level.
+#[derive(Debug, PartialEq)]
+pub enum CompressionType {
+    NONE,
+    LZ4,
+    DEFLATE,
 }
 
 /// Represents the final, global configuration of the program.
@@ -19,9 +46,9 @@ lazy_static! {
 #[allow(non_snake_case)]
 pub struct Configuration {
     pub BLOCK_SIZE: usize,
+	pub BLOCK_RESTART_INTERVAL: usize, // keys between restart points, which are stored without prefix compression
+	pub INT_BLOCK_ENCODING: bool, // bit-pack blocks of 4-byte keys and values when the comparator is bytewise
 	pub FILE_SIZE: usize,
-	pub KEY_SIZE: usize,
-	pub RECORD_SIZE: usize,
 	pub BUFFER_CAPACITY: usize,
 	pub SIZE_RATIO: usize, // T
     pub RUNS_PER_LEVEL: usize, // K
@@ -29,37 +56,154 @@ pub struct Configuration {
     pub T_OVER_K: f64, // T/K, storing this in configuration to avoid calculating it each time
     pub FULL_THRESHOLD: f64, // if the size/capacity of a run is below the full_threshold, we will merge into the run
 	pub BF_BITS_PER_ENTRY: usize,
+	pub MAX_OPEN_FILES: usize, // bound on the number of file handles kept open for reads
+	pub ROW_CACHE_CAPACITY: usize, // bytes of key-value pairs cached for point lookups, 0 disables the row cache
+	pub READ_MODE: ReadMode,
+	pub DIRECT_IO: bool, // open DiskFiles with O_DIRECT, bypassing the page cache for writes and pread-mode reads
+	pub MEMTABLE: MemtableType, // HASH by default, the skiplist is opt-in
+	pub WAL_ENABLED: bool, // log writes so the memory buffer can be recovered after a crash
+	pub WAL_SYNC: bool, // fsync the log after every write batch
+	pub COMPRESSION: Vec<CompressionType>, // per level starting at level 1, the last entry applies to all deeper levels
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             BLOCK_SIZE: 4096, // 4 KB 
+            BLOCK_RESTART_INTERVAL: 16,
+            INT_BLOCK_ENCODING: false,
             FILE_SIZE: 24576,//24576, // 24 KB 
-            KEY_SIZE: 4,
-            RECORD_SIZE: 8, // 4+4 byte
             BUFFER_CAPACITY: 24576, // 24 KBs
             SIZE_RATIO: 4, 
             RUNS_PER_LEVEL: 1,
             CPUS: 2,
-            T_OVER_K: 1.0, 
-            FULL_THRESHOLD: 0.75,
+            T_OVER_K: 4.0, // SIZE_RATIO / RUNS_PER_LEVEL
+            FULL_THRESHOLD: 0.9,
             BF_BITS_PER_ENTRY: 10,
+            MAX_OPEN_FILES: 512,
+            ROW_CACHE_CAPACITY: 0,
+            READ_MODE: ReadMode::PREAD,
+            DIRECT_IO: false,
+            MEMTABLE: MemtableType::HASH,
+            WAL_ENABLED: false,
+            WAL_SYNC: false,
+            COMPRESSION: vec![CompressionType::NONE],
         }
     }
 }
 
 impl Configuration {
+    // the installed configuration, the defaults if none was
     fn new() -> Self {
-        let mut default = Configuration::default();
-        let args: Vec<String> = args().collect();
-        if args.len() == 3 {
-            default.SIZE_RATIO = args[1].parse::<usize>().unwrap();
-            default.RUNS_PER_LEVEL = args[2].parse::<usize>().unwrap();
+        let configuration = INSTALLED.lock().take().unwrap_or_else(Configuration::fallback);
+        info!("size ratio is {}, runs per level is {}", configuration.SIZE_RATIO, configuration.RUNS_PER_LEVEL);
+        configuration
+    }
+
+    #[cfg(not(test))]
+    fn fallback() -> Self {
+        Configuration::default()
+    }
+
+    // The unit tests share one configuration, so it has the features that are
+    // off by default on, for the tests to cover them.
+    #[cfg(test)]
+    fn fallback() -> Self {
+        Configuration {
+            INT_BLOCK_ENCODING: true,
+            WAL_ENABLED: true,
+            COMPRESSION: vec![CompressionType::NONE, CompressionType::LZ4, CompressionType::DEFLATE],
+            ..Configuration::default()
+        }
+    }
+
+    // Makes configuration the one CONFIGURATION holds. Has to come before
+    // anything reads CONFIGURATION, as the settings cannot change after; the
+    // command line installs the one its flags give, see from_matches.
+    pub fn install(configuration: Configuration) {
+        *INSTALLED.lock() = Some(configuration);
+        lazy_static::initialize(&CONFIGURATION);
+        assert!(INSTALLED.lock().is_none(), "The configuration was installed after it was read");
+    }
+
+    // A flag for each setting, taken by every command. T_OVER_K follows from
+    // --size-ratio and --runs-per-level.
+    pub fn arguments<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        let setting = |name: &'a str, help: &'b str| Arg::with_name(name).long(name).takes_value(true).global(true).help(help);
+        let number = |name: &'a str, help: &'b str| setting(name, help).validator(|value| value.parse::<usize>().map(|_| ()).map_err(|error| error.to_string()));
+        let switch = |name: &'a str, help: &'b str| setting(name, help).possible_values(&["true", "false"]);
+        vec![
+            number("block-size", "Bytes of encoded records per data block"),
+            number("block-restart-interval", "Keys between restart points of a block"),
+            switch("int-block-encoding", "Bit-pack blocks of 4 byte keys and values"),
+            number("file-size", "Bytes of records per table file"),
+            number("buffer-capacity", "Bytes of records the memory buffer holds before a flush"),
+            number("size-ratio", "T, the capacity ratio of adjacent levels"),
+            number("runs-per-level", "K, the runs a level holds"),
+            number("cpus", "Threads replaying workloads"),
+            setting("full-threshold", "Fraction of its capacity past which a run is not merged into")
+                .validator(|value| value.parse::<f64>().map(|_| ()).map_err(|error| error.to_string())),
+            number("bf-bits-per-entry", "Bloom filter bits per key"),
+            number("max-open-files", "File handles kept open for reads"),
+            number("row-cache-capacity", "Bytes of the row cache, 0 disables it"),
+            setting("read-mode", "How table files are read").possible_values(&["pread", "mmap"]),
+            switch("direct-io", "Bypass the page cache with O_DIRECT"),
+            setting("memtable", "Implementation of the memory buffer, hash unless skiplist is given").possible_values(&["skiplist", "hash"]),
+            switch("wal", "Log writes so the memory buffer survives a crash"),
+            switch("wal-sync", "Fsync the log after every write batch"),
+            setting("compression", "Codec of each level from level 1, the last one applies to deeper levels")
+                .use_delimiter(true).possible_values(&["none", "lz4", "deflate"]),
+        ]
+    }
+
+    // the defaults for the settings matches has no flags for
+    pub fn from_matches(matches: &ArgMatches) -> Configuration {
+        // global flags reach the matches of the subcommand they follow
+        let mut matches = matches;
+        while let (_, Some(subcommand_matches)) = matches.subcommand() {
+            matches = subcommand_matches;
         }
-        default.T_OVER_K = default.SIZE_RATIO as f64 / default.RUNS_PER_LEVEL as f64;
-        default.FULL_THRESHOLD = 0.9;
-        info!("size ratio is {}, runs per level is {}", default.SIZE_RATIO, default.RUNS_PER_LEVEL);
-        default
+        let mut configuration = Configuration::default();
+        let switch = |name: &str| matches.value_of(name).map(|value| value == "true");
+        if let Some(value) = parse_value(matches, "block-size") { configuration.BLOCK_SIZE = value; }
+        if let Some(value) = parse_value(matches, "block-restart-interval") { configuration.BLOCK_RESTART_INTERVAL = value; }
+        if let Some(value) = switch("int-block-encoding") { configuration.INT_BLOCK_ENCODING = value; }
+        if let Some(value) = parse_value(matches, "file-size") { configuration.FILE_SIZE = value; }
+        if let Some(value) = parse_value(matches, "buffer-capacity") { configuration.BUFFER_CAPACITY = value; }
+        if let Some(value) = parse_value(matches, "size-ratio") { configuration.SIZE_RATIO = value; }
+        if let Some(value) = parse_value(matches, "runs-per-level") { configuration.RUNS_PER_LEVEL = value; }
+        if let Some(value) = parse_value(matches, "cpus") { configuration.CPUS = value; }
+        if let Some(value) = parse_value(matches, "full-threshold") { configuration.FULL_THRESHOLD = value; }
+        if let Some(value) = parse_value(matches, "bf-bits-per-entry") { configuration.BF_BITS_PER_ENTRY = value; }
+        if let Some(value) = parse_value(matches, "max-open-files") { configuration.MAX_OPEN_FILES = value; }
+        if let Some(value) = parse_value(matches, "row-cache-capacity") { configuration.ROW_CACHE_CAPACITY = value; }
+        match matches.value_of("read-mode") {
+            Some("mmap") => configuration.READ_MODE = ReadMode::MMAP,
+            Some(_) => configuration.READ_MODE = ReadMode::PREAD,
+            None => (),
+        }
+        if let Some(value) = switch("direct-io") { configuration.DIRECT_IO = value; }
+        match matches.value_of("memtable") {
+            Some("hash") => configuration.MEMTABLE = MemtableType::HASH,
+            Some(_) => configuration.MEMTABLE = MemtableType::SKIPLIST,
+            None => (),
+        }
+        if let Some(value) = switch("wal") { configuration.WAL_ENABLED = value; }
+        if let Some(value) = switch("wal-sync") { configuration.WAL_SYNC = value; }
+        if let Some(values) = matches.values_of("compression") {
+            configuration.COMPRESSION = values.map(|value| match value {
+                "lz4" => CompressionType::LZ4,
+                "deflate" => CompressionType::DEFLATE,
+                _ => CompressionType::NONE,
+            }).collect();
+        }
+        assert!(configuration.SIZE_RATIO > 0 && configuration.RUNS_PER_LEVEL > 0, "The size ratio and runs per level must be positive");
+        configuration.T_OVER_K = configuration.SIZE_RATIO as f64 / configuration.RUNS_PER_LEVEL as f64;
+        configuration
     }
-}
\ No newline at end of file
+}
+
+// validated by the flag's validator
+fn parse_value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| value.parse().unwrap_or_else(|_| panic!("Bad value {} for --{}", value, name)))
+}
@@ -12,4 +12,6 @@ pub mod lib_in_memory;
 pub mod lib_test;
 pub mod lib_on_disk;
 pub mod lib_merge;
-pub mod lib_lsm_tree;
\ No newline at end of file
+pub mod lib_lsm_tree;
+pub mod lib_shell;
+pub mod lib_cli;
@@ -0,0 +1,270 @@
+use std::path::{Path};
+use std::sync::{Arc};
+use std::time::{Instant};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{info};
+use prometheus::{TextEncoder, Encoder};
+use threadpool::ThreadPool;
+
+use crate::configuration::{CONFIGURATION, Configuration};
+use crate::lib_helper::{bulkwrite, check_tree_metadata, expected_values, i32_to_bytes, bytes_to_i32, replay_file, workload_options};
+use crate::lib_lsm_tree::LSMTree;
+use crate::lib_on_disk::lib_manifest::{Manifest};
+use crate::lib_on_disk::lib_repair;
+use crate::lib_on_disk::lib_sst_dump::{TableDump};
+use crate::lib_shell;
+use crate::lib_workload;
+
+// The commands of the hybrid_de binary. The settings of Configuration are
+// flags of every command, so they can follow the command's name.
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    let dir = || Arg::with_name("DIR").required(true).help("Tree directory");
+    let int_keys = || Arg::with_name("int-keys").long("int-keys").help("Shows 4 byte keys and values as the integers the workloads write");
+    App::new("hybrid_de")
+        .about("An LSM tree with a tunable size ratio and number of runs per level")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .args(&Configuration::arguments())
+        .subcommand(SubCommand::with_name("gen-workload")
+            .about("Writes a bulkwrite file of b lines and a workload file of p and g lines")
+            .arg(Arg::with_name("workload-file").long("workload-file").takes_value(true).default_value("workload.txt"))
+            .arg(Arg::with_name("bulkwrite-file").long("bulkwrite-file").takes_value(true).default_value("bulkwrite.txt"))
+            .arg(Arg::with_name("bulkwrites").long("bulkwrites").takes_value(true).default_value("1000000").help("Records in the bulkwrite file"))
+            .arg(Arg::with_name("puts").long("puts").takes_value(true).default_value("10000"))
+            .arg(Arg::with_name("gets").long("gets").takes_value(true).default_value("10000"))
+            .arg(Arg::with_name("key-min").long("key-min").takes_value(true).allow_hyphen_values(true).default_value("-2147483648"))
+            .arg(Arg::with_name("key-max").long("key-max").takes_value(true).allow_hyphen_values(true).default_value("2147483647"))
+            .arg(Arg::with_name("skewed-data").long("skewed-data").help("Normally distributed bulkwrite keys instead of uniform ones"))
+            .arg(Arg::with_name("skewed-queries").long("skewed-queries").help("Normally distributed put and get keys instead of uniform ones"))
+            .arg(Arg::with_name("no-result").long("no-result").help("Gets of random keys rather than of keys written before")))
+        .subcommand(SubCommand::with_name("load")
+            .about("Loads a bulkwrite file into a tree")
+            .arg(dir())
+            .arg(Arg::with_name("BULKWRITE_FILE").required(true)))
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs a workload file against a tree, printing the value each get finds")
+            .arg(dir())
+            .arg(Arg::with_name("WORKLOAD_FILE").required(true)))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Times a workload file against a tree")
+            .arg(dir())
+            .arg(Arg::with_name("WORKLOAD_FILE").required(true))
+            .arg(Arg::with_name("bulkwrite-file").long("bulkwrite-file").takes_value(true).help("Loaded first, outside the timing"))
+            .arg(Arg::with_name("metrics").long("metrics").help("Prints the metrics afterwards")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks the block checksums and metadata of a tree, and the values the given files leave it with")
+            .arg(dir())
+            .arg(Arg::with_name("FILES").multiple(true).help("Bulkwrite and workload files in the order they were run, exact when they were run with --cpus 1")))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Prints the levels, runs and files of a tree")
+            .arg(dir())
+            .arg(int_keys()))
+        .subcommand(SubCommand::with_name("layout")
+            .about("Draws the levels, runs and files of a tree by key range, or prints them as JSON")
+            .arg(dir())
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["ascii", "json", "dot"]).default_value("ascii"))
+            .arg(Arg::with_name("width").long("width").takes_value(true).default_value("64").help("Columns of the key range in the ascii format"))
+            .arg(int_keys()))
+        .subcommand(SubCommand::with_name("repair")
+            .about("Rebuilds the manifest of a tree from its table files")
+            .arg(dir()))
+        .subcommand(SubCommand::with_name("sst-dump")
+            .about("Prints the layout of a table file and verifies its block checksums")
+            .arg(Arg::with_name("FILE").required(true).help("Table file"))
+            .arg(Arg::with_name("records").long("records").help("Also prints every record"))
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["text", "json"]).default_value("text"))
+            .arg(int_keys()))
+        .subcommand(SubCommand::with_name("shell")
+            .about("Opens a tree and runs get, put, scan and other commands read from stdin")
+            .arg(dir())
+            .arg(Arg::with_name("int-keys").long("int-keys").help("Reads and shows keys and values as the integers the workloads write")))
+}
+
+// runs the command matches holds, exiting with status 1 if it finds a problem
+pub fn run_command(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("gen-workload", Some(matches)) => gen_workload(matches),
+        ("load", Some(matches)) => load(matches),
+        ("run", Some(matches)) => run(matches),
+        ("bench", Some(matches)) => bench(matches),
+        ("verify", Some(matches)) => verify(matches),
+        ("stats", Some(matches)) => stats(matches),
+        ("layout", Some(matches)) => layout(matches),
+        ("repair", Some(matches)) => repair(matches),
+        ("sst-dump", Some(matches)) => sst_dump(matches),
+        ("shell", Some(matches)) => shell(matches),
+        _ => unreachable!(),
+    }
+}
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    let value = matches.value_of(name).unwrap();
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Bad value {} for {}", value, name);
+        std::process::exit(1);
+    })
+}
+
+fn open_tree(matches: &ArgMatches) -> LSMTree {
+    exit_on_error(LSMTree::try_open(matches.value_of("DIR").unwrap()))
+}
+
+// Trees the workloads write may hold merge operands, which only read back and
+// compact with the workloads' merge operator.
+fn open_workload_tree(matches: &ArgMatches) -> LSMTree {
+    exit_on_error(LSMTree::try_open_with_options(matches.value_of("DIR").unwrap(), workload_options()))
+}
+
+fn exit_on_error(tree: Result<LSMTree, String>) -> LSMTree {
+    tree.unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    })
+}
+
+fn gen_workload(matches: &ArgMatches) {
+    let mut workload_file = matches.value_of("workload-file").unwrap().to_string();
+    let mut bulkwrite_file = matches.value_of("bulkwrite-file").unwrap().to_string();
+    lib_workload::set_workload_specifications(!matches.is_present("skewed-data"), !matches.is_present("skewed-queries"), !matches.is_present("no-result"),
+        number(matches, "bulkwrites"), number(matches, "puts"), number(matches, "gets"), number(matches, "key-min"), number(matches, "key-max"),
+        &mut workload_file, &mut bulkwrite_file);
+    println!("wrote {} and {}", bulkwrite_file, workload_file);
+}
+
+fn load(matches: &ArgMatches) {
+    let mut tree = open_workload_tree(matches);
+    let start = Instant::now();
+    bulkwrite(&matches.value_of("BULKWRITE_FILE").unwrap().to_string(), &mut tree);
+    println!("loaded in {:?}", start.elapsed());
+}
+
+fn run(matches: &ArgMatches) {
+    let tree = Arc::new(open_workload_tree(matches));
+    let pool = ThreadPool::new(CONFIGURATION.CPUS);
+    replay_file(matches.value_of("WORKLOAD_FILE").unwrap(), tree, &pool, true);
+}
+
+fn bench(matches: &ArgMatches) {
+    let mut tree = open_workload_tree(matches);
+    if let Some(bulkwrite_file) = matches.value_of("bulkwrite-file") {
+        bulkwrite(&bulkwrite_file.to_string(), &mut tree);
+    }
+    let pool = ThreadPool::new(CONFIGURATION.CPUS);
+    let start = Instant::now();
+    let num_ops = replay_file(matches.value_of("WORKLOAD_FILE").unwrap(), Arc::new(tree), &pool, false);
+    let duration = start.elapsed();
+    info!("Time elapsed is: {:?}", duration);
+    println!("{} operations in {:?}, {:.0} per second", num_ops, duration, num_ops as f64 / duration.as_secs_f64());
+    if matches.is_present("metrics") {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap();
+        println!("{}", String::from_utf8(buffer).unwrap());
+    }
+}
+
+// The blocks of the files the manifest lists are checked before the tree is
+// opened, as opening it and check_tree_metadata panic on a damaged one.
+fn verify(matches: &ArgMatches) {
+    let dir = matches.value_of("DIR").unwrap();
+    let manifest = match Manifest::read(dir) {
+        Some(manifest) => manifest,
+        None => {
+            eprintln!("{} holds no tree, or its manifest is damaged", dir);
+            std::process::exit(1);
+        },
+    };
+    let mut problems = 0;
+    let filenames: Vec<String> = manifest.levels.iter().flat_map(|level| level.runs.iter()).flat_map(|run| run.files.iter())
+        .map(|name| Path::new(dir).join(name).to_string_lossy().to_string())
+        .collect();
+    for filename in filenames.iter() {
+        match TableDump::read_table(filename) {
+            Some(dump) if dump.damaged_blocks() == 0 => (),
+            Some(dump) => {
+                println!("{}: {} of {} blocks fail their checksums", filename, dump.damaged_blocks(), dump.blocks.len());
+                problems += 1;
+            },
+            None => {
+                println!("{}: missing, or its footer is damaged", filename);
+                problems += 1;
+            },
+        }
+    }
+    println!("checked the blocks of {} files", filenames.len());
+    if problems > 0 {
+        println!("the repair command rebuilds the tree without the damaged blocks");
+        std::process::exit(1);
+    }
+    let tree = open_workload_tree(matches);
+    check_tree_metadata(&tree);
+    println!("metadata consistent");
+
+    let files: Vec<String> = matches.values_of("FILES").map_or(Vec::new(), |files| files.map(|file| file.to_string()).collect());
+    if files.is_empty() {
+        return;
+    }
+    let expected = expected_values(&files);
+    for (key, value) in expected.iter() {
+        let mut found = Vec::new();
+        let matched = tree.get(&i32_to_bytes(*key), &mut found) && bytes_to_i32(&found) == *value;
+        if !matched {
+            if problems < 10 {
+                println!("key {}: expected {}, found {}", key, value, if found.is_empty() { "nothing".to_string() } else { bytes_to_i32(&found).to_string() });
+            }
+            problems += 1;
+        }
+    }
+    println!("{} of {} keys hold the expected values", expected.len() - problems, expected.len());
+    if problems > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn stats(matches: &ArgMatches) {
+    let tree = open_tree(matches);
+    lib_shell::print_stats(&tree);
+    lib_shell::print_levels(&tree, matches.is_present("int-keys"));
+}
+
+fn layout(matches: &ArgMatches) {
+    let layout = open_tree(matches).layout();
+    let int_keys = matches.is_present("int-keys");
+    match matches.value_of("format") {
+        Some("json") => println!("{}", layout.to_json(int_keys)),
+        Some("dot") => print!("{}", layout.to_dot(int_keys)),
+        _ => print!("{}", layout.to_ascii(std::cmp::max(number(matches, "width"), 1), int_keys)),
+    }
+}
+
+fn repair(matches: &ArgMatches) {
+    let report = lib_repair::repair(matches.value_of("DIR").unwrap());
+    println!("recovered {} files whole, salvaged {}, lost {}", report.files_recovered, report.files_salvaged, report.files_lost);
+    println!("{} records in {} levels, last sequence number {}", report.records_recovered, report.levels, report.last_seq);
+    for lost in report.lost.iter() {
+        println!("lost {}", lost.describe());
+    }
+}
+
+fn sst_dump(matches: &ArgMatches) {
+    let filename = matches.value_of("FILE").unwrap();
+    let dump = match TableDump::read_table(filename) {
+        Some(dump) => dump,
+        None => {
+            eprintln!("{} is not a table file, or its footer is damaged", filename);
+            std::process::exit(1);
+        },
+    };
+    match matches.value_of("format") {
+        Some("json") => dump.print_json(matches.is_present("records"), matches.is_present("int-keys")),
+        _ => dump.print_text(matches.is_present("records"), matches.is_present("int-keys")),
+    }
+    if dump.damaged_blocks() > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn shell(matches: &ArgMatches) {
+    let tree = open_workload_tree(matches);
+    let interactive = unsafe { libc::isatty(0) } == 1;
+    let stdin = std::io::stdin();
+    lib_shell::run_shell(&tree, stdin.lock(), interactive, matches.is_present("int-keys"));
+}
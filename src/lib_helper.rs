@@ -4,23 +4,32 @@ use log::{info, error, debug};
 use std::convert::TryInto;
 use std::io::{BufReader, Error};
 use std::io::prelude::*;
-use std::fs::File;
+use std::fs::{self, File};
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::{Reverse};
+use std::path::{Path};
 use std::time::{Instant};
 use std::sync::{Arc};
-use atomic_counter::{AtomicCounter};
 use threadpool::ThreadPool;
-use super::configuration::CONFIGURATION;
-use super::lib_template::{Record};
+use super::lib_template::{Record, compare_keys, IntAddOperator};
 use super::lib_lsm_tree::LSMTree;
-use super::lib_on_disk::lib_disk_level::DiskLevel;
-use super::lib_on_disk::lib_disk_run::Run;
+use super::lib_lsm_tree::lib_options::TreeOptions;
+use super::lib_on_disk::lib_disk_file::DiskFile;
+use super::lib_on_disk::lib_sst_writer::SstWriter;
+use super::lib_lsm_tree::lib_ingest::IngestOptions;
+use super::lib_on_disk::lib_compression::compressor_for_level;
+use super::lib_on_disk::lib_file_cache::{FILE_HANDLE_CACHE};
+
+// lines of a bulkwrite file sorted in memory at once
+const BULKWRITE_CHUNK_RECORDS: usize = 1 << 20;
+pub const TEMPORARY_SUFFIX: &str = ".tmp";
 
 pub fn parse_instruction(mut _instruction: String) -> (String, i32, i32)
 {
 	let mut _key = 0;
 	let mut _value = 0;
 	let op_code = &_instruction[0..2];
-	if op_code.trim() == "b" || op_code.trim() == "p" || op_code.trim() == "r"
+	if op_code.trim() == "b" || op_code.trim() == "p" || op_code.trim() == "r" || op_code.trim() == "a"
 	{
 		let slice = &_instruction[2..];
 		let index: usize = match slice.find(' ')
@@ -56,9 +65,12 @@ pub fn check_tree_metadata(lsm_tree: &LSMTree) {
 	let buffer = lsm_tree.buffer.read();
 	info!("BUFFER SIZE {}: CAPACITY {}: FULLNESS {}", buffer.size(), buffer.capacity(), buffer.size() as f64 / buffer.capacity() as f64);
 	drop(buffer);
+	if let Some(row_cache) = &lsm_tree.row_cache {
+		row_cache.print_stats();
+	}
 	let levels = lsm_tree.levels.read();
 	for level in levels.iter() {
-		info!("LEVEL {}: SIZE {}: CAPACITY {}: FULLNESS {}", level.level, level.size(), level.capacity(), level.size() as f64 / level.capacity() as f64);
+		info!("LEVEL {}: SIZE {}: CAPACITY {}: FULLNESS {}: COMPRESSION {} RATIO {}", level.level, level.size(), level.capacity(), level.size() as f64 / level.capacity() as f64, compressor_for_level(level.level).name(), level.compression_ratio());
 		let mut run_size = 0;
 		let runs = level.runs.read();
         for run in runs.iter() {
@@ -68,26 +80,33 @@ pub fn check_tree_metadata(lsm_tree: &LSMTree) {
 				let file = &run.files[i];
 				let file_records = file.read_all_file_records();
 				assert!(IsSorted::is_sorted(&mut file_records.iter()));
-				assert!(file.fence_pointers[0] == run.fence_pointers[i]);
-				for j in (0..file_records.len()).step_by(CONFIGURATION.BLOCK_SIZE / CONFIGURATION.RECORD_SIZE) {
-					assert!(file_records[j].key == file.fence_pointers[j / (CONFIGURATION.BLOCK_SIZE / CONFIGURATION.RECORD_SIZE)]);
+				assert!(file.first_key == run.fence_pointers[i]);
+				for j in 0..file.fence_pointers.len() {
+					assert!(file.read_block(j)[0].key == file.fence_pointers[j]);
 				}
-				assert!(IsSorted::is_sorted(&mut file.fence_pointers.iter()));
+				assert!(IsSorted::is_sorted_by(&mut file.fence_pointers.iter(), |a, b| Some(compare_keys(a, b))));
 			}
-			assert!(IsSorted::is_sorted(&mut run.fence_pointers.iter()));
+			assert!(IsSorted::is_sorted_by(&mut run.fence_pointers.iter(), |a, b| Some(compare_keys(a, b))));
         }
         assert!(run_size == level.size());
 	}
 }
 
+// "a key value" adds value to the i32 counter at key
+pub fn workload_options() -> TreeOptions {
+	let mut options = TreeOptions::create_options();
+	options.merge_operator = Some(Arc::new(IntAddOperator));
+	options
+}
+
 pub fn run(bulkwrite_file: &String, workload_file: &String, pool: ThreadPool) {
-	let mut lsm_tree = LSMTree::create_lsmtree();
+	let mut lsm_tree = LSMTree::create_lsmtree_with_options(workload_options());
 	bulkwrite(bulkwrite_file, &mut lsm_tree);
 	run_file(workload_file, lsm_tree, pool);
 }
 
 pub fn run_with_time(bulkwrite_file: &String, workload_file: &String, pool: ThreadPool) {
-	let mut lsm_tree: LSMTree = LSMTree::create_lsmtree();
+	let mut lsm_tree: LSMTree = LSMTree::create_lsmtree_with_options(workload_options());
 	bulkwrite(bulkwrite_file, &mut lsm_tree);
 	let start = Instant::now();
 	run_file_for_benchmark(workload_file, lsm_tree, pool);
@@ -97,132 +116,257 @@ pub fn run_with_time(bulkwrite_file: &String, workload_file: &String, pool: Thre
 
 // Useful for checking behavior of LSM Tree
 pub fn run_file(_workload_file: &String, lsm_tree: LSMTree, pool: ThreadPool) {
-	let wl = File::open(_workload_file).expect("Error in opening workload file!");
-	let wl = BufReader::new(wl);
 	let lsm_tree = Arc::new(lsm_tree);
-	let mut _num_misses = 0;
-    for line in wl.lines() {
-		let lsm_tree = lsm_tree.clone();
-		let (op_code, key, value) = parse_instruction(line.unwrap());
-
-		pool.execute(move || {
-			match op_code.trim() {
-				"b" | "p" => lsm_tree.put(&key, &value),
-				"g" => {
-					let mut val = 0;
-					if lsm_tree.get(&key, &mut val) {
-						print!("{}\n", val);
-					} else {
-						_num_misses += 1;
-						println!("");
-					}
-				}
-				_ => error!("ERROR, BAD OPCODE")
-			}
-		})
-	}
-	pool.join();
+	replay_file(_workload_file, lsm_tree.clone(), &pool, true);
 	check_tree_metadata(&lsm_tree);
 	lsm_tree.delete_files();
-	//debug!("failed gets: {}", num_misses);
 	//lsm_tree.print_stats();
 }
 
 // No prints for benchmarking
 pub fn run_file_for_benchmark(_workload_file: &String, lsm_tree: LSMTree, pool: ThreadPool) {
-	let wl = File::open(_workload_file).expect("Error in opening workload file!");
-	let wl = BufReader::new(wl);
 	let lsm_tree = Arc::new(lsm_tree);
-	
+	replay_file(_workload_file, lsm_tree.clone(), &pool, false);
+	check_tree_metadata(&lsm_tree);
+	lsm_tree.delete_files();
+}
+
+// Runs the lines of a workload file against the tree on the pool's threads, and
+// returns how many there were. The value each get finds is printed if
+// print_gets is set, an empty line if it finds none.
+pub fn replay_file(workload_file: &str, lsm_tree: Arc<LSMTree>, pool: &ThreadPool, print_gets: bool) -> usize {
+	let wl = File::open(workload_file).expect("Error in opening workload file!");
+	let wl = BufReader::new(wl);
+	let mut num_ops = 0;
     for line in wl.lines() {
 		let lsm_tree = lsm_tree.clone();
 		let (op_code, key, value) = parse_instruction(line.unwrap());
+		num_ops += 1;
 
 		pool.execute(move || {
 			match op_code.trim() {
-				"b" | "p" => lsm_tree.put(&key, &value),
+				"b" | "p" => lsm_tree.put(&i32_to_bytes(key), &i32_to_bytes(value)),
+				"a" => lsm_tree.merge(&i32_to_bytes(key), &i32_to_bytes(value)),
 				"g" => {
-					let mut val = 0;
-					lsm_tree.get(&key, &mut val);
+					let mut val = Vec::new();
+					let found = lsm_tree.get(&i32_to_bytes(key), &mut val);
+					if print_gets {
+						match found {
+							true => println!("{}", bytes_to_i32(&val)),
+							false => println!(),
+						}
+					}
 				}
 				_ => error!("ERROR, BAD OPCODE")
 			}
 		})
 	}
 	pool.join();
-	check_tree_metadata(&lsm_tree);
-	lsm_tree.delete_files();
+	num_ops
+}
+
+// The value each key holds once the files are run in order, as run_file would
+// leave it, for checking a tree loaded and run with them.
+pub fn expected_values(files: &[String]) -> HashMap<i32, i32> {
+	let mut values = HashMap::new();
+	for file in files.iter() {
+		let br = BufReader::new(File::open(file).unwrap_or_else(|_| panic!("Error in opening file {}!", file)));
+		for line in br.lines() {
+			let (op_code, key, value) = parse_instruction(line.unwrap());
+			match op_code.trim() {
+				"b" | "p" => {
+					values.insert(key, value);
+				},
+				"a" => {
+					let sum = values.get(&key).cloned().unwrap_or(0i32).wrapping_add(value);
+					values.insert(key, sum);
+				},
+				_ => (),
+			}
+		}
+	}
+	values
 }
 
-fn create_record_from_line(line: Result<String, Error>) -> Record {
+fn create_record_from_line(line: Result<String, Error>, seq: u64) -> Record {
 	let (op_code, key, value) = parse_instruction(line.unwrap().to_string());
 	assert!(op_code.trim() == "b");
-	Record::create_record(key, value)
+	Record::create_record(i32_to_bytes(key), i32_to_bytes(value), seq)
 }
 
+// Loads the "b key value" lines of the file with an external sort, so the file
+// never has to fit in memory: chunks of it are sorted and written as table files,
+// then the chunks are merged into one sorted set of files and ingested. Later
+// lines win over earlier ones for the same key.
 pub fn bulkwrite(bulkwrite_file: &String, lsm_tree: &mut LSMTree) {
 	let br = File::open(bulkwrite_file).expect("Error in opening bulkwrite file!");
 	let br = BufReader::new(br);
-	let v = &mut br.lines().map(|l| create_record_from_line(l)).collect::<Vec<_>>();
-	v.reverse();
-	let num_records = v.len();
-	let mut records_read = 0;
-	let last_level: usize = ((num_records * CONFIGURATION.RECORD_SIZE * (CONFIGURATION.SIZE_RATIO - 1)) as f64 / (CONFIGURATION.BUFFER_CAPACITY * CONFIGURATION.SIZE_RATIO) as f64).ceil().log(CONFIGURATION.SIZE_RATIO as f64).ceil() as usize;
-	debug!("last level is {}", last_level);
-	let mut level: usize = 0;
-	let mut run = 0;
-	let mut levels = lsm_tree.levels.write();
-	while records_read < num_records {
-		if run == 0 {
-			level += 1;
-			let run_capacity = CONFIGURATION.BUFFER_CAPACITY * CONFIGURATION.SIZE_RATIO.pow(level as u32) / CONFIGURATION.RUNS_PER_LEVEL;
-			levels.push(DiskLevel::empty_level(run_capacity, level))
+	let tmp_dir = Path::new(&lsm_tree.dir).join("bulkwrite.tmp").to_string_lossy().to_string();
+	let mut chunks: Vec<Vec<String>> = Vec::new();
+	let mut records = Vec::new();
+	for (i, line) in br.lines().enumerate() {
+		// line numbers order the versions of a key within a chunk
+		records.push(create_record_from_line(line, i as u64));
+		if records.len() == BULKWRITE_CHUNK_RECORDS {
+			let chunk_dir = Path::new(&tmp_dir).join(chunks.len().to_string()).to_string_lossy().to_string();
+			chunks.push(write_sorted_chunk(std::mem::take(&mut records), &chunk_dir));
+		}
+	}
+	if !records.is_empty() {
+		let chunk_dir = Path::new(&tmp_dir).join(chunks.len().to_string()).to_string_lossy().to_string();
+		chunks.push(write_sorted_chunk(records, &chunk_dir));
+	}
+	debug!("bulkwrite sorted {} chunks", chunks.len());
+	// the sorted files are only needed by the tree, so they move into it
+	let options = IngestOptions { move_files: true };
+	match chunks.len() {
+		0 => (),
+		1 => lsm_tree.ingest_with_options(&chunks[0], &options),
+		_ => lsm_tree.ingest_with_options(&merge_sorted_chunks(&chunks, &Path::new(&tmp_dir).join("merged").to_string_lossy()), &options),
+	}
+	if Path::new(&tmp_dir).exists() {
+		fs::remove_dir_all(&tmp_dir).unwrap_or_else(|error| panic!("Failed to remove {}: {}", tmp_dir, error));
+	}
+}
+
+// the newest version of each key, written in key order
+fn write_sorted_chunk(mut records: Vec<Record>, dir: &str) -> Vec<String> {
+	records.sort();
+	records.dedup_by(|a, b| a.key == b.key);
+	let mut writer = SstWriter::create_writer(dir);
+	for record in records.iter() {
+		writer.put(&record.key, &record.value);
+	}
+	writer.finish()
+}
+
+// Merges chunks with one file of each in memory at a time. Of the versions of a
+// key, the one of the latest chunk wins.
+fn merge_sorted_chunks(chunks: &[Vec<String>], dir: &str) -> Vec<String> {
+	// the chunk's index stands in for the sequence number, so later chunks sort first
+	let read_file = |chunk: usize, file_idx: usize| -> Vec<Record> {
+		let mut records = DiskFile::open_disk_file(chunks[chunk][file_idx].clone()).read_all_file_records();
+		for record in records.iter_mut() {
+			record.seq = chunk as u64;
 		}
-		let run_capacity = CONFIGURATION.BUFFER_CAPACITY * CONFIGURATION.SIZE_RATIO.pow(level as u32) / CONFIGURATION.RUNS_PER_LEVEL;
-		let max_run_records = run_capacity / CONFIGURATION.RECORD_SIZE;
-		let records_to_read = std::cmp::min(num_records - records_read, max_run_records);
-		let current = &mut v[records_read..records_read + records_to_read].to_vec();
-		current.sort_by(|a, b| a.key.cmp(&b.key));
-		current.dedup_by(|a, b| a.key == b.key);
-		debug!("creating run {} at level {} with size {}", CONFIGURATION.RUNS_PER_LEVEL - 1 - run, level, current.len() * CONFIGURATION.RECORD_SIZE);
-		let mut runs = levels[level - 1].runs.write();
-		runs.insert(0, Run::create_run(current.len() * CONFIGURATION.RECORD_SIZE, run_capacity, &records_to_bytes(current), level, run));
-		levels[level - 1].run_counter.inc();
-		debug!("run counter at level {} is {}", level, levels[level - 1].run_counter.get());
-		levels[level - 1].add_size(current.len() * CONFIGURATION.RECORD_SIZE);
-		run = (run + 1) % CONFIGURATION.RUNS_PER_LEVEL;
-		records_read += records_to_read;
+		records.reverse();
+		records
+	};
+	let mut pending: Vec<Vec<Record>> = Vec::new(); // records of each chunk's current file, last one first
+	let mut next_file: Vec<usize> = Vec::new();
+	let mut heap = BinaryHeap::new();
+	for chunk in 0..chunks.len() {
+		let mut records = read_file(chunk, 0);
+		heap.push(Reverse(records.pop().unwrap()));
+		pending.push(records);
+		next_file.push(1);
 	}
-	assert!(records_read == num_records);
+	let mut writer = SstWriter::create_writer(dir);
+	let mut last_key: Option<Vec<u8>> = None;
+	while let Some(Reverse(record)) = heap.pop() {
+		let chunk = record.seq as usize;
+		if pending[chunk].is_empty() && next_file[chunk] < chunks[chunk].len() {
+			pending[chunk] = read_file(chunk, next_file[chunk]);
+			next_file[chunk] += 1;
+		}
+		if let Some(next) = pending[chunk].pop() {
+			heap.push(Reverse(next));
+		}
+		if last_key.as_ref() != Some(&record.key) {
+			writer.put(&record.key, &record.value);
+			last_key = Some(record.key);
+		}
+	}
+	writer.finish()
+}
+
+pub fn generate_filename(dir: &str, level: usize, run: usize, file_idx: usize) -> String {
+	let name = level.to_string() + "." + &run.to_string() + "." + &file_idx.to_string();
+	Path::new(dir).join(name).to_string_lossy().to_string()
 }
 
-pub fn generate_filename(level: usize, run: usize, file_idx: usize) -> String {
-	level.to_string() + "." + &run.to_string() + "." + &file_idx.to_string()
+// whether name is one generate_filename gives, level.run.file
+pub fn is_table_filename(name: &str) -> bool {
+	let parts: Vec<&str> = name.split('.').collect();
+	parts.len() == 3 && parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
 }
 
-pub fn bytes_to_records(bytes: &[u8]) -> Vec<Record> {
-	let mut records: Vec<Record> = Vec::new();
-	for i in (0..bytes.len()).step_by(CONFIGURATION.RECORD_SIZE) {
-		let boundary = i + CONFIGURATION.KEY_SIZE;
-		let key = i32::from_be_bytes(bytes[i..boundary].try_into().unwrap());
-		let value = i32::from_be_bytes(bytes[boundary..i + CONFIGURATION.RECORD_SIZE].try_into().unwrap());
-		records.push(Record::create_record(key, value));
+// names of the table files in dir, sorted
+pub fn table_filenames(dir: &str) -> Vec<String> {
+	let mut names: Vec<String> = fs::read_dir(dir).unwrap_or_else(|error| panic!("Failed to list tree directory {}: {}", dir, error))
+		.map(|entry| entry.unwrap_or_else(|error| panic!("Failed to list tree directory {}: {}", dir, error)).file_name().to_string_lossy().to_string())
+		.filter(|name| is_table_filename(name))
+		.collect();
+	names.sort();
+	names
+}
+
+// name a file is written under until it is complete, see install_file
+pub fn temporary_filename(filename: &str) -> String {
+	filename.to_string() + TEMPORARY_SUFFIX
+}
+
+// Renames a complete, synced file into place and syncs the directory, so that
+// after a crash the file is either missing or whole under its name. A handle
+// cached for an earlier file of that name would read the old data.
+pub fn install_file(tmp_filename: &str, filename: &str) {
+	fs::rename(tmp_filename, filename).unwrap_or_else(|error| panic!("Failed to rename {} to {}: {}", tmp_filename, filename, error));
+	FILE_HANDLE_CACHE.evict(&filename.to_string());
+	sync_directory(Path::new(filename).parent().unwrap_or(Path::new(".")));
+}
+
+pub fn sync_directory(dir: &Path) {
+	let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+	File::open(dir).and_then(|dir| dir.sync_all()).unwrap_or_else(|error| panic!("Failed to sync directory {:?}: {}", dir, error));
+}
+
+// The workload files hold i32 keys and values. Flipping the sign bit of the
+// big-endian encoding makes bytewise order match numeric order.
+pub fn i32_to_bytes(x: i32) -> Vec<u8> {
+	((x as u32) ^ 0x8000_0000).to_be_bytes().to_vec()
+}
+
+pub fn bytes_to_i32(bytes: &[u8]) -> i32 {
+	(u32::from_be_bytes(bytes[..4].try_into().unwrap()) ^ 0x8000_0000) as i32
+}
+
+// Printable keys are shown as text, anything else as hex
+pub fn bytes_to_string(bytes: &[u8]) -> String {
+	if !bytes.is_empty() && bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+		return String::from_utf8_lossy(bytes).to_string();
 	}
-	records
+	let mut hex = "0x".to_string();
+	for b in bytes.iter() {
+		hex += &format!("{:02x}", b);
+	}
+	hex
 }
 
-pub fn records_to_bytes(records: &Vec<Record>) -> Vec<u8> {
-	let mut bytes = Vec::new();
-	for record in records.iter() {
-		bytes.extend(&record.key.to_be_bytes());
-		bytes.extend(&record.value.to_be_bytes());
+// 4 byte keys and values as the i32s the workloads write if int_keys is set, as bytes_to_string otherwise
+pub fn format_bytes(bytes: &[u8], int_keys: bool) -> String {
+	match int_keys && bytes.len() == 4 {
+		true => bytes_to_i32(bytes).to_string(),
+		false => bytes_to_string(bytes),
+	}
+}
+
+// s as a quoted JSON string
+pub fn json_string(s: &str) -> String {
+	let mut out = "\"".to_string();
+	for c in s.chars() {
+		match c {
+			'"' => out += "\\\"",
+			'\\' => out += "\\\\",
+			c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+			c => out.push(c),
+		}
 	}
-	bytes
+	out + "\""
 }
 
-pub fn binary_search_fp(fence_pointers: &Vec<i32>, key: &i32) -> Option<usize> {
+pub fn binary_search_fp(fence_pointers: &[Vec<u8>], key: &[u8]) -> Option<usize> {
 	// If not found, binary search will return error with index it can be inserted in
-	let fp_idx = fence_pointers.binary_search(&key);
+	let fp_idx = fence_pointers.binary_search_by(|fp| compare_keys(fp, key));
 	let fp_idx = match fp_idx {
 		Err(other_idx) => {
 			if other_idx == 0 {
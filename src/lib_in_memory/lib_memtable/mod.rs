@@ -0,0 +1,99 @@
+use std::collections::{HashMap};
+use std::cmp::Ordering;
+use parking_lot::{RwLock};
+
+use crate::lib_template::{Record, compare_keys};
+
+/// In-memory write buffer holding every version put, each tagged with its
+/// sequence number. Inserts take `&self` so that implementations which
+/// support concurrent writers can be used behind a shared lock; `clear` needs
+/// exclusive access and is only called once the contents have been flushed.
+pub trait Memtable: Send + Sync {
+	fn put(&self, record: Record);
+	// newest version of key with a sequence number <= seq
+	fn get(&self, key: &[u8], seq: u64) -> Option<Record>;
+	// all versions of the keys with lower <= key <= upper, sorted by key, newest first
+	fn range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record>;
+	// all versions, sorted by key, newest first
+	fn sorted_records(&self) -> Vec<Record>;
+	fn len(&self) -> usize;
+	fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	// bytes the records take once encoded
+	fn size(&self) -> usize;
+	fn clear(&mut self);
+}
+
+struct HashState {
+	map: HashMap<Vec<u8>, Vec<Record>>, // key -> versions, newest first
+	len: usize, // versions in map
+	size: usize, // encoded bytes of all versions in map
+}
+
+/// The original buffer: a HashMap, sorted on every flush or range query.
+pub struct HashMemtable {
+	state: RwLock<HashState>,
+}
+
+impl HashMemtable {
+	pub fn create_memtable() -> HashMemtable {
+		HashMemtable {
+			state: RwLock::new(HashState {
+				map: HashMap::new(),
+				len: 0,
+				size: 0,
+			}),
+		}
+	}
+
+	fn collect(map: &HashMap<Vec<u8>, Vec<Record>>, filter: impl Fn(&[u8]) -> bool) -> Vec<Record> {
+		let mut records: Vec<Record> = map.iter()
+			.filter(|(key, _)| filter(key))
+			.flat_map(|(_, versions)| versions.iter().cloned())
+			.collect();
+		records.sort();
+		records
+	}
+}
+
+impl Memtable for HashMemtable {
+	fn put(&self, record: Record) {
+		let mut state = self.state.write();
+		state.len += 1;
+		state.size += record.size();
+		let versions = state.map.entry(record.key.clone()).or_default();
+		// writers may take the lock out of sequence number order
+		let idx = versions.iter().position(|version| version.seq < record.seq).unwrap_or(versions.len());
+		versions.insert(idx, record);
+	}
+
+	fn get(&self, key: &[u8], seq: u64) -> Option<Record> {
+		let state = self.state.read();
+		let versions = state.map.get(key)?;
+		versions.iter().find(|version| version.seq <= seq).cloned()
+	}
+
+	fn range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+		HashMemtable::collect(&self.state.read().map, |key| compare_keys(key, lower) != Ordering::Less && compare_keys(key, upper) != Ordering::Greater)
+	}
+
+	fn sorted_records(&self) -> Vec<Record> {
+		HashMemtable::collect(&self.state.read().map, |_| true)
+	}
+
+	fn len(&self) -> usize {
+		self.state.read().len
+	}
+
+	fn size(&self) -> usize {
+		self.state.read().size
+	}
+
+	fn clear(&mut self) {
+		let mut state = self.state.write();
+		state.map.clear();
+		state.len = 0;
+		state.size = 0;
+	}
+}
@@ -0,0 +1,161 @@
+use log::{info};
+use std::collections::{HashMap, BTreeMap};
+use std::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use std::cmp::Ordering as KeyOrdering;
+use parking_lot::{Mutex};
+
+use crate::lib_template::{compare_keys};
+use crate::lib_on_disk::lib_block::{entry_size};
+use crate::metrics::{ROW_CACHE_HITS, ROW_CACHE_MISSES, ROW_CACHE_CAPACITY, ROW_CACHE_USAGE};
+
+struct RowCacheState {
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>, // key -> (value, last access tick)
+    lru: BTreeMap<u64, Vec<u8>>, // last access tick -> key, oldest first
+    tick: u64,
+    usage: usize, // bytes charged for the cached entries
+}
+
+/// LRU cache of key-value pairs found on disk, consulted by `LSMTree::get`
+/// before searching the levels. Only keys that are not in the buffer are cached.
+pub struct RowCache {
+    capacity: usize, // bytes
+    epoch: AtomicUsize, // bumped on every invalidation
+    state: Mutex<RowCacheState>,
+    // lookups of this cache, the metrics add up those of every tree's
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RowCache {
+    pub fn create_row_cache(capacity: usize) -> RowCache {
+        ROW_CACHE_CAPACITY.set(capacity as i64);
+        RowCache {
+            capacity,
+            epoch: AtomicUsize::new(0),
+            state: Mutex::new(RowCacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                usage: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Readers take the epoch before looking anywhere, and pass it back to insert.
+    // If a write invalidated the cache in between, the value found may be stale.
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.state.lock();
+        let tick = state.tick;
+        let value = match state.entries.get_mut(key) {
+            Some(entry) => {
+                let old_tick = entry.1;
+                entry.1 = tick;
+                Some((entry.0.clone(), old_tick))
+            }
+            None => None,
+        };
+        match value {
+            Some((value, old_tick)) => {
+                state.lru.remove(&old_tick);
+                state.lru.insert(tick, key.to_vec());
+                state.tick += 1;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                ROW_CACHE_HITS.inc();
+                Some(value)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                ROW_CACHE_MISSES.inc();
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8], epoch: usize) {
+        let mut state = self.state.lock();
+        if self.epoch() != epoch {
+            return;
+        }
+        let tick = state.tick;
+        state.tick += 1;
+        state.usage += RowCache::charge(key, value);
+        if let Some((old_value, old_tick)) = state.entries.insert(key.to_vec(), (value.to_vec(), tick)) {
+            state.lru.remove(&old_tick);
+            state.usage -= RowCache::charge(key, &old_value);
+        }
+        state.lru.insert(tick, key.to_vec());
+        while state.usage > self.capacity {
+            let oldest = *state.lru.keys().next().unwrap();
+            let victim = state.lru.remove(&oldest).unwrap();
+            let (victim_value, _) = state.entries.remove(&victim).unwrap();
+            state.usage -= RowCache::charge(&victim, &victim_value);
+        }
+        ROW_CACHE_USAGE.set(state.usage as i64);
+    }
+
+    pub fn invalidate(&self, key: &[u8]) {
+        let mut state = self.state.lock();
+        self.epoch.fetch_add(1, Ordering::Release);
+        if let Some((old_value, old_tick)) = state.entries.remove(key) {
+            state.lru.remove(&old_tick);
+            state.usage -= RowCache::charge(key, &old_value);
+        }
+        ROW_CACHE_USAGE.set(state.usage as i64);
+    }
+
+    // drops the cached keys in [lower, upper]
+    pub fn invalidate_range(&self, lower: &[u8], upper: &[u8]) {
+        let mut state = self.state.lock();
+        self.epoch.fetch_add(1, Ordering::Release);
+        let keys: Vec<Vec<u8>> = state.entries.keys()
+            .filter(|key| compare_keys(key, lower) != KeyOrdering::Less && compare_keys(key, upper) != KeyOrdering::Greater)
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            let (old_value, old_tick) = state.entries.remove(key).unwrap();
+            state.lru.remove(&old_tick);
+            state.usage -= RowCache::charge(key, &old_value);
+        }
+        ROW_CACHE_USAGE.set(state.usage as i64);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().usage
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.hits() as f64;
+        let lookups = hits + self.misses() as f64;
+        if lookups == 0.0 {
+            return 0.0;
+        }
+        hits / lookups
+    }
+
+    pub fn print_stats(&self) {
+        info!("ROW CACHE SIZE {}: CAPACITY {}: HITS {}: MISSES {}: HIT RATIO {}", self.size(), self.capacity(), self.hits(), self.misses(), self.hit_ratio());
+    }
+
+    // private helper function, charges an entry as a block would hold it
+    fn charge(key: &[u8], value: &[u8]) -> usize {
+        entry_size(0, key.len(), value.len(), 0, 0)
+    }
+}
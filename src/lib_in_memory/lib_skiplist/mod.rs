@@ -0,0 +1,187 @@
+use rand::Rng;
+use std::cmp::Ordering as KeyOrdering;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::lib_template::{Record, Comparator, comparator};
+use super::lib_memtable::{Memtable};
+
+const MAX_HEIGHT: usize = 12;
+
+struct Node {
+	record: Record,
+	next: Vec<AtomicPtr<Node>>, // one successor per level of the node's tower
+}
+
+impl Node {
+	fn new(record: Record, height: usize) -> Node {
+		let mut next = Vec::with_capacity(height);
+		for _ in 0..height {
+			next.push(AtomicPtr::new(ptr::null_mut()));
+		}
+		Node {
+			record,
+			next,
+		}
+	}
+}
+
+// nodes are ordered by key, then newest version first
+fn compare_node(cmp: &dyn Comparator, node: &Node, key: &[u8], seq: u64) -> KeyOrdering {
+	cmp.compare(&node.record.key, key).then_with(|| seq.cmp(&node.record.seq))
+}
+
+/// Ordered memtable holding every version put. Inserts are lock-free: a node is
+/// published by a CAS on its level-0 predecessor, then linked into the upper
+/// levels one CAS at a time. Nodes are never freed while readers may hold a
+/// shared reference to the buffer; they are only freed by `clear` (exclusive
+/// access) or on drop.
+pub struct SkipListMemtable {
+	head: Box<Node>, // sentinel, MAX_HEIGHT levels
+	len: AtomicUsize,
+	size: AtomicUsize, // encoded bytes of all records
+}
+
+impl SkipListMemtable {
+	pub fn create_memtable() -> SkipListMemtable {
+		SkipListMemtable {
+			head: Box::new(Node::new(Record::create_record(Vec::new(), Vec::new(), 0), MAX_HEIGHT)),
+			len: AtomicUsize::new(0),
+			size: AtomicUsize::new(0),
+		}
+	}
+
+	// each level holds ~1/4 of the nodes of the level below it
+	fn random_height() -> usize {
+		let mut rng = rand::thread_rng();
+		let mut height = 1;
+		while height < MAX_HEIGHT && rng.gen_range(0, 4) == 0 {
+			height += 1;
+		}
+		height
+	}
+
+	// Fills preds/succs with, at every level, the last node before (key, seq)
+	// and the node following it.
+	fn find(&self, cmp: &dyn Comparator, key: &[u8], seq: u64, preds: &mut [*const Node; MAX_HEIGHT], succs: &mut [*mut Node; MAX_HEIGHT]) {
+		let mut pred: *const Node = &*self.head;
+		for level in (0..MAX_HEIGHT).rev() {
+			let mut curr = unsafe { (&*pred).next[level].load(Ordering::Acquire) };
+			while !curr.is_null() && compare_node(cmp, unsafe { &*curr }, key, seq) == KeyOrdering::Less {
+				pred = curr;
+				curr = unsafe { (&*curr).next[level].load(Ordering::Acquire) };
+			}
+			preds[level] = pred;
+			succs[level] = curr;
+		}
+	}
+
+	// first node at or after (key, seq)
+	fn seek(&self, cmp: &dyn Comparator, key: &[u8], seq: u64) -> *mut Node {
+		let mut preds = [ptr::null(); MAX_HEIGHT];
+		let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+		self.find(cmp, key, seq, &mut preds, &mut succs);
+		succs[0]
+	}
+
+	fn collect_from(&self, cmp: &dyn Comparator, mut curr: *mut Node, upper: Option<&[u8]>) -> Vec<Record> {
+		let mut records = Vec::new();
+		while !curr.is_null() {
+			let node = unsafe { &*curr };
+			if let Some(upper) = upper {
+				if cmp.compare(&node.record.key, upper) == KeyOrdering::Greater {
+					break;
+				}
+			}
+			records.push(node.record.clone());
+			curr = node.next[0].load(Ordering::Acquire);
+		}
+		records
+	}
+
+	fn free_nodes(&mut self) {
+		let mut curr = self.head.next[0].load(Ordering::Acquire);
+		while !curr.is_null() {
+			let node = unsafe { Box::from_raw(curr) };
+			curr = node.next[0].load(Ordering::Acquire);
+		}
+		for level in 0..MAX_HEIGHT {
+			self.head.next[level].store(ptr::null_mut(), Ordering::Release);
+		}
+		self.len.store(0, Ordering::Release);
+		self.size.store(0, Ordering::Release);
+	}
+}
+
+impl Memtable for SkipListMemtable {
+	fn put(&self, record: Record) {
+		let cmp = comparator();
+		let mut preds = [ptr::null(); MAX_HEIGHT];
+		let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+		let height = SkipListMemtable::random_height();
+		let record_size = record.size();
+		let node = Box::into_raw(Box::new(Node::new(record, height)));
+		let (key, seq) = unsafe { (&(&*node).record.key[..], (*node).record.seq) };
+		loop {
+			self.find(&*cmp, key, seq, &mut preds, &mut succs);
+			for (level, succ) in succs.iter().enumerate().take(height) {
+				unsafe { (&*node).next[level].store(*succ, Ordering::Relaxed) };
+			}
+			// the node becomes visible once it is linked at level 0
+			let pred = unsafe { &*preds[0] };
+			if pred.next[0].compare_exchange(succs[0], node, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+				break;
+			}
+		}
+		for level in 1..height {
+			loop {
+				let pred = unsafe { &*preds[level] };
+				if pred.next[level].compare_exchange(succs[level], node, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+					break;
+				}
+				// a concurrent insert changed this level, search again
+				self.find(&*cmp, key, seq, &mut preds, &mut succs);
+				unsafe { (&*node).next[level].store(succs[level], Ordering::Release) };
+			}
+		}
+		self.len.fetch_add(1, Ordering::AcqRel);
+		self.size.fetch_add(record_size, Ordering::AcqRel);
+	}
+
+	fn get(&self, key: &[u8], seq: u64) -> Option<Record> {
+		let cmp = comparator();
+		let node = self.seek(&*cmp, key, seq);
+		if !node.is_null() && cmp.compare(unsafe { &(*node).record.key }, key) == KeyOrdering::Equal {
+			return Some(unsafe { (*node).record.clone() });
+		}
+		None
+	}
+
+	fn range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+		let cmp = comparator();
+		self.collect_from(&*cmp, self.seek(&*cmp, lower, u64::MAX), Some(upper))
+	}
+
+	fn sorted_records(&self) -> Vec<Record> {
+		let cmp = comparator();
+		self.collect_from(&*cmp, self.head.next[0].load(Ordering::Acquire), None)
+	}
+
+	fn len(&self) -> usize {
+		self.len.load(Ordering::Acquire)
+	}
+
+	fn size(&self) -> usize {
+		self.size.load(Ordering::Acquire)
+	}
+
+	fn clear(&mut self) {
+		self.free_nodes();
+	}
+}
+
+impl Drop for SkipListMemtable {
+	fn drop(&mut self) {
+		self.free_nodes();
+	}
+}
@@ -1,63 +1,96 @@
+pub mod lib_row_cache;
+pub mod lib_memtable;
+pub mod lib_skiplist;
+
 //use std::mem;
 use log::{debug};
 
-use std::collections::{HashMap, HashSet};
-use std::i32;
-use crate::configuration::CONFIGURATION;
-use super::lib_template::{Record};
+use std::collections::{HashSet};
+use parking_lot::{Mutex};
+use crate::configuration::{CONFIGURATION, MemtableType};
+use super::lib_template::{Record, covering_tombstone_seq};
+use self::lib_memtable::{Memtable, HashMemtable};
+use self::lib_skiplist::{SkipListMemtable};
 
-use super::lib_helper::{bytes_to_records};
+use super::lib_helper::{bytes_to_string};
 
 #[allow(non_upper_case_globals)]
 
 pub struct MemoryBuffer {
-	pub buffer_size: usize,
-	pub buffer: HashMap<i32, i32>,
+	pub buffer_size: usize, // bytes of encoded records the buffer holds before it is flushed
+	pub buffer: Box<dyn Memtable>,
+	range_tombstones: Mutex<Vec<Record>>, // kept apart from point records, which the memtables index by key
 	level: usize,
 }
 
 impl MemoryBuffer {
 	pub fn create_buffer() -> MemoryBuffer {
 		MemoryBuffer {
-			buffer_size: CONFIGURATION.BUFFER_CAPACITY/CONFIGURATION.RECORD_SIZE, // #elements in the in-memory buffer
-			buffer: HashMap::new(),
+			buffer_size: CONFIGURATION.BUFFER_CAPACITY,
+			buffer: match CONFIGURATION.MEMTABLE {
+				MemtableType::HASH => Box::new(HashMemtable::create_memtable()),
+				MemtableType::SKIPLIST => Box::new(SkipListMemtable::create_memtable()),
+			},
+			range_tombstones: Mutex::new(Vec::new()),
 			level: 0,
 		}
 	}
 
-	pub fn put(&mut self, key: &i32, value: &i32) {
-		self.buffer.insert(*key, *value);
+	pub fn put(&self, record: Record) {
+		if record.is_range_deletion() {
+			self.range_tombstones.lock().push(record);
+			return;
+		}
+		self.buffer.put(record);
+	}
+
+	// sequence number of the newest range tombstone visible at seq covering key, or 0
+	pub fn range_tombstone_seq(&self, key: &[u8], seq: u64) -> u64 {
+		covering_tombstone_seq(self.range_tombstones.lock().iter(), key, seq)
+	}
+
+	pub fn range_tombstones(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+		self.range_tombstones.lock().iter().filter(|tombstone| tombstone.overlaps(lower, upper)).cloned().collect()
 	}
 
-	pub fn get(&self, key: &i32, record: &mut Record) -> bool {
-		match self.buffer.get(key) {
-			Some(&value) => {
-				*record = Record::create_record(*key, value);
+	pub fn get(&self, key: &[u8], seq: u64, record: &mut Record) -> bool {
+		match self.buffer.get(key, seq) {
+			Some(found) => {
+				*record = found;
 				return true;
 			}
 			_ => return false,
 		}
 	}
 
+	// The memtable hands out its records sorted, so only the range tombstones,
+	// few and in arrival order, are sorted before the two are merged.
 	pub fn merge(&self) -> Vec<Record> {
-		let mut records = Vec::new();
-		let mut buffer_data: Vec<_> = self.buffer.clone().into_iter().collect();
-		buffer_data.sort();
-		for (key, value) in buffer_data.iter() {
-			records.push(Record::create_record(*key, *value));
+		let records = self.buffer.sorted_records();
+		let mut tombstones = self.range_tombstones.lock().clone();
+		tombstones.sort();
+		let mut merged = Vec::with_capacity(records.len() + tombstones.len());
+		let mut tombstones = tombstones.into_iter().peekable();
+		for record in records.into_iter() {
+			while let Some(tombstone) = tombstones.next_if(|tombstone| *tombstone < record) {
+				merged.push(tombstone);
+			}
+			merged.push(record);
 		}
-		records
+		merged.extend(tombstones);
+		merged
 	}
 
-	pub fn flush(&mut self, data: Vec<u8>, _capacity: usize) {
-		let records = bytes_to_records(&data);
-		for record in records.iter() {
-			self.buffer.insert(record.key, record.value);
-		}
+	pub fn find_range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+		self.buffer.range(lower, upper)
 	}
 
 	pub fn size(&self) -> usize {
-		self.buffer.len() * CONFIGURATION.RECORD_SIZE
+		self.buffer.size() + self.tombstones_size()
+	}
+
+	fn tombstones_size(&self) -> usize {
+		self.range_tombstones.lock().iter().map(|tombstone| tombstone.size()).sum()
 	}
 
 	pub fn capacity(&self) -> usize {
@@ -69,18 +102,19 @@ impl MemoryBuffer {
 	}
 
     	pub fn is_full(&self) -> bool {
-		assert!(self.buffer.len() <= self.buffer_size);
-		self.buffer.len() >= self.buffer_size
+		// concurrent writers may overshoot buffer_size before one of them flushes
+		self.size() >= self.buffer_size
 	}
 
 	pub fn clear(&mut self) { 
 		self.buffer.clear();
+		self.range_tombstones.lock().clear();
 	}
 
-	pub fn print_stats(&self, distinct_keys: &mut HashSet<i32>) {
-		for (key, &value) in self.buffer.iter() {
-			debug!("{}:{}:L{}", key, value, self.level);
-			distinct_keys.insert(*key);
+	pub fn print_stats(&self, distinct_keys: &mut HashSet<Vec<u8>>) {
+		for record in self.buffer.sorted_records().iter() {
+			debug!("{}:{}:L{}", bytes_to_string(&record.key), bytes_to_string(&record.value), self.level);
+			distinct_keys.insert(record.key.clone());
 		}
 		debug!("\n");
 	}
@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path};
+use std::sync::{Arc};
+
+use crate::lib_helper::{is_table_filename, temporary_filename, install_file};
+use crate::lib_on_disk::lib_disk_file::{DiskFile};
+use crate::lib_on_disk::lib_manifest::{Manifest};
+use crate::lib_on_disk::lib_wal::{WAL_FILENAME};
+use super::LSMTree;
+
+// Lists the table files of a backup, one "name crc global_seq" line per file,
+// with the crc32 of its blocks as recorded in its footer. Names are reused once
+// a tree is created again, so a file is only taken for the one in the backup
+// when its checksum and global sequence number match too.
+pub const BACKUP_CHECKSUMS_FILENAME: &str = "CHECKSUMS";
+
+// writes bytes to filename through a synced temporary file
+fn write_file(filename: &str, bytes: &[u8]) {
+    let tmp_filename = temporary_filename(filename);
+    let mut file = File::create(&tmp_filename).unwrap_or_else(|error| panic!("Failed to create {}: {}", tmp_filename, error));
+    file.write_all(bytes).unwrap_or_else(|error| panic!("Failed to write {}: {}", tmp_filename, error));
+    file.sync_all().unwrap_or_else(|error| panic!("Failed to sync {}: {}", tmp_filename, error));
+    install_file(&tmp_filename, filename);
+}
+
+// checksums recorded by the last backup to dest, empty if it has none or they
+// cannot be read, in which case every file is copied again
+fn read_checksums(dest: &str) -> HashMap<String, (u32, u64)> {
+    let text = fs::read_to_string(Path::new(dest).join(BACKUP_CHECKSUMS_FILENAME)).unwrap_or_default();
+    text.lines().filter_map(|line| {
+        let mut parts = line.split(' ');
+        let name = parts.next()?.to_string();
+        let checksum = parts.next()?.parse().ok()?;
+        let global_seq = parts.next()?.parse().ok()?;
+        Some((name, (checksum, global_seq)))
+    }).collect()
+}
+
+/// The tree at one point in time: its manifest, the files the manifest lists
+/// and the log of the writes since. Compactions cannot remove the files from
+/// disk while it is held.
+struct PinnedVersion {
+    manifest: Manifest,
+    files: Vec<Arc<DiskFile>>,
+    log: Vec<u8>,
+}
+
+impl PinnedVersion {
+    // The log goes first and the manifest last, so dest only holds a tree once
+    // the copy is complete.
+    fn install(&self, dest: &str) {
+        write_file(&Path::new(dest).join(WAL_FILENAME).to_string_lossy(), &self.log);
+        self.manifest.write(dest);
+    }
+}
+
+/// What an incremental backup copied.
+pub struct BackupReport {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub files_skipped: usize, // already in the backup from an earlier run
+    pub files_removed: usize, // left by an earlier run, no longer part of the tree
+}
+
+impl LSMTree {
+    // Holding the buffer's write lock keeps writers and flushes out, so the log
+    // holds exactly the writes that are not in the files yet.
+    fn pin_version(&self) -> PinnedVersion {
+        let mut buffer = self.buffer.write();
+        if self.wal.is_none() && buffer.size() > 0 {
+            // without a log, the buffer only reaches the copy through a flush
+            self.flush_buffer_with_guard(buffer);
+            buffer = self.buffer.write();
+        }
+        let levels = self.levels.read();
+        let (manifest, files) = self.current_version(&levels);
+        let log = match &self.wal {
+            Some(wal) => wal.contents(),
+            None => Vec::new(),
+        };
+        drop(levels);
+        drop(buffer);
+        PinnedVersion {
+            manifest,
+            files,
+            log,
+        }
+    }
+
+    // Copy of the tree that LSMTree::open reads back, taken while writes go on.
+    // The files are hard linked, so dest has to be on the same file system.
+    pub fn checkpoint(&self, dest: &str) {
+        assert!(!Manifest::exists(dest), "Checkpoint directory {} already holds a tree", dest);
+        fs::create_dir_all(dest).unwrap_or_else(|error| panic!("Failed to create checkpoint directory {}: {}", dest, error));
+        let version = self.pin_version();
+        for file in version.files.iter() {
+            let link = Path::new(dest).join(file.name());
+            fs::hard_link(&file.filename, &link).unwrap_or_else(|error| panic!("Failed to link {} to {:?}: {}", file.filename, link, error));
+        }
+        version.install(dest);
+    }
+
+    // Brings the backup in dest up to date with the tree. Files are immutable,
+    // so only the ones not in dest yet are copied: those whose name is not in
+    // the backup's checksums, or is listed with another checksum, as a tree
+    // created again numbers its files from the start. The checksums come from
+    // the footers, so files already backed up are not read.
+    pub fn backup(&self, dest: &str) -> BackupReport {
+        fs::create_dir_all(dest).unwrap_or_else(|error| panic!("Failed to create backup directory {}: {}", dest, error));
+        let version = self.pin_version();
+        let mut report = BackupReport {
+            files_copied: 0,
+            bytes_copied: 0,
+            files_skipped: 0,
+            files_removed: 0,
+        };
+        let copied = read_checksums(dest);
+        let mut checksums = String::new();
+        for file in version.files.iter() {
+            checksums += &format!("{} {} {}\n", file.name(), file.checksum, file.global_seq);
+            let copy = Path::new(dest).join(file.name()).to_string_lossy().to_string();
+            if copied.get(file.name()) == Some(&(file.checksum, file.global_seq)) && Path::new(&copy).exists() {
+                report.files_skipped += 1;
+                continue;
+            }
+            let tmp_copy = temporary_filename(&copy);
+            report.bytes_copied += fs::copy(&file.filename, &tmp_copy).unwrap_or_else(|error| panic!("Failed to copy {} to {}: {}", file.filename, tmp_copy, error));
+            File::open(&tmp_copy).and_then(|copy| copy.sync_all()).unwrap_or_else(|error| panic!("Failed to sync {}: {}", tmp_copy, error));
+            install_file(&tmp_copy, &copy);
+            report.files_copied += 1;
+        }
+        // before the manifest, so it never lists a file without its checksum
+        write_file(&Path::new(dest).join(BACKUP_CHECKSUMS_FILENAME).to_string_lossy(), checksums.as_bytes());
+        version.install(dest);
+
+        let live: HashSet<String> = version.manifest.files().into_iter().collect();
+        for entry in fs::read_dir(dest).unwrap_or_else(|error| panic!("Failed to list backup directory {}: {}", dest, error)) {
+            let path = entry.unwrap_or_else(|error| panic!("Failed to list backup directory {}: {}", dest, error)).path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string();
+            if is_table_filename(&name) && !live.contains(&name) {
+                fs::remove_file(&path).unwrap_or_else(|error| panic!("Failed to remove {:?} from backup: {}", path, error));
+                report.files_removed += 1;
+            }
+        }
+        report
+    }
+}
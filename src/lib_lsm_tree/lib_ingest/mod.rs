@@ -0,0 +1,186 @@
+use std::cmp::Ordering as KeyOrdering;
+use std::fs::{self, File};
+use std::path::{Path};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use atomic_counter::{AtomicCounter};
+
+use crate::configuration::CONFIGURATION;
+use crate::lib_helper::{generate_filename, temporary_filename, install_file, sync_directory};
+use crate::lib_template::{compare_keys};
+use crate::lib_on_disk::lib_disk_file::{DiskFile};
+use crate::lib_on_disk::lib_disk_level::{DiskLevel};
+use crate::lib_on_disk::lib_disk_run::{Run};
+use crate::lib_on_disk::lib_file_cache::{FILE_HANDLE_CACHE};
+use super::LSMTree;
+
+// numbers the staged copies of each ingest apart
+static NEXT_INGEST: AtomicUsize = AtomicUsize::new(0);
+
+pub struct IngestOptions {
+    // Hard-link the files into the tree directory and remove them once ingested,
+    // instead of copying them, so they belong to the tree from the start. Falls
+    // back to a copy across file systems.
+    pub move_files: bool,
+}
+
+impl IngestOptions {
+    pub fn create_options() -> IngestOptions {
+        IngestOptions {
+            move_files: false,
+        }
+    }
+}
+
+impl LSMTree {
+    pub fn ingest(&self, filenames: &[String]) {
+        self.ingest_with_options(filenames, &IngestOptions::create_options());
+    }
+
+    // Adds files written by an SstWriter to the tree, as one write newer than
+    // every write before it. The files are copied, or linked, into the tree
+    // directory first and only the copies are changed, so the caller's files are
+    // left as they were. The copies become a run of the deepest level that
+    // neither it nor a level above holds keys of, without being rewritten, and
+    // that level is merged down if the run overfills it. If level 1 already
+    // holds some keys, they are merged into it like a flush instead.
+    pub fn ingest_with_options(&self, filenames: &[String], options: &IngestOptions) {
+        let _comparator = self.enter_comparator();
+        assert!(!filenames.is_empty(), "Nothing to ingest");
+        let staged = self.stage_files(filenames, options.move_files);
+        let mut files: Vec<DiskFile> = staged.iter().map(|filename| DiskFile::open_disk_file(filename.clone())).collect();
+        if let Err(message) = check_ingested_files(&mut files, &staged, filenames) {
+            drop(files);
+            for filename in staged.iter() {
+                FILE_HANDLE_CACHE.evict(filename);
+                let _ = fs::remove_file(filename);
+            }
+            panic!("{}", message);
+        }
+        let size: usize = files.iter().map(|file| file.size).sum();
+        let key_ranges: Vec<(Vec<u8>, Vec<u8>)> = files.iter().map(|file| (file.first_key.clone(), file.last_key.clone())).collect();
+
+        // a compaction moving data between levels would invalidate the choice of level
+        while self.compacting.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now();
+        }
+        // The log is replayed from the manifest's sequence number, which the files
+        // move past, so no write older than them may be left in the buffer.
+        let mut buffer = self.buffer.write();
+        while buffer.size() > 0 {
+            self.flush_buffer_with_guard(buffer);
+            buffer = self.buffer.write();
+        }
+        let seq = self.sequence.fetch_add(1, Ordering::AcqRel) + 1;
+        for file in files.iter_mut() {
+            file.set_global_seq(seq);
+        }
+
+        let levels = self.levels.read();
+        let first_overlap = levels.iter().position(|level| files.iter().any(|file| level.overlaps(&file.first_key, &file.last_key)));
+        let ingested_level_idx = match first_overlap {
+            Some(0) => {
+                let capacity_of_run = levels[0].capacity() / CONFIGURATION.RUNS_PER_LEVEL;
+                let context = self.compaction_context(levels.len() == 1);
+                // merged into the runs of the level, so not needed once the merge is done
+                let files: Vec<Arc<DiskFile>> = files.into_iter().map(Arc::new).collect();
+                for file in files.iter() {
+                    file.mark_obsolete();
+                }
+                levels[0].flush(vec![files], size, std::cmp::max(CONFIGURATION.FILE_SIZE, capacity_of_run), capacity_of_run, &context);
+                drop(levels);
+                self.invalidate_filtered(&context);
+                0
+            },
+            Some(level_idx) => {
+                self.add_ingested_run(&levels[level_idx - 1], files, size);
+                drop(levels);
+                level_idx - 1
+            },
+            None => {
+                drop(levels);
+                // below the last level if the files do not fit in one of its runs
+                let mut levels = self.levels.write();
+                while levels.last().is_none_or(|level| level.capacity() / CONFIGURATION.RUNS_PER_LEVEL < size) {
+                    let prev_capacity = levels.last().map_or(buffer.capacity(), |level| level.capacity());
+                    let capacity_of_run = (prev_capacity as f64 * CONFIGURATION.T_OVER_K) as usize;
+                    let level_no = levels.len() + 1;
+                    levels.push(DiskLevel::empty_level(capacity_of_run, level_no, &self.dir));
+                }
+                self.add_ingested_run(levels.last().unwrap(), files, size);
+                levels.len() - 1
+            },
+        };
+        self.flushed_seq.store(seq, Ordering::Release);
+        self.write_manifest();
+        self.publish(seq, seq);
+        // after publishing, so a read that caches an older value is turned away
+        if let Some(row_cache) = &self.row_cache {
+            for (lower, upper) in key_ranges.iter() {
+                row_cache.invalidate_range(lower, upper);
+            }
+        }
+        drop(buffer);
+        if options.move_files {
+            for filename in filenames.iter() {
+                fs::remove_file(filename).unwrap_or_else(|error| panic!("Failed to remove ingested file {}: {}", filename, error));
+            }
+        }
+        // a run more than the level holds is merged down as after a flush
+        self.merge_levels_from(ingested_level_idx);
+        self.compacting.store(false, Ordering::Relaxed);
+        self.compaction();
+    }
+
+    // Copies, or links, the files into the tree directory under temporary names,
+    // which orphan removal clears away if the tree goes down before they are
+    // installed.
+    fn stage_files(&self, filenames: &[String], link: bool) -> Vec<String> {
+        let ingest = NEXT_INGEST.fetch_add(1, Ordering::Relaxed);
+        let staged: Vec<String> = filenames.iter().enumerate().map(|(idx, filename)| {
+            // level 0 is the buffer, so no table file of the tree has this name
+            let staged = temporary_filename(&generate_filename(&self.dir, 0, ingest, idx));
+            // a hard link only works within one file system
+            if !(link && fs::hard_link(filename, &staged).is_ok()) {
+                fs::copy(filename, &staged).unwrap_or_else(|error| panic!("Failed to copy {} to {}: {}", filename, staged, error));
+                File::open(&staged).and_then(|copy| copy.sync_all()).unwrap_or_else(|error| panic!("Failed to sync {}: {}", staged, error));
+            }
+            staged
+        }).collect();
+        sync_directory(Path::new(&self.dir));
+        staged
+    }
+
+    // installs the staged files as a new run of level
+    fn add_ingested_run(&self, level: &DiskLevel, files: Vec<DiskFile>, size: usize) {
+        let run = level.run_counter.get();
+        level.run_counter.inc();
+        let files: Vec<Arc<DiskFile>> = files.into_iter().enumerate().map(|(idx, mut file)| {
+            let filename = generate_filename(&self.dir, level.level, run, idx);
+            FILE_HANDLE_CACHE.evict(&file.filename);
+            install_file(&file.filename, &filename);
+            file.filename = filename;
+            Arc::new(file)
+        }).collect();
+        let capacity_of_run = level.capacity() / CONFIGURATION.RUNS_PER_LEVEL;
+        level.add_run(Run::create_run_from_files(size, capacity_of_run, files, level.level, run, &self.dir));
+    }
+}
+
+// Sorts the staged copies of filenames by key and checks they make one run.
+// Errors name the caller's files rather than the copies.
+fn check_ingested_files(files: &mut [DiskFile], staged: &[String], filenames: &[String]) -> Result<(), String> {
+    let original = |file: &DiskFile| &filenames[staged.iter().position(|staged| *staged == file.filename).unwrap()];
+    for file in files.iter() {
+        if file.max_seq != 0 {
+            return Err(format!("File {} was not written by an SstWriter, or was ingested already", original(file)));
+        }
+    }
+    files.sort_by(|a, b| compare_keys(&a.first_key, &b.first_key));
+    for pair in files.windows(2) {
+        if compare_keys(&pair[0].last_key, &pair[1].first_key) != KeyOrdering::Less {
+            return Err(format!("Files {} and {} overlap", original(&pair[0]), original(&pair[1])));
+        }
+    }
+    Ok(())
+}
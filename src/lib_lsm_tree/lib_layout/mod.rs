@@ -0,0 +1,215 @@
+use std::sync::atomic::{Ordering};
+
+use crate::lib_helper::{format_bytes, json_string};
+use crate::lib_template::{compare_keys};
+use crate::lib_on_disk::lib_compression::{compressor_for_level};
+use super::LSMTree;
+
+pub struct FileLayout {
+    pub name: String,
+    pub size: usize, // bytes of records
+    pub disk_size: usize, // bytes of the blocks on disk
+    pub blocks: usize, // data blocks
+    pub range_tombstones: usize,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub min_seq: u64,
+    pub max_seq: u64,
+}
+
+pub struct RunLayout {
+    pub run: usize,
+    pub size: usize,
+    pub capacity: usize,
+    pub files: Vec<FileLayout>, // in key order
+}
+
+pub struct LevelLayout {
+    pub level: usize,
+    pub size: usize,
+    pub capacity: usize,
+    pub compression: String,
+    pub compression_ratio: f64,
+    pub runs: Vec<RunLayout>, // oldest first
+}
+
+/// The shape of a tree at one point in time, for dashboards and for looking
+/// at by eye: the buffer, then the levels with their runs and files.
+pub struct TreeLayout {
+    pub last_seq: u64,
+    pub buffer_size: usize,
+    pub buffer_capacity: usize,
+    pub levels: Vec<LevelLayout>,
+}
+
+impl LSMTree {
+    // The buffer lock is held while the levels are read, as by checkpoints, so
+    // no flush moves data between them meanwhile.
+    pub fn layout(&self) -> TreeLayout {
+        let buffer = self.buffer.read();
+        let levels = self.levels.read();
+        let layout = TreeLayout {
+            last_seq: self.visible_seq.load(Ordering::Acquire),
+            buffer_size: buffer.size(),
+            buffer_capacity: buffer.capacity(),
+            levels: levels.iter().map(|level| LevelLayout {
+                level: level.level,
+                size: level.size(),
+                capacity: level.capacity(),
+                compression: compressor_for_level(level.level).name().to_string(),
+                compression_ratio: level.compression_ratio(),
+                runs: level.runs.read().iter().map(|run| RunLayout {
+                    run: run.run,
+                    size: run.size,
+                    capacity: run.capacity,
+                    files: run.files.iter().map(|file| FileLayout {
+                        name: file.name().to_string(),
+                        size: file.size,
+                        disk_size: file.disk_size,
+                        blocks: file.block_offsets.len(),
+                        range_tombstones: file.range_tombstones.len(),
+                        first_key: file.first_key.clone(),
+                        last_key: file.last_key.clone(),
+                        min_seq: file.min_seq,
+                        max_seq: file.max_seq,
+                    }).collect(),
+                }).collect(),
+            }).collect(),
+        };
+        drop(levels);
+        drop(buffer);
+        layout
+    }
+}
+
+impl TreeLayout {
+    // smallest and largest key of any file, None if there are none
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        let files = || self.levels.iter().flat_map(|level| level.runs.iter()).flat_map(|run| run.files.iter());
+        let first = files().map(|file| &file.first_key[..]).min_by(|a, b| compare_keys(a, b))?;
+        let last = files().map(|file| &file.last_key[..]).max_by(|a, b| compare_keys(a, b))?;
+        Some((first, last))
+    }
+
+    // Where key lies between lower and upper, from 0 to 1. Keys are read as
+    // numbers from their first 8 bytes, which keeps the order of the bytewise
+    // comparator, and of i32_to_bytes keys in particular.
+    fn position(key: &[u8], lower: &[u8], upper: &[u8]) -> f64 {
+        let number = |key: &[u8]| {
+            let mut bytes = [0u8; 8];
+            let len = std::cmp::min(key.len(), 8);
+            bytes[..len].copy_from_slice(&key[..len]);
+            u64::from_be_bytes(bytes) as f64
+        };
+        let (key, lower, upper) = (number(key), number(lower), number(upper));
+        match upper > lower {
+            true => ((key - lower) / (upper - lower)).clamp(0.0, 1.0),
+            false => 0.0,
+        }
+    }
+
+    pub fn to_json(&self, int_keys: bool) -> String {
+        let key = |bytes: &[u8]| json_string(&format_bytes(bytes, int_keys));
+        let levels: Vec<String> = self.levels.iter().map(|level| {
+            let runs: Vec<String> = level.runs.iter().map(|run| {
+                let files: Vec<String> = run.files.iter().map(|file| {
+                    format!("{{\"name\":{},\"size\":{},\"disk_size\":{},\"blocks\":{},\"range_tombstones\":{},\"first_key\":{},\"last_key\":{},\"min_seq\":{},\"max_seq\":{}}}",
+                        json_string(&file.name), file.size, file.disk_size, file.blocks, file.range_tombstones, key(&file.first_key), key(&file.last_key), file.min_seq, file.max_seq)
+                }).collect();
+                format!("{{\"run\":{},\"size\":{},\"capacity\":{},\"fullness\":{:.4},\"files\":[{}]}}", run.run, run.size, run.capacity, fullness(run.size, run.capacity), files.join(","))
+            }).collect();
+            format!("{{\"level\":{},\"size\":{},\"capacity\":{},\"fullness\":{:.4},\"compression\":{},\"compression_ratio\":{:.4},\"runs\":[{}]}}",
+                level.level, level.size, level.capacity, fullness(level.size, level.capacity), json_string(&level.compression), level.compression_ratio, runs.join(","))
+        }).collect();
+        format!("{{\"last_seq\":{},\"buffer\":{{\"size\":{},\"capacity\":{},\"fullness\":{:.4}}},\"levels\":[{}]}}",
+            self.last_seq, self.buffer_size, self.buffer_capacity, fullness(self.buffer_size, self.buffer_capacity), levels.join(","))
+    }
+
+    // Files as boxes placed by their key range, one row per run and the levels
+    // top to bottom. The positions are fixed, so render it with neato -n, e.g.
+    // neato -n -Tsvg tree.dot > tree.svg.
+    pub fn to_dot(&self, int_keys: bool) -> String {
+        const WIDTH: f64 = 1440.0; // points across the whole key range
+        const ROW: i64 = 36; // points between the rows of runs
+        let show = |bytes: &[u8]| format_bytes(bytes, int_keys).replace('"', "\\\"");
+        let mut out = "digraph tree {\n    node [shape=box, fixedsize=true, fontsize=8, height=0.3];\n".to_string();
+        let (lower, upper) = match self.key_range() {
+            Some((lower, upper)) => (lower.to_vec(), upper.to_vec()),
+            None => (Vec::new(), Vec::new()),
+        };
+        let mut row = 0;
+        for level in self.levels.iter() {
+            out += &format!("    level{} [shape=plaintext, label=\"level {}\\n{:.0}% full\", pos=\"-80,{}!\", width=1];\n",
+                level.level, level.level, 100.0 * fullness(level.size, level.capacity), -row * ROW);
+            // newest run on top, as reads visit it first
+            for run in level.runs.iter().rev() {
+                for file in run.files.iter() {
+                    let start = TreeLayout::position(&file.first_key, &lower, &upper) * WIDTH;
+                    let end = TreeLayout::position(&file.last_key, &lower, &upper) * WIDTH;
+                    // node positions are centers, widths are in inches
+                    let width = (end - start).max(4.0);
+                    out += &format!("    \"{}\" [label=\"{}\", tooltip=\"{} .. {}, {} bytes\", pos=\"{:.1},{}!\", width={:.3}];\n",
+                        file.name, file.name, show(&file.first_key), show(&file.last_key), file.size, start + width / 2.0, -row * ROW, width / 72.0);
+                }
+                row += 1;
+            }
+            if level.runs.is_empty() {
+                row += 1;
+            }
+        }
+        out += "}\n";
+        out
+    }
+
+    // One line per level with its fullness, then a line per run marking the
+    // key range of its files across width columns. Adjacent files alternate
+    // between # and =, so their boundaries show.
+    pub fn to_ascii(&self, width: usize, int_keys: bool) -> String {
+        let mut out = format!("buffer   {:>8} of {:>8}  {}\n", human_size(self.buffer_size), human_size(self.buffer_capacity), bar(fullness(self.buffer_size, self.buffer_capacity), 10));
+        let (lower, upper) = match self.key_range() {
+            Some((lower, upper)) => (lower.to_vec(), upper.to_vec()),
+            None => return out,
+        };
+        for level in self.levels.iter() {
+            out += &format!("level {:<2} {:>8} of {:>8}  {}  {}, {} runs\n", level.level, human_size(level.size), human_size(level.capacity),
+                bar(fullness(level.size, level.capacity), 10), level.compression, level.runs.len());
+            for run in level.runs.iter().rev() {
+                let mut line = vec![' '; width];
+                for (idx, file) in run.files.iter().enumerate() {
+                    let column = |key: &[u8]| std::cmp::min((TreeLayout::position(key, &lower, &upper) * width as f64) as usize, width - 1);
+                    for cell in line[column(&file.first_key)..=column(&file.last_key)].iter_mut() {
+                        *cell = if idx % 2 == 0 { '#' } else { '=' };
+                    }
+                }
+                out += &format!("  run {:<6} |{}| {} files\n", run.run, line.into_iter().collect::<String>(), run.files.len());
+            }
+        }
+        let show = |bytes: &[u8]| format_bytes(bytes, int_keys);
+        let lower_label = show(&lower);
+        out += &format!("            {}{:>pad$}\n", lower_label, show(&upper), pad = (width + 2).saturating_sub(lower_label.len()));
+        out
+    }
+}
+
+fn fullness(size: usize, capacity: usize) -> f64 {
+    match capacity {
+        0 => 0.0,
+        capacity => size as f64 / capacity as f64,
+    }
+}
+
+// [####......] for 0.4
+fn bar(fullness: f64, width: usize) -> String {
+    let filled = std::cmp::min((fullness * width as f64).round() as usize, width);
+    format!("[{}{}] {:>3.0}%", "#".repeat(filled), ".".repeat(width - filled), 100.0 * fullness)
+}
+
+fn human_size(bytes: usize) -> String {
+    match bytes {
+        bytes if bytes >= 1 << 30 => format!("{:.1}G", bytes as f64 / (1u64 << 30) as f64),
+        bytes if bytes >= 1 << 20 => format!("{:.1}M", bytes as f64 / (1 << 20) as f64),
+        bytes if bytes >= 1 << 10 => format!("{:.1}K", bytes as f64 / (1 << 10) as f64),
+        bytes => format!("{}B", bytes),
+    }
+}
+
@@ -0,0 +1,27 @@
+use std::sync::{Arc};
+
+use crate::lib_template::{Comparator, MergeOperator};
+use crate::lib_merge::{CompactionFilter};
+
+/// Per-tree settings and hooks, passed to `LSMTree::create_lsmtree_with_options`.
+/// Settings shared by every tree stay in `CONFIGURATION`.
+#[derive(Clone)]
+pub struct TreeOptions {
+    // Orders the keys, bytewise if None. A tree has to be opened with the
+    // comparator it was written with, as its files are sorted by it.
+    pub comparator: Option<Arc<dyn Comparator>>,
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>, // asked about every value a flush or compaction writes
+    // Combines merge operands with the value below them, LSMTree::merge needs
+    // one. A tree holding operands has to be opened with the same operator.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl TreeOptions {
+    pub fn create_options() -> TreeOptions {
+        TreeOptions {
+            comparator: None,
+            compaction_filter: None,
+            merge_operator: None,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::{BTreeMap};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::{Mutex};
+
+/// Sequence numbers of the live snapshots, with the number of handles on each.
+pub struct SnapshotList {
+    seqs: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn create_snapshot_list() -> SnapshotList {
+        SnapshotList {
+            seqs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // ascending
+    pub fn sequence_numbers(&self) -> Vec<u64> {
+        self.seqs.lock().keys().cloned().collect()
+    }
+
+    // Reads the sequence number under the lock, so a flush or compaction that
+    // lists the snapshots either sees this one or ran before it was taken.
+    fn acquire(&self, visible_seq: &AtomicU64) -> u64 {
+        let mut seqs = self.seqs.lock();
+        let seq = visible_seq.load(Ordering::Acquire);
+        *seqs.entry(seq).or_insert(0) += 1;
+        seq
+    }
+
+    fn release(&self, seq: u64) {
+        let mut seqs = self.seqs.lock();
+        let count = seqs.get_mut(&seq).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            seqs.remove(&seq);
+        }
+    }
+}
+
+/// Read view of the tree as of a sequence number, returned by `LSMTree::snapshot`.
+/// Compactions keep the versions it can see until it is dropped.
+pub struct Snapshot {
+    seq: u64,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn create_snapshot(visible_seq: &AtomicU64, list: Arc<SnapshotList>) -> Snapshot {
+        let seq = list.acquire(visible_seq);
+        Snapshot {
+            seq,
+            list,
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
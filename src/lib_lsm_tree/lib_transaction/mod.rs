@@ -0,0 +1,59 @@
+use std::collections::{HashMap, HashSet};
+
+use super::LSMTree;
+use super::lib_snapshot::{Snapshot};
+use super::lib_write_batch::{WriteBatch};
+
+/// Optimistic read-modify-write transaction, returned by `LSMTree::begin`. Reads
+/// see the tree as of the transaction's snapshot plus its own writes, and writes
+/// are buffered until `commit`, which fails if a key read with `get_for_update`
+/// was written after the snapshot.
+pub struct Transaction<'a> {
+    tree: &'a LSMTree,
+    snapshot: Snapshot,
+    batch: WriteBatch,
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>, // latest value written per key, None if deleted
+    read_keys: HashSet<Vec<u8>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(tree: &'a LSMTree) -> Transaction<'a> {
+        Transaction {
+            tree,
+            snapshot: tree.snapshot(),
+            batch: WriteBatch::new(),
+            writes: HashMap::new(),
+            read_keys: HashSet::new(),
+        }
+    }
+
+    // reads key and makes the commit depend on it not being written by anyone else
+    pub fn get_for_update(&mut self, key: &[u8], value: &mut Vec<u8>) -> bool {
+        self.read_keys.insert(key.to_vec());
+        match self.writes.get(key) {
+            Some(Some(written)) => {
+                *value = written.clone();
+                true
+            },
+            Some(None) => false,
+            None => self.tree.get_with_snapshot(key, value, &self.snapshot),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.put(key, value);
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.batch.delete(key);
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    // Applies the writes as one batch. Returns false, writing nothing, on a
+    // conflict, or if the buffer was flushed since the snapshot and the
+    // conflicts can no longer be checked in memory; the caller should retry.
+    pub fn commit(self) -> bool {
+        self.tree.write_if_unchanged(self.batch, Some((&self.read_keys, self.snapshot.seq())))
+    }
+}
@@ -0,0 +1,109 @@
+use std::convert::TryInto;
+use std::time::{Duration};
+
+use crate::lib_template::{Record, RecordKind, current_time_millis};
+use crate::lib_on_disk::lib_block::{put_varint, get_varint};
+
+/// Puts, deletes, range deletes and merges applied together by `LSMTree::write`:
+/// readers see all of them or none, and the batch is logged as a single WAL record.
+pub struct WriteBatch {
+    ops: Vec<Record>, // in the order added, sequence numbers are assigned by records()
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(Record::create_record(key.to_vec(), value.to_vec(), 0));
+    }
+
+    // the value reads as deleted once ttl has passed
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        let expire_at = current_time_millis() + ttl.as_millis() as u64;
+        self.ops.push(Record::create_expiring_record(key.to_vec(), value.to_vec(), 0, expire_at));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(Record::create_tombstone(key.to_vec(), 0));
+    }
+
+    // deletes every key in [lower, upper]
+    pub fn delete_range(&mut self, lower: &[u8], upper: &[u8]) {
+        self.ops.push(Record::create_range_tombstone(lower.to_vec(), upper.to_vec(), 0));
+    }
+
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.ops.push(Record::create_merge_operand(key.to_vec(), operand.to_vec(), 0));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.len() == 0
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.ops.iter().map(|op| &op.key)
+    }
+
+    // Operations get consecutive sequence numbers from first_seq, so a later
+    // operation on the same key in the batch wins.
+    pub fn records(&self, first_seq: u64) -> Vec<Record> {
+        self.ops.iter().enumerate().map(|(i, op)| Record {
+            seq: first_seq + i as u64,
+            ..op.clone()
+        }).collect()
+    }
+
+    // [first sequence number (u64)] then per operation [kind (u8)][expiry time (varint)][key length (varint)][value length (varint)][key][value]
+    pub fn encode(&self, first_seq: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(&first_seq.to_be_bytes());
+        for op in self.ops.iter() {
+            bytes.push(op.kind.to_byte());
+            put_varint(&mut bytes, op.expire_at);
+            put_varint(&mut bytes, op.key.len() as u64);
+            put_varint(&mut bytes, op.value.len() as u64);
+            bytes.extend(&op.key);
+            bytes.extend(&op.value);
+        }
+        bytes
+    }
+
+    // returns the batch and its first sequence number
+    pub fn decode(bytes: &[u8]) -> (WriteBatch, u64) {
+        let first_seq = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let mut batch = WriteBatch::new();
+        let mut offset = 8;
+        while offset < bytes.len() {
+            let kind = RecordKind::from_byte(bytes[offset]);
+            offset += 1;
+            let expire_at = get_varint(bytes, &mut offset);
+            let key_len = get_varint(bytes, &mut offset) as usize;
+            let value_len = get_varint(bytes, &mut offset) as usize;
+            let key = bytes[offset..offset + key_len].to_vec();
+            let value = bytes[offset + key_len..offset + key_len + value_len].to_vec();
+            offset += key_len + value_len;
+            batch.ops.push(Record {
+                key,
+                value,
+                seq: 0,
+                kind,
+                expire_at,
+            });
+        }
+        (batch, first_seq)
+    }
+}
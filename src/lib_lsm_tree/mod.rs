@@ -1,59 +1,359 @@
+pub mod lib_options;
+pub mod lib_snapshot;
+pub mod lib_write_batch;
+pub mod lib_transaction;
+pub mod lib_checkpoint;
+pub mod lib_ingest;
+pub mod lib_layout;
+
 use std::collections::{HashSet};
-use log::{debug};
+use std::fs;
+use std::path::{Path};
+use std::sync::{Arc};
+use std::time::{Duration};
+use log::{debug, info};
 
-use parking_lot::{RwLock, RwLockWriteGuard};
-use std::i32;
+use parking_lot::{RwLock, RwLockWriteGuard, Mutex};
 
 use crate::configuration::CONFIGURATION;
+use crate::lib_helper::{table_filenames};
 
-use crate::lib_template::{Record};
+use crate::lib_template::{Record, RecordKind, ComparatorScope, apply_operands, resolve_versions, current_time_millis, covering_tombstone_seq};
+use crate::lib_merge::{CompactionContext};
 use crate::lib_on_disk::lib_disk_level::{DiskLevel};
 use crate::lib_in_memory::{MemoryBuffer};
+use crate::lib_in_memory::lib_row_cache::{RowCache};
+use crate::lib_on_disk::lib_wal::{WriteAheadLog, WAL_FILENAME};
+use crate::lib_on_disk::lib_manifest::{Manifest, OrphanReport};
+use crate::lib_on_disk::lib_disk_file::{DiskFile};
+use self::lib_options::{TreeOptions};
+use self::lib_snapshot::{Snapshot, SnapshotList};
+use self::lib_write_batch::{WriteBatch};
+use self::lib_transaction::{Transaction};
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 pub struct LSMTree {
     pub buffer: RwLock<MemoryBuffer>,
     pub levels: RwLock<Vec<DiskLevel>>,
     pub compacting: AtomicBool, // true if thread is currently compacting, false otherwise
+    pub row_cache: Option<RowCache>, // caches values found on disk, None if CONFIGURATION.ROW_CACHE_CAPACITY is 0
+    pub options: TreeOptions,
+    pub sequence: AtomicU64, // last sequence number handed to a write
+    pub visible_seq: AtomicU64, // reads see writes up to this sequence number, all earlier writes are in the buffer
+    pub snapshots: Arc<SnapshotList>,
+    pub wal: Option<WriteAheadLog>, // None if CONFIGURATION.WAL_ENABLED is false
+    pub flushed_seq: AtomicU64, // writes up to this sequence number may have left the buffer
+    pub dir: String, // holds the files, the log and the manifest
+    manifest_lock: Mutex<()>, // one manifest write at a time, so the last one written is the latest
+    pub orphans: OrphanReport, // table files open found outside the manifest and removed
 }
 
 impl LSMTree {
     pub fn create_lsmtree() -> LSMTree {
+        LSMTree::create_lsmtree_with_options(TreeOptions::create_options())
+    }
+
+    // a new tree in the working directory, replacing any tree there
+    pub fn create_lsmtree_with_options(options: TreeOptions) -> LSMTree {
+        let wal = match CONFIGURATION.WAL_ENABLED {
+            true => Some(WriteAheadLog::create_log(WAL_FILENAME)),
+            false => None,
+        };
+        let lsm_tree = LSMTree::create_lsmtree_with_log(".", wal, options);
+        // an empty manifest, so the files of an earlier tree are never opened with this one
+        lsm_tree.write_manifest();
+        lsm_tree
+    }
+
+    fn create_lsmtree_with_log(dir: &str, wal: Option<WriteAheadLog>, options: TreeOptions) -> LSMTree {
         let buffer = RwLock::new(MemoryBuffer::create_buffer());
         LSMTree {
             buffer: buffer,
             levels: RwLock::new(Vec::new()),
             compacting: AtomicBool::new(false),
+            row_cache: match CONFIGURATION.ROW_CACHE_CAPACITY {
+                0 => None,
+                capacity => Some(RowCache::create_row_cache(capacity)),
+            },
+            options,
+            sequence: AtomicU64::new(0),
+            visible_seq: AtomicU64::new(0),
+            snapshots: Arc::new(SnapshotList::create_snapshot_list()),
+            wal,
+            flushed_seq: AtomicU64::new(0),
+            dir: dir.to_string(),
+            manifest_lock: Mutex::new(()),
+            orphans: OrphanReport {
+                files_removed: 0,
+                bytes_reclaimed: 0,
+            },
         }
     }
 
-    pub fn put(&self, key: &i32, value: &i32) {
-        let mut buffer = self.buffer.write();
-        buffer.put(key, value);
-        if buffer.is_full() {
-            self.flush_buffer_with_guard(buffer);
-            self.compaction();
+    // Makes the tree's comparator the one keys are compared with on this thread
+    // until the scope is dropped. Every operation enters it, so no comparison
+    // waits on a lock.
+    pub(crate) fn enter_comparator(&self) -> ComparatorScope {
+        ComparatorScope::enter(self.options.comparator.as_ref())
+    }
+
+    // Reopens the tree in the working directory after it was shut down, cleanly
+    // or not, and keeps appending to the same log.
+    pub fn recover() -> LSMTree {
+        LSMTree::recover_with_options(TreeOptions::create_options())
+    }
+
+    pub fn recover_with_options(options: TreeOptions) -> LSMTree {
+        LSMTree::open_with_options(".", options)
+    }
+
+    // Opens the tree in dir, or starts an empty one there. The levels are those
+    // listed in the manifest, and the buffer holds the write batches logged since
+    // the last flush. Table files the manifest does not list are removed.
+    pub fn open(dir: &str) -> LSMTree {
+        LSMTree::open_with_options(dir, TreeOptions::create_options())
+    }
+
+    pub fn open_with_options(dir: &str, options: TreeOptions) -> LSMTree {
+        LSMTree::try_open_with_options(dir, options).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    pub fn try_open(dir: &str) -> Result<LSMTree, String> {
+        LSMTree::try_open_with_options(dir, TreeOptions::create_options())
+    }
+
+    // As open_with_options, but a directory whose manifest is missing or damaged
+    // while table files are left is refused rather than taken for an empty tree,
+    // which would remove those files as orphans. So is one whose manifest lists a
+    // file that is gone. Repair rebuilds the manifest in either case.
+    pub fn try_open_with_options(dir: &str, options: TreeOptions) -> Result<LSMTree, String> {
+        let _comparator = ComparatorScope::enter(options.comparator.as_ref());
+        fs::create_dir_all(dir).unwrap_or_else(|error| panic!("Failed to create tree directory {}: {}", dir, error));
+        let manifest = match Manifest::read(dir) {
+            Some(manifest) => manifest,
+            None if Manifest::exists(dir) => return Err(format!("The manifest of {} is damaged, the repair command rebuilds it from the table files", dir)),
+            None if !table_filenames(dir).is_empty() => return Err(format!("{} holds table files but no manifest, the repair command rebuilds it from them", dir)),
+            None => Manifest::create_manifest(0),
+        };
+        if let Some(name) = manifest.files().into_iter().find(|name| !Path::new(dir).join(name).exists()) {
+            return Err(format!("{} is missing from {}, the repair command rebuilds the manifest from the table files left", name, dir));
+        }
+        let wal_filename = Path::new(dir).join(WAL_FILENAME).to_string_lossy().to_string();
+        let payloads = WriteAheadLog::read_log(&wal_filename);
+        let wal = match CONFIGURATION.WAL_ENABLED {
+            true => Some(WriteAheadLog::open_log(&wal_filename)),
+            false => None,
+        };
+        let mut lsm_tree = LSMTree::create_lsmtree_with_log(dir, wal, options);
+        lsm_tree.orphans = manifest.remove_orphans(dir);
+        if lsm_tree.orphans.files_removed > 0 {
+            info!("Removed {} orphan files from {}, reclaiming {} bytes", lsm_tree.orphans.files_removed, dir, lsm_tree.orphans.bytes_reclaimed);
+        }
+        *lsm_tree.levels.write() = manifest.levels.iter().map(|level| DiskLevel::open_level(level, dir)).collect::<Result<_, _>>()?;
+        let mut last_seq = manifest.last_seq;
+        for payload in payloads.iter() {
+            let (batch, first_seq) = WriteBatch::decode(payload);
+            let buffer = lsm_tree.buffer.read();
+            // a crash between writing the manifest and resetting the log leaves batches already in the files
+            for record in batch.records(first_seq).into_iter().filter(|record| record.seq > manifest.last_seq) {
+                buffer.put(record);
+            }
+            last_seq = std::cmp::max(last_seq, first_seq + batch.len() as u64 - 1);
+        }
+        lsm_tree.sequence.store(last_seq, Ordering::Release);
+        lsm_tree.visible_seq.store(last_seq, Ordering::Release);
+        // the replayed batches are only in the buffer and the log, so the next open has to replay them again
+        lsm_tree.flushed_seq.store(manifest.last_seq, Ordering::Release);
+        lsm_tree.write_manifest();
+        Ok(lsm_tree)
+    }
+
+    // Lists the files of every level in the manifest. Runs dropped from a level
+    // are only removed from disk once a manifest without them is written.
+    pub fn write_manifest(&self) {
+        let _manifest_lock = self.manifest_lock.lock();
+        let levels = self.levels.read();
+        let retired: Vec<_> = levels.iter().map(|level| level.take_obsolete_runs()).collect();
+        let (manifest, _files) = self.current_version(&levels);
+        manifest.write(&self.dir);
+        drop(retired);
+    }
+
+    // The manifest of the tree and the files it lists. Levels are read from the
+    // top, so data a compaction moves down meanwhile is listed at least once.
+    fn current_version(&self, levels: &[DiskLevel]) -> (Manifest, Vec<Arc<DiskFile>>) {
+        let mut manifest = Manifest::create_manifest(self.flushed_seq.load(Ordering::Acquire));
+        let mut files = Vec::new();
+        for level in levels.iter() {
+            let (entry, level_files) = level.manifest_entry();
+            manifest.levels.push(entry);
+            files.extend(level_files);
+        }
+        (manifest, files)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch);
+    }
+
+    // the value reads as deleted once ttl has passed, and compactions drop it
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key, value, ttl);
+        self.write(batch);
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch);
+    }
+
+    // deletes every key in [lower, upper] with a single range tombstone
+    pub fn delete_range(&self, lower: &[u8], upper: &[u8]) {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(lower, upper);
+        self.write(batch);
+    }
+
+    // stores operand, which reads and compactions combine with the key's value through the merge operator
+    pub fn merge(&self, key: &[u8], operand: &[u8]) {
+        assert!(self.options.merge_operator.is_some(), "Merge requires a merge operator, see TreeOptions::merge_operator");
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch);
+    }
+
+    // Applies the batch under one shared buffer lock, so a flush cannot split it
+    pub fn write(&self, batch: WriteBatch) {
+        self.write_if_unchanged(batch, None);
+    }
+
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::begin(self)
+    }
+
+    // Writes the batch unless a key in read_keys was written after read_seq, and
+    // returns whether it was written.
+    pub(crate) fn write_if_unchanged(&self, batch: WriteBatch, read_keys: Option<(&HashSet<Vec<u8>>, u64)>) -> bool {
+        if batch.is_empty() {
+            return true;
+        }
+        let _comparator = self.enter_comparator();
+        // inserts only need shared access, the memtable synchronizes concurrent writers
+        let buffer = self.buffer.read();
+        let first_seq = self.sequence.fetch_add(batch.len() as u64, Ordering::AcqRel) + 1;
+        if let Some((read_keys, read_seq)) = read_keys {
+            // once every earlier write is published, all writes newer than the
+            // last flush are in the buffer, which holds shared until we are done
+            self.wait_visible(first_seq - 1);
+            let conflict = read_seq < self.flushed_seq.load(Ordering::Acquire) || read_keys.iter().any(|key| {
+                let mut record = Record::create_record(Vec::new(), Vec::new(), 0);
+                (buffer.get(key, first_seq - 1, &mut record) && record.seq > read_seq) || buffer.range_tombstone_seq(key, first_seq - 1) > read_seq
+            });
+            if conflict {
+                // the sequence numbers stay unused
+                self.publish(first_seq, first_seq + batch.len() as u64 - 1);
+                return false;
+            }
+        }
+        if let Some(wal) = &self.wal {
+            wal.append(&batch.encode(first_seq));
+        }
+        for record in batch.records(first_seq) {
+            buffer.put(record);
+        }
+        self.publish(first_seq, first_seq + batch.len() as u64 - 1);
+        // after publishing, so a read that caches an older value is turned away
+        if let Some(row_cache) = &self.row_cache {
+            for record in batch.records(first_seq) {
+                match record.is_range_deletion() {
+                    true => row_cache.invalidate_range(&record.key, &record.value),
+                    false => row_cache.invalidate(&record.key),
+                }
+            }
+        }
+        let is_full = buffer.is_full();
+        drop(buffer);
+
+        if is_full {
+            let buffer = self.buffer.write();
+            // another writer may have flushed the buffer while we waited for the lock
+            if buffer.is_full() {
+                self.flush_buffer_with_guard(buffer);
+                self.compaction();
+            }
+        }
+        true
+    }
+
+    fn wait_visible(&self, seq: u64) {
+        while self.visible_seq.load(Ordering::Acquire) != seq {
+            std::thread::yield_now();
+        }
+    }
+
+    // Makes first_seq..=last_seq visible to reads at once, after every earlier
+    // write, so that a snapshot never misses a write older than itself.
+    fn publish(&self, first_seq: u64, last_seq: u64) {
+        while self.visible_seq.compare_exchange_weak(first_seq - 1, last_seq, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            std::thread::yield_now();
         }
     }
 
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::create_snapshot(&self.visible_seq, self.snapshots.clone())
+    }
+
+    // bottommost if the output of the compaction is the deepest data in the tree
+    fn compaction_context(&self, bottommost: bool) -> CompactionContext {
+        CompactionContext::create_context(self.snapshots.sequence_numbers(), bottommost, self.options.compaction_filter.clone(), self.options.merge_operator.clone())
+    }
+
     pub fn flush_buffer_with_guard(&self, mut buffer: RwLockWriteGuard<MemoryBuffer>) {
-        let data = buffer.merge();
-        let mut new_capacity = buffer.capacity() as f64 * CONFIGURATION.T_OVER_K;
-        new_capacity -= new_capacity % CONFIGURATION.RECORD_SIZE as f64;
-        let buffer_size = buffer.size();
+        let _comparator = self.enter_comparator();
+        // the write lock keeps writers out, so snapshots taken after this see every version in the buffer
+        let bottommost = self.levels.read().is_empty();
+        let context = self.compaction_context(bottommost);
+        let data = context.retain_versions(buffer.merge(), 1);
+        let new_capacity = buffer.capacity() as f64 * CONFIGURATION.T_OVER_K;
+        let buffer_size = data.iter().map(|record| record.size()).sum();
 
         let levels = self.levels.read();
         if levels.len() > 0 {
             levels[0].flush_from_buffer(data, buffer_size, new_capacity as usize);
+            drop(levels);
         } else {
             drop(levels);
-            let new_level = DiskLevel::create_level_from_buffer(data, buffer_size, new_capacity as usize, 1);
+            let new_level = DiskLevel::create_level_from_buffer(data, buffer_size, new_capacity as usize, 1, &self.dir);
             let mut levels = self.levels.write();
             levels.push(new_level);
             drop(levels);
         }
+        // the write lock keeps writers out, so every assigned sequence number is in data
+        self.flushed_seq.store(self.sequence.load(Ordering::Acquire), Ordering::Release);
+        // the log can only go once the manifest lists the new run
+        self.write_manifest();
         buffer.clear();
+        if let Some(wal) = &self.wal {
+            wal.reset();
+        }
+        self.invalidate_filtered(&context);
+    }
+
+    // Drops the values a compaction filter removed or changed from the row cache,
+    // once the merge that filtered them replaced their old versions. Reads that
+    // found an old version meanwhile took the cache epoch before, so they do not
+    // cache it afterwards.
+    fn invalidate_filtered(&self, context: &CompactionContext) {
+        if let Some(row_cache) = &self.row_cache {
+            for key in context.filtered_keys.lock().iter() {
+                row_cache.invalidate(key);
+            }
+        }
     }
 
     pub fn compaction(&self) {
@@ -66,15 +366,26 @@ impl LSMTree {
     }
 
     pub fn merge_and_flush(&self) {
+        self.merge_levels_from(0);
+    }
+
+    // Merges each full level into the one below it, from the level at
+    // first_level_idx down to the first level that is not full.
+    pub fn merge_levels_from(&self, first_level_idx: usize) {
+        let _comparator = self.enter_comparator();
         let mut levels = self.levels.read();
         let mut level_files = Vec::new();
-        for i in 0..levels.len() {
+        for i in first_level_idx..levels.len() {
             if level_files.len() > 0 {
                 let (prev_size, new_capacity) = self.prev_size_new_capacity(i);
-                levels[i].flush(level_files, prev_size, std::cmp::max(CONFIGURATION.FILE_SIZE, new_capacity as usize), new_capacity as usize);
+                let context = self.compaction_context(i == levels.len() - 1);
+                let num_runs = level_files.len();
+                levels[i].flush(level_files, prev_size, std::cmp::max(CONFIGURATION.FILE_SIZE, new_capacity as usize), new_capacity as usize, &context);
                 drop(levels);
 
-                self.clear_prev_level(i);
+                self.clear_prev_level(i, num_runs);
+                self.write_manifest();
+                self.invalidate_filtered(&context);
                 levels = self.levels.read();
             }
             if !levels[i].is_full() {
@@ -86,17 +397,65 @@ impl LSMTree {
         // need to create new level
         if level_files.len() > 0 {
             let level_len = levels.len();
+            let num_runs = level_files.len();
             // drop read-only reference, we need write reference later
             drop(levels);
 
             let (_prev_size, new_capacity) = self.prev_size_new_capacity(level_len);
 
+            let context = self.compaction_context(true);
             let mut levels = self.levels.write();
-            levels.push(DiskLevel::create_level(level_files, std::cmp::max(CONFIGURATION.FILE_SIZE, new_capacity as usize), new_capacity as usize, level_len + 1));
+            levels.push(DiskLevel::create_level(level_files, std::cmp::max(CONFIGURATION.FILE_SIZE, new_capacity as usize), new_capacity as usize, level_len + 1, &self.dir, &context));
+            drop(levels);
+
+            self.clear_prev_level(level_len, num_runs);
+            self.write_manifest();
+            self.invalidate_filtered(&context);
+        }
+    }
+
+    // writes the buffer to level 1 whether it is full or not
+    pub fn flush(&self) {
+        let buffer = self.buffer.write();
+        if buffer.size() > 0 {
+            self.flush_buffer_with_guard(buffer);
+            self.compaction();
+        }
+    }
+
+    // Merges the buffer and every level into the level below it, full or not, so
+    // the data ends up in the last level. A last level that overflows is then
+    // compacted as usual.
+    pub fn compact_all(&self) {
+        let _comparator = self.enter_comparator();
+        while self.compacting.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now();
+        }
+        let buffer = self.buffer.write();
+        if buffer.size() > 0 {
+            self.flush_buffer_with_guard(buffer);
+        } else {
+            drop(buffer);
+        }
+        let num_levels = self.levels.read().len();
+        for i in 1..num_levels {
+            let levels = self.levels.read();
+            let level_files = levels[i - 1].get_all_files();
+            if level_files.is_empty() {
+                continue;
+            }
+            let (prev_size, new_capacity) = self.prev_size_new_capacity(i);
+            let context = self.compaction_context(i == num_levels - 1);
+            let num_runs = level_files.len();
+            levels[i].flush(level_files, prev_size, std::cmp::max(CONFIGURATION.FILE_SIZE, new_capacity as usize), new_capacity as usize, &context);
             drop(levels);
 
-            self.clear_prev_level(level_len);
+            self.clear_prev_level(i, num_runs);
+            self.write_manifest();
+            self.invalidate_filtered(&context);
         }
+        self.compacting.store(false, Ordering::Relaxed);
+        self.compaction();
     }
 
     fn prev_size_new_capacity(&self, level_idx: usize) -> (usize, f64) {
@@ -107,39 +466,171 @@ impl LSMTree {
             let levels = self.levels.read();
             (levels[level_idx - 1].size(), levels[level_idx - 1].capacity())
         }; 
-        let mut new_capacity = prev_capacity as f64 * CONFIGURATION.T_OVER_K;
-        new_capacity -= new_capacity % CONFIGURATION.RECORD_SIZE as f64;
+        let new_capacity = prev_capacity as f64 * CONFIGURATION.T_OVER_K;
         (prev_size, new_capacity)
     }
 
-    fn clear_prev_level(&self, level_idx: usize) {
+    // Removes the num_runs oldest runs of the previous level, the ones merged into
+    // level_idx. A buffer flush may have added newer runs to it since.
+    fn clear_prev_level(&self, level_idx: usize, num_runs: usize) {
         if level_idx == 0 {
             let mut buffer = self.buffer.write();
             buffer.clear();
         } else {
             let levels = self.levels.read();
-            levels[level_idx - 1].clear();
+            levels[level_idx - 1].clear_oldest_runs(num_runs);
         }
     }
 
-    pub fn get(&self, key: &i32, value: &mut i32) -> bool {
-        let mut record = Record::create_record(0, 0);
-        
+    pub fn get(&self, key: &[u8], value: &mut Vec<u8>) -> bool {
+        self.try_get(key, value).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    // Like get, but errors rather than panics if a block the value would be in
+    // is damaged on disk.
+    pub fn try_get(&self, key: &[u8], value: &mut Vec<u8>) -> Result<bool, String> {
+        let cache_epoch = match &self.row_cache {
+            Some(row_cache) => row_cache.epoch(),
+            None => 0,
+        };
+        let seq = self.visible_seq.load(Ordering::Acquire);
+        self.get_at_seq(key, seq, value, self.row_cache.as_ref().map(|row_cache| (row_cache, cache_epoch)))
+    }
+
+    // the row cache only holds the latest values, so snapshot reads bypass it
+    pub fn get_with_snapshot(&self, key: &[u8], value: &mut Vec<u8>, snapshot: &Snapshot) -> bool {
+        self.get_at_seq(key, snapshot.seq(), value, None).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    // Follows merge operands down to the value they apply to, first in the buffer,
+    // then on disk.
+    fn get_at_seq(&self, key: &[u8], mut seq: u64, value: &mut Vec<u8>, row_cache: Option<(&RowCache, usize)>) -> Result<bool, String> {
+        let _comparator = self.enter_comparator();
+        let mut record = Record::create_record(Vec::new(), Vec::new(), 0);
+        let mut operands = Vec::new(); // newest first
+
         let buffer = self.buffer.read();
-        if buffer.get(key, &mut record) {
-            *value = record.value;
-            return true;
+        let tombstone_seq = buffer.range_tombstone_seq(key, seq);
+        let mut base = None;
+        let mut in_buffer = false;
+        while buffer.get(key, seq, &mut record) {
+            if record.seq < tombstone_seq {
+                in_buffer = true;
+                break;
+            }
+            match record.kind {
+                RecordKind::Merge => {
+                    seq = record.seq - 1;
+                    operands.push(record.value.clone());
+                },
+                RecordKind::Value => {
+                    if !record.is_expired(current_time_millis()) {
+                        base = Some(record.value.clone());
+                    }
+                    in_buffer = true;
+                    break;
+                },
+                RecordKind::Deletion | RecordKind::RangeDeletion => {
+                    in_buffer = true;
+                    break;
+                },
+            }
         }
         drop(buffer);
 
+        // everything on disk is older than a range tombstone in the buffer
+        if !in_buffer && tombstone_seq == 0 {
+            // a flush folds the operands into the value on disk without
+            // invalidating the key, so the value below them is not cached
+            base = self.get_from_disk(key, seq, row_cache.filter(|_| operands.is_empty()))?;
+        }
+        match apply_operands(self.options.merge_operator.as_deref(), key, base, &operands) {
+            Some(result) => {
+                *value = result;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    // Value of key on disk, with its merge operands applied.
+    // A cached value is returned without looking at expiry times or range
+    // tombstones, which relies on the cache only ever holding what a search of
+    // the levels would find: values that can expire are never inserted below,
+    // and whatever hides or changes a value on disk invalidates it, that
+    // is writes and range deletions of the key, ingests over it and compaction
+    // filters removing or changing it. Compactions otherwise only drop versions
+    // already hidden by one of those.
+    fn get_from_disk(&self, key: &[u8], mut seq: u64, row_cache: Option<(&RowCache, usize)>) -> Result<Option<Vec<u8>>, String> {
+        if let Some((row_cache, _)) = row_cache {
+            if let Some(cached) = row_cache.get(key) {
+                return Ok(Some(cached));
+            }
+        }
+
+        let mut record = Record::create_record(Vec::new(), Vec::new(), 0);
+        let mut versions = Vec::new();
+        let levels = self.levels.read();
+        let tombstone_seq = levels.iter().map(|level| level.range_tombstone_seq(key, seq)).max().unwrap_or(0);
+        // the next older version is in the same level or a deeper one
+        while find_in_levels(&levels, key, seq, &mut record)? {
+            if record.seq < tombstone_seq {
+                break;
+            }
+            versions.push(record.clone());
+            if !record.is_merge() {
+                break;
+            }
+            seq = record.seq - 1;
+        }
+        drop(levels);
+
+        let value = resolve_versions(self.options.merge_operator.as_deref(), &versions);
+        // the row cache does not know when a value expires, see above
+        let expires = versions.iter().any(|version| version.expire_at != 0);
+        if let (Some(value), Some((row_cache, cache_epoch)), false) = (&value, row_cache, expires) {
+            row_cache.insert(key, value, cache_epoch);
+        }
+        Ok(value)
+    }
+
+    // Latest value of each key in [lower, upper] as of the snapshot, or as of now
+    // without one, sorted by key
+    pub fn scan(&self, lower: &[u8], upper: &[u8], snapshot: Option<&Snapshot>) -> Vec<Record> {
+        let _comparator = self.enter_comparator();
+        let seq = match snapshot {
+            Some(snapshot) => snapshot.seq(),
+            None => self.visible_seq.load(Ordering::Acquire),
+        };
+        let buffer = self.buffer.read();
+        let mut records = buffer.find_range(lower, upper);
+        let mut tombstones = buffer.range_tombstones(lower, upper);
+        drop(buffer);
         let levels = self.levels.read();
         for level in levels.iter() {
-            if level.get(key, &mut record) {
-                *value = record.value;
-                return true;
+            records.extend(level.range(lower, upper));
+            tombstones.extend(level.range_tombstones(lower, upper));
+        }
+        drop(levels);
+        records.retain(|record| record.seq <= seq);
+        records.sort();
+        // records now hold the versions of each key newest first
+        let mut results = Vec::new();
+        let mut first = 0;
+        while first < records.len() {
+            let mut last = first + 1;
+            while last < records.len() && records[last].key == records[first].key {
+                last += 1;
             }
+            // versions older than a covering range tombstone are deleted
+            let tombstone_seq = covering_tombstone_seq(tombstones.iter(), &records[first].key, seq);
+            let live = records[first..last].iter().take_while(|record| record.seq > tombstone_seq).count();
+            if let Some(value) = resolve_versions(self.options.merge_operator.as_deref(), &records[first..first + live]) {
+                results.push(Record::create_record(records[first].key.clone(), value, records[first].seq));
+            }
+            first = last;
         }
-        false
+        results
     }
 
     pub fn print_stats(&self) {    
@@ -147,7 +638,7 @@ impl LSMTree {
         debug!("LOGICAL PAIRS: will be printed at end");
         let mut distinct_keys = HashSet::new();
         for level in levels.iter() {
-            print!("LVL{}: {}", level.level(), level.size() / CONFIGURATION.RECORD_SIZE);
+            print!("LVL{}: {}", level.level(), level.size());
         }
         for level in levels.iter() {
             level.print_stats(&mut distinct_keys);
@@ -160,5 +651,17 @@ impl LSMTree {
         for level in levels.iter() {
             level.clear();
         }
+        drop(levels);
+        self.write_manifest();
+    }
+}
+
+// newest version of key with a sequence number <= seq in the first level holding one
+fn find_in_levels(levels: &[DiskLevel], key: &[u8], seq: u64, record: &mut Record) -> Result<bool, String> {
+    for level in levels.iter() {
+        if level.get(key, seq, record)? {
+            return Ok(true);
+        }
     }
+    Ok(false)
 }
@@ -1,13 +1,14 @@
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::sync::{Arc};
+use parking_lot::{Mutex};
 
 use super::configuration::CONFIGURATION;
-use super::lib_template::{Record};
+use super::lib_template::{Record, RecordKind, MergeOperator, compare_keys, resolve_versions, current_time_millis};
 use super::lib_helper::{generate_filename};
 use super::lib_on_disk::lib_disk_file::{DiskFile};
 use super::lib_on_disk::lib_disk_run::{Run};
-use crate::metrics::{PUT_IO_COUNTER};
+use crate::metrics::{PUT_IO_COUNTER, COMPACTION_FILTER_DECISIONS, COMPACTION_FILES_SKIPPED};
 
 use atomic_counter::AtomicCounter;
 
@@ -43,60 +44,267 @@ impl Ord for HeapNode {
     }
 }
 
-pub fn merge_from_files(mut files_to_merge: Vec<Vec<Arc<DiskFile>>>, run_merge_into: &Run) -> Vec<Arc<DiskFile>> {
-    let mut merged_runs: Vec<u8> = Vec::new();
+pub enum FilterDecision {
+    Keep,
+    Remove, // written as a tombstone, so older versions stay hidden
+    ChangeValue(Vec<u8>),
+}
+
+/// Decides the fate of each value a flush or compaction writes, e.g. to purge
+/// data of deleted tenants or rewrite values in a new format. Registered
+/// through `TreeOptions`. Values that a live snapshot can read are not filtered.
+pub trait CompactionFilter: Send + Sync {
+    // level is the level being written
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> FilterDecision;
+    fn name(&self) -> &str;
+}
+
+/// State of the tree that a flush or compaction needs to decide which versions to keep.
+pub struct CompactionContext {
+    pub snapshots: Vec<u64>, // sequence numbers of the live snapshots, ascending
+    pub bottommost: bool, // no older data of any key lies below the output
+    pub now: u64, // values that expire by this time are deleted
+    pub filter: Option<Arc<dyn CompactionFilter>>,
+    pub merge_operator: Option<Arc<dyn MergeOperator>>, // folds the merge operands of a key
+    pub filtered_keys: Arc<Mutex<Vec<Vec<u8>>>>, // keys whose value the filter removed or changed, shared with derived contexts
+}
+
+impl CompactionContext {
+    pub fn create_context(snapshots: Vec<u64>, bottommost: bool, filter: Option<Arc<dyn CompactionFilter>>, merge_operator: Option<Arc<dyn MergeOperator>>) -> CompactionContext {
+        CompactionContext {
+            snapshots,
+            bottommost,
+            now: current_time_millis(),
+            filter,
+            merge_operator,
+            filtered_keys: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // same context for an output that older runs of its level lie below
+    pub fn above_older_runs(&self) -> CompactionContext {
+        CompactionContext {
+            snapshots: self.snapshots.clone(),
+            bottommost: false,
+            now: self.now,
+            filter: self.filter.clone(),
+            merge_operator: self.merge_operator.clone(),
+            filtered_keys: self.filtered_keys.clone(),
+        }
+    }
+
+    // Sequence number of the earliest snapshot that sees a version written at seq,
+    // u64::MAX if only reads of the latest state see it. Of several versions of a
+    // key in the same stripe, only the newest can be read.
+    fn stripe(&self, seq: u64) -> u64 {
+        match self.snapshots.binary_search(&seq) {
+            Ok(idx) => self.snapshots[idx],
+            Err(idx) => *self.snapshots.get(idx).unwrap_or(&u64::MAX),
+        }
+    }
+
+    // Versions of a key, newest first, that some read can still need: the newest
+    // of each stripe, with the merge operands in it applied to the value below
+    // them. A tombstone only hides older versions, so once it is the newest
+    // version every snapshot sees and nothing lies below, it and the versions it
+    // hides go. Range tombstones starting at the key join active_tombstones, the
+    // ones met so far whose range reaches the key, and are kept until the same
+    // holds for them.
+    fn compact_key(&self, versions: Vec<Record>, active_tombstones: &mut Vec<Record>, level: usize) -> Vec<Record> {
+        let key = versions[0].key.clone();
+        active_tombstones.retain(|tombstone| compare_keys(&tombstone.value, &key) != Ordering::Less);
+        let (tombstones, mut versions): (Vec<Record>, Vec<Record>) = versions.into_iter().partition(|version| version.is_range_deletion());
+        active_tombstones.extend(tombstones.iter().cloned());
+        // no read sees a version without the newer range tombstone of its stripe
+        versions.retain(|version| !active_tombstones.iter().any(|tombstone| tombstone.seq > version.seq && self.stripe(tombstone.seq) == self.stripe(version.seq)));
+
+        // an expired value hides older versions like a tombstone
+        for version in versions.iter_mut() {
+            if version.kind == RecordKind::Value && version.is_expired(self.now) {
+                *version = Record::create_tombstone(version.key.clone(), version.seq);
+            }
+        }
+        let earliest_stripe = *self.snapshots.first().unwrap_or(&u64::MAX);
+        let mut kept = Vec::new();
+        let mut first = 0;
+        while first < versions.len() {
+            let stripe = self.stripe(versions[first].seq);
+            let mut last = first + 1;
+            while last < versions.len() && self.stripe(versions[last].seq) == stripe {
+                last += 1;
+            }
+            let newest = &versions[first];
+            let mut stripe_kept = match newest.is_merge() {
+                true => self.fold_operands(&versions[first..last], last == versions.len()),
+                false => vec![newest.clone()],
+            };
+            if stripe == u64::MAX && stripe_kept[0].kind == RecordKind::Value {
+                stripe_kept[0] = self.apply_filter(stripe_kept[0].clone(), level);
+            }
+            stripe_kept.retain(|record| !(record.is_deletion() && self.bottommost && stripe == earliest_stripe));
+            kept.extend(stripe_kept);
+            first = last;
+        }
+        kept.extend(tombstones.into_iter().filter(|tombstone| !(self.bottommost && self.stripe(tombstone.seq) == earliest_stripe)));
+        kept.sort();
+        kept
+    }
+
+    // Whether a range tombstone hides every record of a file, so a merge can drop
+    // it unread. A file with range tombstones of its own is always read.
+    fn covers_file(&self, tombstones: &[Record], file: &DiskFile) -> bool {
+        file.range_tombstones.is_empty() && tombstones.iter().any(|tombstone| tombstone.covers(&file.first_key) && tombstone.covers(&file.last_key)
+            && tombstone.seq > file.max_seq && self.stripe(tombstone.seq) == self.stripe(file.min_seq))
+    }
+
+    fn apply_filter(&self, record: Record, level: usize) -> Record {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return record,
+        };
+        match filter.filter(level, &record.key, &record.value) {
+            FilterDecision::Keep => {
+                COMPACTION_FILTER_DECISIONS.with_label_values(&["keep"]).inc();
+                record
+            },
+            FilterDecision::Remove => {
+                COMPACTION_FILTER_DECISIONS.with_label_values(&["remove"]).inc();
+                self.filtered_keys.lock().push(record.key.clone());
+                Record::create_tombstone(record.key, record.seq)
+            },
+            FilterDecision::ChangeValue(value) => {
+                COMPACTION_FILTER_DECISIONS.with_label_values(&["change_value"]).inc();
+                self.filtered_keys.lock().push(record.key.clone());
+                Record {
+                    value,
+                    ..record
+                }
+            },
+        }
+    }
+
+    // Applies the merge operands at the head of a stripe's versions, newest first,
+    // to the value below them. If the value is in an older stripe or file, the
+    // operands are combined into one if the operator allows it.
+    fn fold_operands(&self, versions: &[Record], oldest_stripe: bool) -> Vec<Record> {
+        let newest = &versions[0];
+        let operands_end = versions.iter().position(|version| !version.is_merge()).unwrap_or(versions.len());
+        if operands_end < versions.len() || (oldest_stripe && self.bottommost) {
+            let value = resolve_versions(self.merge_operator.as_deref(), versions).unwrap();
+            return vec![Record::create_record(newest.key.clone(), value, newest.seq)];
+        }
+        let operands: Vec<&[u8]> = versions.iter().rev().map(|version| &version.value[..]).collect();
+        let merge_operator = self.merge_operator.as_ref().expect("Merge operands found, but the tree has no merge operator, see TreeOptions::merge_operator");
+        match merge_operator.partial_merge(&newest.key, &operands) {
+            Some(operand) => vec![Record::create_merge_operand(newest.key.clone(), operand, newest.seq)],
+            None => versions.to_vec(),
+        }
+    }
+
+    // drops the versions no snapshot can read from records sorted by key, newest version first, written to level
+    pub fn retain_versions(&self, records: Vec<Record>, level: usize) -> Vec<Record> {
+        let mut kept = Vec::new();
+        let mut key_versions: Vec<Record> = Vec::new();
+        let mut active_tombstones = Vec::new();
+        for record in records.into_iter() {
+            if !key_versions.is_empty() && key_versions[0].key != record.key {
+                kept.extend(self.compact_key(std::mem::take(&mut key_versions), &mut active_tombstones, level));
+            }
+            key_versions.push(record);
+        }
+        if !key_versions.is_empty() {
+            kept.extend(self.compact_key(key_versions, &mut active_tombstones, level));
+        }
+        kept
+    }
+}
+
+pub fn merge_from_files(mut files_to_merge: Vec<Vec<Arc<DiskFile>>>, run_merge_into: &Run, context: &CompactionContext) -> Vec<Arc<DiskFile>> {
+    let mut merged_runs: Vec<Record> = Vec::new();
+    let mut merged_size: usize = 0;
     let mut merged_files: Vec<Arc<DiskFile>> = Vec::new();
     let mut heap = BinaryHeap::new();
     let mut runs_to_merge = Vec::new();
+    let mut active_tombstones = Vec::new();
+
+    // files whose records a range tombstone hides are not worth reading
+    let tombstones: Vec<Record> = files_to_merge.iter().flatten().flat_map(|file| file.range_tombstones.iter().cloned()).collect();
+    if !tombstones.is_empty() {
+        for files in files_to_merge.iter_mut() {
+            files.retain(|file| {
+                let covered = context.covers_file(&tombstones, file);
+                if covered {
+                    COMPACTION_FILES_SKIPPED.inc();
+                }
+                !covered
+            });
+        }
+        files_to_merge.retain(|files| !files.is_empty());
+    }
     for i in 0..files_to_merge.len() {
         let file_records = files_to_merge[i][0].read_all_file_records();
-        PUT_IO_COUNTER.inc_by((files_to_merge[i][0].size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
-        let first = file_records[0];
+        PUT_IO_COUNTER.inc_by((files_to_merge[i][0].disk_size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
+        let first = file_records[0].clone();
         files_to_merge[i].remove(0);
         runs_to_merge.push(file_records);
         heap.push(HeapNode::create_heap_node(first, i, 1));
     }
-    let mut last_key = 0;
+    let mut key_versions: Vec<Record> = Vec::new(); // versions of the key being merged, newest first
     while let Some(HeapNode {element, run_idx, mut next_ele_idx}) = heap.pop() {
-        if merged_runs.len() == 0 || element.key != last_key {
-            merged_runs.extend(&element.key.to_be_bytes());
-            merged_runs.extend(&element.value.to_be_bytes());
-            last_key = element.key;
+        if !key_versions.is_empty() && key_versions[0].key != element.key {
+            let versions = context.compact_key(std::mem::take(&mut key_versions), &mut active_tombstones, run_merge_into.level);
+            add_key_versions(versions, &mut merged_runs, &mut merged_size, run_merge_into, &mut merged_files);
         }
+        key_versions.push(element);
         // re-fill runs_to_merge[run_idx]
         if next_ele_idx == runs_to_merge[run_idx].len() && files_to_merge[run_idx].len() > 0 {
             runs_to_merge[run_idx] = files_to_merge[run_idx][0].read_all_file_records();
-            PUT_IO_COUNTER.inc_by((files_to_merge[run_idx][0].size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
+            PUT_IO_COUNTER.inc_by((files_to_merge[run_idx][0].disk_size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
             files_to_merge[run_idx].remove(0);
             next_ele_idx = 0;
         }
         // add the next element in runs_to_merge[run_idx] to heap
         if next_ele_idx < runs_to_merge[run_idx].len() {
-            let next_element = runs_to_merge[run_idx][next_ele_idx];
-            assert!(next_element.key >= last_key);
+            let next_element = runs_to_merge[run_idx][next_ele_idx].clone();
+            assert!(compare_keys(&next_element.key, &key_versions[0].key) != Ordering::Less);
             let new_node = HeapNode::create_heap_node(next_element, run_idx, next_ele_idx + 1);
             heap.push(new_node);
         }
-        // write file out
-        if merged_runs.len() == CONFIGURATION.FILE_SIZE {
-            let merged_file = DiskFile::create_disk_file(generate_filename(run_merge_into.level, run_merge_into.run, run_merge_into.file_counter.get()), &merged_runs, merged_runs.len());
-            PUT_IO_COUNTER.inc_by((merged_file.size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
-            merged_files.push(Arc::new(merged_file));
-            run_merge_into.file_counter.inc();
-            merged_runs.clear();
-        } 
+    }
+    if !key_versions.is_empty() {
+        add_key_versions(context.compact_key(key_versions, &mut active_tombstones, run_merge_into.level), &mut merged_runs, &mut merged_size, run_merge_into, &mut merged_files);
     }
     // write rest of data out into file
     if merged_runs.len() > 0 {
-        let merged_file = DiskFile::create_disk_file(generate_filename(run_merge_into.level, run_merge_into.run, run_merge_into.file_counter.get()), &merged_runs, merged_runs.len());
-        PUT_IO_COUNTER.inc_by((merged_file.size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
-        merged_files.push(Arc::new(merged_file));
-        run_merge_into.file_counter.inc();
+        write_merged_file(&merged_runs, run_merge_into, &mut merged_files);
         merged_runs.clear();
     }
     merged_files
 }
 
+// write file out before it goes over FILE_SIZE, keeping the versions of a key together
+fn add_key_versions(versions: Vec<Record>, merged_runs: &mut Vec<Record>, merged_size: &mut usize, run_merge_into: &Run, merged_files: &mut Vec<Arc<DiskFile>>) {
+    if versions.is_empty() {
+        return;
+    }
+    if *merged_size > 0 && *merged_size + versions[0].size() > CONFIGURATION.FILE_SIZE {
+        write_merged_file(merged_runs, run_merge_into, merged_files);
+        merged_runs.clear();
+        *merged_size = 0;
+    }
+    for version in versions.into_iter() {
+        *merged_size += version.size();
+        merged_runs.push(version);
+    }
+}
+
+fn write_merged_file(records: &[Record], run_merge_into: &Run, merged_files: &mut Vec<Arc<DiskFile>>) {
+    let merged_file = DiskFile::create_disk_file(generate_filename(&run_merge_into.dir, run_merge_into.level, run_merge_into.run, run_merge_into.file_counter.get()), run_merge_into.level, records);
+    PUT_IO_COUNTER.inc_by((merged_file.disk_size as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
+    merged_files.push(Arc::new(merged_file));
+    run_merge_into.file_counter.inc();
+}
+
 // pub fn merge_k_sorted(vectors_to_merge: Vec<Vec<Record>>) -> Vec<Record> {
 //     let mut merged_vectors: Vec<Record> = Vec::new();
 //     let mut heap = BinaryHeap::new();
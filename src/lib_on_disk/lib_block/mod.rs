@@ -0,0 +1,284 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+
+use crate::configuration::CONFIGURATION;
+use crate::lib_template::{Record, RecordKind, compare_keys, is_bytewise};
+use super::lib_int_block::{encode_int_block, decode_int_block, search_int_block, packed_size, to_u32};
+
+// The last byte of a block tags its format.
+const PREFIX_BLOCK: u8 = 0;
+const INT_BLOCK: u8 = 1;
+
+// Prefix block layout:
+//     entry*  restart offset (u32)*  number of restarts (u32)  PREFIX_BLOCK
+// entry:
+//     shared key bytes (varint)  unshared key bytes (varint)  value bytes (varint)  sequence number (varint)  kind (u8)  expiry time (varint)  key suffix  value
+// Every BLOCK_RESTART_INTERVAL entries the key is stored whole (shared = 0) and
+// its offset is recorded as a restart point, so lookups can binary search the
+// restart points and then scan at most one interval.
+// Blocks of 4-byte keys and values, without tombstones or expiry times, are
+// instead written in the bit-packed layout of lib_int_block when that is smaller.
+
+pub fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+pub fn get_varint(bytes: &[u8], offset: &mut usize) -> u64 {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*offset];
+        *offset += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+pub fn varint_size(mut value: u64) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+// bytes of an entry whose key shares its first shared bytes with the previous key
+pub fn entry_size(shared: usize, key_len: usize, value_len: usize, seq: u64, expire_at: u64) -> usize {
+    let unshared = key_len - shared;
+    varint_size(shared as u64) + varint_size(unshared as u64) + varint_size(value_len as u64) + varint_size(seq) + 1 + varint_size(expire_at) + unshared + value_len
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+pub struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    counter: usize, // entries since the last restart point
+    last_key: Vec<u8>,
+    // kept while every record added fits the bit-packed layout
+    int_eligible: bool,
+    int_keys: Vec<u32>,
+    int_values: Vec<u32>,
+    int_seqs: Vec<u64>,
+    min_value: u32,
+    max_value: u32,
+    min_seq: u64,
+    max_seq: u64,
+}
+
+impl Default for BlockBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder {
+            buffer: Vec::new(),
+            restarts: Vec::new(),
+            counter: 0,
+            last_key: Vec::new(),
+            int_eligible: CONFIGURATION.INT_BLOCK_ENCODING && is_bytewise(),
+            int_keys: Vec::new(),
+            int_values: Vec::new(),
+            int_seqs: Vec::new(),
+            min_value: u32::MAX,
+            max_value: 0,
+            min_seq: u64::MAX,
+            max_seq: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.restarts.len() == 0
+    }
+
+    // size of the finished block
+    pub fn size(&self) -> usize {
+        let prefix_size = self.prefix_size();
+        if self.int_eligible && !self.is_empty() {
+            let packed = packed_size(self.int_keys.len(), self.int_keys.last().unwrap() - self.int_keys[0], self.max_value - self.min_value, self.max_seq - self.min_seq) + 1;
+            return std::cmp::min(prefix_size, packed);
+        }
+        prefix_size
+    }
+
+    fn prefix_size(&self) -> usize {
+        self.buffer.len() + 4 * self.restarts.len() + 5
+    }
+
+    fn fits_int_block(&self, record: &Record) -> bool {
+        self.int_eligible && record.kind == RecordKind::Value && record.expire_at == 0 && record.key.len() == 4 && record.value.len() == 4
+    }
+
+    // size of the finished block if record were added to it
+    pub fn size_with(&self, record: &Record) -> usize {
+        let restart = self.is_empty() || self.counter == CONFIGURATION.BLOCK_RESTART_INTERVAL;
+        let shared = if restart { 0 } else { shared_prefix_len(&self.last_key, &record.key) };
+        let entry_size = entry_size(shared, record.key.len(), record.value.len(), record.seq, record.expire_at);
+        let prefix_size = self.prefix_size() + entry_size + if restart { 4 } else { 0 };
+        if self.fits_int_block(record) {
+            let key = to_u32(&record.key);
+            let value = to_u32(&record.value);
+            let base_key = if self.is_empty() { key } else { self.int_keys[0] };
+            let value_range = std::cmp::max(self.max_value, value) - std::cmp::min(self.min_value, value);
+            let seq_range = std::cmp::max(self.max_seq, record.seq) - std::cmp::min(self.min_seq, record.seq);
+            let packed = packed_size(self.int_keys.len() + 1, key - base_key, value_range, seq_range) + 1;
+            return std::cmp::min(prefix_size, packed);
+        }
+        prefix_size
+    }
+
+    pub fn add(&mut self, record: &Record) {
+        if self.fits_int_block(record) {
+            let value = to_u32(&record.value);
+            self.int_keys.push(to_u32(&record.key));
+            self.int_values.push(value);
+            self.int_seqs.push(record.seq);
+            self.min_value = std::cmp::min(self.min_value, value);
+            self.max_value = std::cmp::max(self.max_value, value);
+            self.min_seq = std::cmp::min(self.min_seq, record.seq);
+            self.max_seq = std::cmp::max(self.max_seq, record.seq);
+        } else {
+            self.int_eligible = false;
+        }
+        let mut shared = 0;
+        if self.is_empty() || self.counter == CONFIGURATION.BLOCK_RESTART_INTERVAL {
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+        } else {
+            shared = shared_prefix_len(&self.last_key, &record.key);
+        }
+        let unshared = record.key.len() - shared;
+        put_varint(&mut self.buffer, shared as u64);
+        put_varint(&mut self.buffer, unshared as u64);
+        put_varint(&mut self.buffer, record.value.len() as u64);
+        put_varint(&mut self.buffer, record.seq);
+        self.buffer.push(record.kind.to_byte());
+        put_varint(&mut self.buffer, record.expire_at);
+        self.buffer.extend(&record.key[shared..]);
+        self.buffer.extend(&record.value);
+        self.last_key = record.key.clone();
+        self.counter += 1;
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        let use_int_block = self.int_eligible && self.size() < self.prefix_size();
+        let mut block = std::mem::take(&mut self.buffer);
+        if use_int_block {
+            block = encode_int_block(&self.int_keys, &self.int_values, &self.int_seqs);
+            block.push(INT_BLOCK);
+        } else {
+            for restart in self.restarts.iter() {
+                block.extend(&restart.to_be_bytes());
+            }
+            block.extend(&(self.restarts.len() as u32).to_be_bytes());
+            block.push(PREFIX_BLOCK);
+        }
+        self.restarts.clear();
+        self.counter = 0;
+        self.last_key.clear();
+        self.int_eligible = CONFIGURATION.INT_BLOCK_ENCODING && is_bytewise();
+        self.int_keys.clear();
+        self.int_values.clear();
+        self.int_seqs.clear();
+        self.min_value = u32::MAX;
+        self.max_value = 0;
+        self.min_seq = u64::MAX;
+        self.max_seq = 0;
+        block
+    }
+}
+
+// Returns (end of the entries, number of restart points). The restart offsets
+// follow the entries, read them with restart_offset.
+fn parse_trailer(block: &[u8]) -> (usize, usize) {
+    let num_restarts = u32::from_be_bytes(block[block.len() - 4..].try_into().unwrap()) as usize;
+    (block.len() - 4 - 4 * num_restarts, num_restarts)
+}
+
+// offset of the entry at restart point idx
+fn restart_offset(block: &[u8], entries_end: usize, idx: usize) -> usize {
+    let offset = entries_end + 4 * idx;
+    u32::from_be_bytes(block[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+// Decodes the entry at offset on top of the previous key, in place.
+// Returns the value range, the sequence number, the kind, the expiry time and the offset of the next entry.
+fn decode_entry(block: &[u8], offset: usize, key: &mut Vec<u8>) -> (std::ops::Range<usize>, u64, RecordKind, u64, usize) {
+    let mut offset = offset;
+    let shared = get_varint(block, &mut offset) as usize;
+    let unshared = get_varint(block, &mut offset) as usize;
+    let value_len = get_varint(block, &mut offset) as usize;
+    let seq = get_varint(block, &mut offset);
+    let kind = RecordKind::from_byte(block[offset]);
+    offset += 1;
+    let expire_at = get_varint(block, &mut offset);
+    key.truncate(shared);
+    key.extend(&block[offset..offset + unshared]);
+    let value_start = offset + unshared;
+    (value_start..value_start + value_len, seq, kind, expire_at, value_start + value_len)
+}
+
+pub fn decode_block(block: &[u8]) -> Vec<Record> {
+    let (block, format) = block.split_at(block.len() - 1);
+    if format[0] == INT_BLOCK {
+        return decode_int_block(block);
+    }
+    let (entries_end, _) = parse_trailer(block);
+    let mut records = Vec::new();
+    let mut key = Vec::new();
+    let mut offset = 0;
+    while offset < entries_end {
+        let (value, seq, kind, expire_at, next) = decode_entry(block, offset, &mut key);
+        records.push(Record { key: key.clone(), value: block[value].to_vec(), seq, kind, expire_at });
+        offset = next;
+    }
+    records
+}
+
+// Newest version of key with a sequence number <= seq. Binary search over the
+// restart points, then a scan from the last restart point before the key.
+pub fn search_block(block: &[u8], key: &[u8], seq: u64) -> Option<Record> {
+    let (block, format) = block.split_at(block.len() - 1);
+    if format[0] == INT_BLOCK {
+        return search_int_block(block, key, seq);
+    }
+    let (entries_end, num_restarts) = parse_trailer(block);
+    let mut restart_key = Vec::new();
+    let mut low = 0;
+    let mut high = num_restarts;
+    // find the first restart point whose key is >= key, versions of key may start before it
+    while low < high {
+        let mid = low + (high - low) / 2;
+        restart_key.clear();
+        decode_entry(block, restart_offset(block, entries_end, mid), &mut restart_key);
+        if compare_keys(&restart_key, key) == Ordering::Less {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let mut offset = restart_offset(block, entries_end, low.max(1) - 1);
+    let mut current = Vec::new();
+    while offset < entries_end {
+        let (value, version, kind, expire_at, next) = decode_entry(block, offset, &mut current);
+        match compare_keys(&current, key) {
+            Ordering::Equal if version <= seq => return Some(Record { key: current, value: block[value].to_vec(), seq: version, kind, expire_at }),
+            Ordering::Greater => return None,
+            _ => offset = next,
+        }
+    }
+    None
+}
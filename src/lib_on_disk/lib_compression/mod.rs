@@ -0,0 +1,146 @@
+use std::borrow::Cow;
+
+use crate::configuration::{CONFIGURATION, CompressionType};
+
+/// Codec applied to each data block. The id is stored in the last byte of the
+/// block on disk, so blocks written with different codecs can be read back
+/// regardless of the current configuration.
+pub trait Compressor: Send + Sync {
+    fn id(&self) -> u8;
+    fn name(&self) -> &str;
+    fn compress(&self, bytes: &[u8]) -> Vec<u8>;
+    fn decompress(&self, bytes: &[u8]) -> Option<Vec<u8>>; // None if bytes are not in the codec's format
+}
+
+pub struct NoCompressor;
+
+impl Compressor for NoCompressor {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.to_vec()
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        Some(bytes.to_vec())
+    }
+}
+
+// Fast, moderate ratio. Suited to the upper levels, which are rewritten often.
+pub struct Lz4Compressor;
+
+impl Compressor for Lz4Compressor {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(bytes)
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(bytes).ok()
+    }
+}
+
+// LZ77 + Huffman coding at the highest level. Slower, better ratio, for the bottom levels.
+pub struct DeflateCompressor;
+
+impl Compressor for DeflateCompressor {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        miniz_oxide::deflate::compress_to_vec(bytes, 10)
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        miniz_oxide::inflate::decompress_to_vec(bytes).ok()
+    }
+}
+
+static NONE: NoCompressor = NoCompressor;
+static LZ4: Lz4Compressor = Lz4Compressor;
+static DEFLATE: DeflateCompressor = DeflateCompressor;
+
+// None if no codec has the id, as in a damaged block
+pub fn compressor_by_id(id: u8) -> Option<&'static dyn Compressor> {
+    match id {
+        0 => Some(&NONE),
+        1 => Some(&LZ4),
+        2 => Some(&DEFLATE),
+        _ => None,
+    }
+}
+
+// codec a block written by compress_block was stored with, None if its id is unknown
+pub fn block_compressor(bytes: &[u8]) -> Option<&'static dyn Compressor> {
+    if bytes.len() < 5 {
+        return None;
+    }
+    compressor_by_id(bytes[bytes.len() - 5])
+}
+
+// Levels past the end of CONFIGURATION.COMPRESSION use its last entry
+pub fn compressor_for_level(level: usize) -> &'static dyn Compressor {
+    let types = &CONFIGURATION.COMPRESSION;
+    let idx = std::cmp::min(level.max(1) - 1, types.len() - 1);
+    match types[idx] {
+        CompressionType::NONE => &NONE,
+        CompressionType::LZ4 => &LZ4,
+        CompressionType::DEFLATE => &DEFLATE,
+    }
+}
+
+// Compresses a block and appends the codec id, then a checksum of both. Blocks
+// that do not shrink are stored uncompressed so reads skip the decompression.
+pub fn compress_block(block: &[u8], compressor: &dyn Compressor) -> Vec<u8> {
+    let mut compressed = compressor.compress(block);
+    let mut id = compressor.id();
+    if compressed.len() >= block.len() {
+        compressed = block.to_vec();
+        id = NONE.id();
+    }
+    compressed.push(id);
+    let checksum = crc32fast::hash(&compressed);
+    compressed.extend(&checksum.to_be_bytes());
+    compressed
+}
+
+// whether a block written by compress_block reads back as it was written
+pub fn verify_block(bytes: &[u8]) -> bool {
+    if bytes.len() < 5 {
+        return false;
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    crc32fast::hash(body).to_be_bytes() == checksum
+}
+
+// Uncompressed blocks are returned in place. Errors say what is wrong with a
+// damaged block.
+pub fn decompress_block(bytes: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    if !verify_block(bytes) {
+        return Err("block checksum mismatch".to_string());
+    }
+    let (payload, id) = bytes[..bytes.len() - 4].split_at(bytes.len() - 5);
+    match compressor_by_id(id[0]) {
+        Some(_) if id[0] == NONE.id() => Ok(Cow::Borrowed(payload)),
+        Some(compressor) => compressor.decompress(payload).map(Cow::Owned).ok_or_else(|| format!("block does not decompress with {}", compressor.name())),
+        None => Err(format!("unknown block compression id {}", id[0])),
+    }
+}
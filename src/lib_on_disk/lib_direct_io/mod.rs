@@ -0,0 +1,108 @@
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+// O_DIRECT wants buffers, offsets and lengths aligned to the logical block size
+// of the device, which has nothing to do with the block size of our files
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Heap buffer whose address and length are multiples of `DIRECT_IO_ALIGNMENT`,
+/// as required for reads and writes on files opened with `O_DIRECT`.
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(align_up(std::cmp::max(len, 1)), DIRECT_IO_ALIGNMENT).expect("Invalid layout for aligned buffer");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer {
+            ptr,
+            layout,
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+pub fn align_down(offset: usize) -> usize {
+    offset - offset % DIRECT_IO_ALIGNMENT
+}
+
+pub fn align_up(offset: usize) -> usize {
+    align_down(offset + DIRECT_IO_ALIGNMENT - 1)
+}
+
+pub fn open_direct(filename: &String) -> File {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(filename)
+        .unwrap_or_else(|error| panic!("Failed to open file {} with O_DIRECT: {}", filename, error))
+}
+
+// The data is padded up to a whole number of blocks for the write,
+// then the file is truncated back to its logical size and synced.
+pub fn write_direct(filename: &String, data: &[u8]) {
+    let mut buffer = AlignedBuffer::new(data.len());
+    buffer[..data.len()].copy_from_slice(data);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(filename)
+        .unwrap_or_else(|error| panic!("Failed to create file {} with O_DIRECT: {}", filename, error));
+    file.write_all(&buffer[..]).unwrap_or_else(|error| panic!("Failed to write to file {}: {}", filename, error));
+    file.set_len(data.len() as u64).unwrap_or_else(|error| panic!("Failed to truncate file {}: {}", filename, error));
+    file.sync_all().unwrap_or_else(|error| panic!("Failed to sync file {}: {}", filename, error));
+}
+
+// Reads the aligned range covering [start_offset, start_offset + buffer.len()) and
+// copies the requested part into buffer. Returns the number of bytes copied.
+pub fn read_direct(file: &File, start_offset: usize, buffer: &mut [u8]) -> usize {
+    let aligned_start = align_down(start_offset);
+    let aligned_end = align_up(start_offset + buffer.len());
+    let mut aligned = AlignedBuffer::new(aligned_end - aligned_start);
+    let mut bytes_read = 0;
+    while aligned_start + bytes_read < aligned_end {
+        let n = file.read_at(&mut aligned[bytes_read..], (aligned_start + bytes_read) as u64).expect("Failed to read file with O_DIRECT");
+        if n == 0 {
+            break;
+        }
+        bytes_read += n;
+    }
+    let skip = start_offset - aligned_start;
+    if bytes_read <= skip {
+        return 0;
+    }
+    let available = std::cmp::min(bytes_read - skip, buffer.len());
+    buffer[..available].copy_from_slice(&aligned[skip..skip + available]);
+    available
+}
@@ -1,116 +1,510 @@
-use std::convert::TryInto;
-use std::fs::{File};
-use std::io::{SeekFrom};
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::io::{ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::path::{Path};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 
-use crate::configuration::CONFIGURATION;
-use crate::lib_helper::{bytes_to_records, binary_search_fp};
-use crate::lib_template::{Record};
-use crate::metrics::{GET_IO_COUNTER};
+use crate::configuration::{CONFIGURATION, ReadMode};
+use crate::lib_helper::{binary_search_fp, temporary_filename, install_file};
+use crate::lib_template::{Record, compare_keys};
+use crate::metrics::{GET_IO_COUNTER, BLOCK_BYTES_UNCOMPRESSED, BLOCK_BYTES_COMPRESSED};
+use super::lib_file_cache::{FILE_HANDLE_CACHE};
+use super::lib_direct_io::{write_direct, read_direct};
+use super::lib_block::{BlockBuilder, decode_block, search_block, put_varint, get_varint};
+use super::lib_compression::{compressor_for_level, compress_block, decompress_block, verify_block};
+use std::borrow::Cow;
 
 use bloom::{BloomFilter};
+use log::{error};
+use memmap::{Mmap};
+
+// The blocks are followed by a footer, so a file can be opened again without
+// the tree that wrote it:
+//     block offsets (varint each)  range deletion block offset (varint)  blocks size (u64)  global seq (u64)  blocks crc32 (u32)  data blocks (u32)  magic (u32)
+// The global sequence number is 0 unless the file was ingested, see set_global_seq.
+const TABLE_MAGIC: u32 = 0x4c534d54;
+const FOOTER_TRAILER_SIZE: usize = 28;
+// ends the errors of damaged files
+pub const REPAIR_HINT: &str = "the repair command rebuilds the tree without the damaged data";
+// the records of a block read by salvage_blocks, or its size on disk if it is damaged
+pub type SalvagedBlock = Result<Vec<Record>, usize>;
 
 pub struct DiskFile {
     pub filename: String,
-    pub size: usize,
-    pub fence_pointers: Vec<i32>,
+    pub size: usize, // bytes of the records it holds, as accounted for by runs and levels
+    pub disk_size: usize, // bytes of the encoded blocks on disk
+    pub uncompressed_size: usize, // bytes of the encoded blocks before compression
+    pub fence_pointers: Vec<Vec<u8>>, // first key of each data block
+    pub block_offsets: Vec<usize>, // start offset of each data block
+    pub first_key: Vec<u8>, // smallest key of a record or range tombstone in the file
+    pub last_key: Vec<u8>, // largest key of a record or range tombstone end in the file
+    pub range_tombstones: Vec<Record>, // also stored in the range deletion block after the data blocks
+    pub range_del_offset: usize, // start offset of the range deletion block, disk_size if there is none
+    pub min_seq: u64,
+    pub max_seq: u64,
+    pub global_seq: u64, // if not 0, the sequence number of every record, whatever the blocks hold
+    pub checksum: u32, // crc32 of the blocks, taken as they are written
     bloom_filter: BloomFilter,
+    mmap: Option<Mmap>, // only set in ReadMode::MMAP
+    obsolete: AtomicBool, // no longer part of the tree, removed from disk once the last holder drops it
 }
 
 impl DiskFile {
 
-    pub fn create_disk_file(filename: String, data: &[u8], size: usize) -> DiskFile {
-        let mut fence_pointers: Vec<i32> = Vec::new();
-        let mut bloom_filter = DiskFile::init_bloom_filter(size);
+    // Records are packed into blocks of at most BLOCK_SIZE encoded bytes. Neither a
+    // record nor the versions of a key are split across blocks, so the fence
+    // pointers are distinct and each covers whole keys, and a block runs over
+    // when the versions of its last key do not fit. Each block is then
+    // compressed with the codec configured for the level. Range tombstones go
+    // in a single block of their own after the data blocks.
+    pub fn create_disk_file(filename: String, level: usize, all_records: &[Record]) -> DiskFile {
+        assert!(!all_records.is_empty());
+        let (range_tombstones, records): (Vec<Record>, Vec<Record>) = all_records.iter().cloned().partition(|record| record.is_range_deletion());
+        let mut fence_pointers: Vec<Vec<u8>> = Vec::new();
+        let mut block_offsets: Vec<usize> = Vec::new();
+        let mut bloom_filter = DiskFile::init_bloom_filter(std::cmp::max(records.len(), 1));
+        let mut data: Vec<u8> = Vec::new();
+        let mut block = BlockBuilder::new();
+        let mut size = 0;
+        let mut uncompressed_size = 0;
+        let compressor = compressor_for_level(level);
 
         // initialize bloom filters and fence pointers
-        for i in (0..size).step_by(CONFIGURATION.RECORD_SIZE) {
-            let key = i32::from_be_bytes(data[i..i + CONFIGURATION.KEY_SIZE].try_into().unwrap());
+        for (i, record) in records.iter().enumerate() {
+            let key: &[u8] = &record.key;
             bloom_filter.insert(&key);
-            if (i % CONFIGURATION.BLOCK_SIZE) == 0 || i == size - CONFIGURATION.RECORD_SIZE {
-                fence_pointers.push(key);
+            let new_key = i == 0 || records[i - 1].key != record.key;
+            if !block.is_empty() && new_key && block.size_with(record) > CONFIGURATION.BLOCK_SIZE {
+                let raw_block = block.finish();
+                uncompressed_size += raw_block.len();
+                data.extend(compress_block(&raw_block, compressor));
             }
+            if block.is_empty() {
+                fence_pointers.push(record.key.clone());
+                block_offsets.push(data.len());
+            }
+            block.add(record);
+            size += record.size();
         }
+        if !block.is_empty() {
+            let raw_block = block.finish();
+            uncompressed_size += raw_block.len();
+            data.extend(compress_block(&raw_block, compressor));
+        }
+        let range_del_offset = data.len();
+        if !range_tombstones.is_empty() {
+            let mut block = BlockBuilder::new();
+            for tombstone in range_tombstones.iter() {
+                block.add(tombstone);
+                size += tombstone.size();
+            }
+            let raw_block = block.finish();
+            uncompressed_size += raw_block.len();
+            data.extend(compress_block(&raw_block, compressor));
+        }
+        let disk_size = data.len();
+        let checksum = crc32fast::hash(&data);
+        DiskFile::encode_footer(&block_offsets, range_del_offset, checksum, &mut data);
+        BLOCK_BYTES_UNCOMPRESSED.with_label_values(&[&level.to_string()]).inc_by(uncompressed_size as i64);
+        BLOCK_BYTES_COMPRESSED.with_label_values(&[&level.to_string()]).inc_by(disk_size as i64);
 
-        let mut file = File::create(&filename).expect(&format!("Failed to create file {}", &filename));
-        let bytes_written = file.write(&data[..]).expect(&format!("Failed to write to file {}", &filename));
-
-        assert!(size == bytes_written);
+        // written and synced under a temporary name first, so a crash never leaves a partial file under the real one
+        let tmp_filename = temporary_filename(&filename);
+        if CONFIGURATION.DIRECT_IO {
+            write_direct(&tmp_filename, &data[..]);
+        } else {
+            let mut file = File::create(&tmp_filename).unwrap_or_else(|error| panic!("Failed to create file {}: {}", tmp_filename, error));
+            file.write_all(&data[..]).unwrap_or_else(|error| panic!("Failed to write to file {}: {}", tmp_filename, error));
+            file.sync_all().unwrap_or_else(|error| panic!("Failed to sync file {}: {}", tmp_filename, error));
+        }
+        install_file(&tmp_filename, &filename);
 
+        let mmap = DiskFile::map_file(&filename);
         DiskFile {
-            filename: filename,
+            filename,
             size: size,
-            fence_pointers: fence_pointers,
-            bloom_filter: bloom_filter,
+            disk_size,
+            uncompressed_size,
+            fence_pointers,
+            block_offsets,
+            first_key: all_records[0].key.clone(),
+            last_key: last_key_of(all_records),
+            range_tombstones,
+            range_del_offset,
+            min_seq: all_records.iter().map(|record| record.seq).min().unwrap(),
+            max_seq: all_records.iter().map(|record| record.seq).max().unwrap(),
+            global_seq: 0,
+            checksum,
+            bloom_filter,
+            mmap,
+            obsolete: AtomicBool::new(false),
         }
     }
 
-    pub fn get(&self, key: &i32, record: &mut Record) -> bool {
-        // if bloom filter does not contain key or key bigger than max fence pointer
-        if !self.bloom_filter.contains(key) || key > self.fence_pointers.last().unwrap() {
-            return false;
+    pub fn open_disk_file(filename: String) -> DiskFile {
+        DiskFile::try_open_disk_file(filename).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    // Reads back a file written by create_disk_file, rebuilding the bloom filter
+    // and fence pointers from its records. Errors if its footer or a block is
+    // damaged.
+    pub fn try_open_disk_file(filename: String) -> Result<DiskFile, String> {
+        let bytes = fs::read(&filename).unwrap_or_else(|error| panic!("Failed to read file {}: {}", filename, error));
+        let (block_offsets, range_del_offset, disk_size, global_seq, checksum) = DiskFile::decode_footer(&bytes, &filename)?;
+        let mut fence_pointers: Vec<Vec<u8>> = Vec::new();
+        let mut records = Vec::new();
+        // the size of the records as the blocks hold them, whatever the global sequence number
+        let mut size = 0;
+        let mut uncompressed_size = 0;
+        for block_idx in 0..block_offsets.len() {
+            let end = *block_offsets.get(block_idx + 1).unwrap_or(&range_del_offset);
+            let raw_block = read_block_bytes_of(&filename, Some(block_idx), &bytes[block_offsets[block_idx]..end])?;
+            uncompressed_size += raw_block.len();
+            let block_records = decode_block(&raw_block);
+            size += block_records.iter().map(|record| record.size()).sum::<usize>();
+            let block_records = apply_global_seq(block_records, global_seq);
+            fence_pointers.push(block_records[0].key.clone());
+            records.extend(block_records);
         }
-        let file_offset = match binary_search_fp(&self.fence_pointers, &key) {
-            Some(idx) => {
-                if idx == self.fence_pointers.len() - 1 {
-                    idx - 1
-                } else {
-                    idx
-                }
+        let mut range_tombstones = Vec::new();
+        if range_del_offset < disk_size {
+            let raw_block = read_block_bytes_of(&filename, None, &bytes[range_del_offset..disk_size])?;
+            uncompressed_size += raw_block.len();
+            range_tombstones = decode_block(&raw_block);
+            size += range_tombstones.iter().map(|record| record.size()).sum::<usize>();
+            range_tombstones = apply_global_seq(range_tombstones, global_seq);
+        }
+        let mut bloom_filter = DiskFile::init_bloom_filter(std::cmp::max(records.len(), 1));
+        for record in records.iter() {
+            let key: &[u8] = &record.key;
+            bloom_filter.insert(&key);
+        }
+        let mut all_records = records;
+        all_records.extend(range_tombstones.iter().cloned());
+        all_records.sort();
+        assert!(!all_records.is_empty(), "File {} holds no records", filename);
+
+        let mmap = DiskFile::map_file(&filename);
+        Ok(DiskFile {
+            filename,
+            size,
+            disk_size,
+            uncompressed_size,
+            fence_pointers,
+            block_offsets,
+            first_key: all_records[0].key.clone(),
+            last_key: last_key_of(&all_records),
+            range_tombstones,
+            range_del_offset,
+            min_seq: all_records.iter().map(|record| record.seq).min().unwrap(),
+            max_seq: all_records.iter().map(|record| record.seq).max().unwrap(),
+            global_seq,
+            checksum,
+            bloom_filter,
+            mmap,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    fn encode_footer(block_offsets: &[usize], range_del_offset: usize, checksum: u32, data: &mut Vec<u8>) {
+        let disk_size = data.len();
+        for offset in block_offsets.iter() {
+            put_varint(data, *offset as u64);
+        }
+        put_varint(data, range_del_offset as u64);
+        data.extend(&(disk_size as u64).to_be_bytes());
+        data.extend(&0u64.to_be_bytes());
+        data.extend(&checksum.to_be_bytes());
+        data.extend(&(block_offsets.len() as u32).to_be_bytes());
+        data.extend(&TABLE_MAGIC.to_be_bytes());
+    }
+
+    // block offsets, range deletion block offset, size of the blocks, global sequence number and crc32 of the blocks
+    fn decode_footer(bytes: &[u8], filename: &str) -> Result<(Vec<usize>, usize, usize, u64, u32), String> {
+        if bytes.len() < FOOTER_TRAILER_SIZE {
+            return Err(format!("File {} is too short to hold a footer, {}", filename, REPAIR_HINT));
+        }
+        let trailer = &bytes[bytes.len() - FOOTER_TRAILER_SIZE..];
+        let magic = u32::from_be_bytes(trailer[24..28].try_into().unwrap());
+        if magic != TABLE_MAGIC {
+            return Err(format!("File {} is not a table file, {}", filename, REPAIR_HINT));
+        }
+        DiskFile::try_decode_footer(bytes).ok_or_else(|| format!("File {} has a damaged footer, {}", filename, REPAIR_HINT))
+    }
+
+    // None unless the footer is whole and its offsets lie within the blocks
+    pub fn try_decode_footer(bytes: &[u8]) -> Option<(Vec<usize>, usize, usize, u64, u32)> {
+        if bytes.len() < FOOTER_TRAILER_SIZE {
+            return None;
+        }
+        let footer_end = bytes.len() - FOOTER_TRAILER_SIZE;
+        let trailer = &bytes[footer_end..];
+        if u32::from_be_bytes(trailer[24..28].try_into().unwrap()) != TABLE_MAGIC {
+            return None;
+        }
+        let disk_size = u64::from_be_bytes(trailer[0..8].try_into().unwrap()) as usize;
+        let global_seq = u64::from_be_bytes(trailer[8..16].try_into().unwrap());
+        let checksum = u32::from_be_bytes(trailer[16..20].try_into().unwrap());
+        let num_blocks = u32::from_be_bytes(trailer[20..24].try_into().unwrap()) as usize;
+        if disk_size > footer_end || num_blocks > footer_end - disk_size {
+            return None;
+        }
+        // every varint ends before the trailer, so get_varint stays within the footer
+        let footer = &bytes[disk_size..footer_end];
+        if footer.iter().filter(|byte| **byte & 0x80 == 0).count() != num_blocks + 1 || footer.last().is_none_or(|byte| byte & 0x80 != 0) {
+            return None;
+        }
+        let mut offset = 0;
+        let block_offsets: Vec<usize> = (0..num_blocks).map(|_| get_varint(footer, &mut offset) as usize).collect();
+        let range_del_offset = get_varint(footer, &mut offset) as usize;
+        let mut previous = 0;
+        for block_offset in block_offsets.iter().chain(std::iter::once(&range_del_offset)) {
+            if *block_offset < previous || *block_offset > disk_size {
+                return None;
+            }
+            previous = *block_offset;
+        }
+        if block_offsets.first().map_or(range_del_offset, |first| *first) != 0 {
+            return None;
+        }
+        Some((block_offsets, range_del_offset, disk_size, global_seq, checksum))
+    }
+
+    // For repair: the records of each data block, or its size on disk if it
+    // fails its checksum, then the same for the range deletion block if there is
+    // one. None if the footer is damaged, as then the blocks cannot be found.
+    pub fn salvage_blocks(filename: &str) -> Option<(Vec<SalvagedBlock>, Option<SalvagedBlock>)> {
+        let bytes = fs::read(filename).ok()?;
+        let (block_offsets, range_del_offset, disk_size, global_seq, _) = DiskFile::try_decode_footer(&bytes)?;
+        let read = |start: usize, end: usize| -> Result<Vec<Record>, usize> {
+            match verify_block(&bytes[start..end]) {
+                true => match decompress_block(&bytes[start..end]) {
+                    Ok(raw_block) => Ok(apply_global_seq(decode_block(&raw_block), global_seq)),
+                    Err(_) => Err(end - start),
+                },
+                false => Err(end - start),
+            }
+        };
+        let blocks = (0..block_offsets.len())
+            .map(|block_idx| read(block_offsets[block_idx], *block_offsets.get(block_idx + 1).unwrap_or(&range_del_offset)))
+            .collect();
+        let range_tombstones = match range_del_offset < disk_size {
+            true => Some(read(range_del_offset, disk_size)),
+            false => None,
+        };
+        Some((blocks, range_tombstones))
+    }
+
+    // Gives every record of a file written without sequence numbers the one it
+    // is ingested with. Stored in the footer, so the blocks are not rewritten.
+    pub fn set_global_seq(&mut self, seq: u64) {
+        assert!(seq > 0 && self.max_seq == 0, "File {} already has sequence numbers", self.filename);
+        let file = fs::OpenOptions::new().write(true).open(&self.filename).unwrap_or_else(|error| panic!("Failed to open file {}: {}", self.filename, error));
+        let offset = self.file_size() - (FOOTER_TRAILER_SIZE - 8) as u64;
+        file.write_all_at(&seq.to_be_bytes(), offset).unwrap_or_else(|error| panic!("Failed to write file {}: {}", self.filename, error));
+        file.sync_all().unwrap_or_else(|error| panic!("Failed to sync file {}: {}", self.filename, error));
+        self.global_seq = seq;
+        self.min_seq = seq;
+        self.max_seq = seq;
+        self.range_tombstones = apply_global_seq(self.range_tombstones.clone(), seq);
+    }
+
+    fn map_file(filename: &String) -> Option<Mmap> {
+        match CONFIGURATION.READ_MODE {
+            ReadMode::MMAP => {
+                let file = File::open(filename).unwrap_or_else(|error| panic!("Failed to open file {} for mmap: {}", filename, error));
+                Some(unsafe { Mmap::map(&file) }.unwrap_or_else(|error| panic!("Failed to mmap file {}: {}", filename, error)))
             },
+            ReadMode::PREAD => None,
+        }
+    }
+
+    // name of the file within the tree directory
+    pub fn name(&self) -> &str {
+        Path::new(&self.filename).file_name().and_then(|name| name.to_str()).unwrap_or_else(|| panic!("Bad file name {}", self.filename))
+    }
+
+    // bytes of the file on disk, footer included
+    pub fn file_size(&self) -> u64 {
+        fs::metadata(&self.filename).unwrap_or_else(|error| panic!("Failed to stat file {}: {}", self.filename, error)).len()
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, AtomicOrdering::Release);
+    }
+
+    // Newest version of key with a sequence number <= seq. Errors if the block
+    // it would be in is damaged.
+    pub fn get(&self, key: &[u8], seq: u64, record: &mut Record) -> Result<bool, String> {
+        // if bloom filter does not contain key or key bigger than the last key
+        if !self.bloom_filter.contains(&key) || compare_keys(key, &self.last_key) == Ordering::Greater {
+            return Ok(false);
+        }
+        let block_idx = match binary_search_fp(&self.fence_pointers, key) {
+            Some(idx) => idx,
             None => {
-                return false;
+                return Ok(false);
             },
         };
-        
+
+        if self.global_seq > seq {
+            return Ok(false);
+        }
+        // count the logical page access even when it is served from the memory map
         GET_IO_COUNTER.inc();
-        let records = self.read_file(file_offset * CONFIGURATION.BLOCK_SIZE, CONFIGURATION.BLOCK_SIZE);
-        let idx = records.binary_search_by_key(key, |&record| record.key);
-        let idx = match idx {
-            Err(_) => {
-                return false
+        let found = match &self.mmap {
+            Some(mmap) => {
+                let (start, end) = self.block_range(block_idx);
+                search_block(&read_block_bytes_of(&self.filename, Some(block_idx), &mmap[start..end])?, key, seq)
             },
-            Ok(v) => v,
+            None => search_block(&read_block_bytes_of(&self.filename, Some(block_idx), &self.read_block_bytes(block_idx))?, key, seq),
         };
-        *record = records[idx];
-        return true;
+        match found {
+            Some(mut found) => {
+                if self.global_seq > 0 {
+                    found.seq = self.global_seq;
+                }
+                *record = found;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    // start and end offset of a data block
+    pub fn block_range(&self, block_idx: usize) -> (usize, usize) {
+        let start = self.block_offsets[block_idx];
+        let end = match self.block_offsets.get(block_idx + 1) {
+            Some(&next) => next,
+            None => self.range_del_offset,
+        };
+        (start, end)
+    }
+
+    pub fn read_block(&self, block_idx: usize) -> Vec<Record> {
+        let read = |bytes: &[u8]| decode_block(&read_block_bytes_of(&self.filename, Some(block_idx), bytes).unwrap_or_else(|message| panic!("{}", message)));
+        let records = match &self.mmap {
+            Some(mmap) => {
+                let (start, end) = self.block_range(block_idx);
+                read(&mmap[start..end])
+            },
+            None => read(&self.read_block_bytes(block_idx)),
+        };
+        apply_global_seq(records, self.global_seq)
+    }
+
+    pub fn read_block_bytes(&self, block_idx: usize) -> Vec<u8> {
+        let (start, end) = self.block_range(block_idx);
+        let mut buffer = vec![0; end - start];
+        let bytes_read = self.read_file_to_buffer(start, &mut buffer);
+        assert!(bytes_read == end - start);
+        buffer
     }
 
     pub fn read_all_file_bytes(&self) -> Vec<u8> {
-        let mut buffer = vec![0; self.size];
+        let mut buffer = vec![0; self.disk_size];
         let bytes_read = self.read_file_to_buffer(0, &mut buffer);
-        assert!(bytes_read == self.size);
+        assert!(bytes_read == self.disk_size);
         buffer
     }
 
+    // point records and range tombstones, sorted
     pub fn read_all_file_records(&self) -> Vec<Record> {
-        let records = bytes_to_records(&self.read_all_file_bytes());
-        assert!(records[0].key == self.fence_pointers[0]);
-        assert!(records.last().unwrap().key == *self.fence_pointers.last().unwrap());
-        assert!(records.len() * CONFIGURATION.RECORD_SIZE == self.size);
+        let mut records = Vec::new();
+        let bytes = self.read_all_file_bytes();
+        for block_idx in 0..self.block_offsets.len() {
+            let (start, end) = self.block_range(block_idx);
+            records.extend(decode_block(&read_block_bytes_of(&self.filename, Some(block_idx), &bytes[start..end]).unwrap_or_else(|message| panic!("{}", message))));
+        }
+        if self.range_del_offset < self.disk_size {
+            let range_tombstones = decode_block(&read_block_bytes_of(&self.filename, None, &bytes[self.range_del_offset..self.disk_size]).unwrap_or_else(|message| panic!("{}", message)));
+            assert!(range_tombstones.len() == self.range_tombstones.len());
+            records.extend(range_tombstones);
+            records.sort();
+        }
+        assert!(records.iter().map(|record| record.size()).sum::<usize>() == self.size);
+        let records = apply_global_seq(records, self.global_seq);
+        assert!(records[0].key == self.first_key);
+        assert!(last_key_of(&records) == self.last_key);
         records
     }
 
-    pub fn read_file(&self, start_offset: usize, num_bytes: usize) -> Vec<Record> {
-        let mut buffer = vec![0; num_bytes];
-        let bytes_read = self.read_file_to_buffer(start_offset, &mut buffer);
-        let records = bytes_to_records(&buffer[..bytes_read]);
-        let num_records = bytes_read / CONFIGURATION.RECORD_SIZE;
-        assert!(num_records == records.len());
-        records
+    pub fn read_file_to_buffer(&self, start_offset: usize, buffer: &mut[u8]) -> usize {
+        if let Some(mmap) = &self.mmap {
+            let end = std::cmp::min(start_offset + buffer.len(), self.disk_size);
+            buffer[..end - start_offset].copy_from_slice(&mmap[start_offset..end]);
+            return end - start_offset;
+        }
+        // positional reads, so concurrent readers can share the cached handle
+        let f = FILE_HANDLE_CACHE.get(&self.filename);
+        if CONFIGURATION.DIRECT_IO {
+            return read_direct(&f, start_offset, buffer);
+        }
+        let mut bytes_read = 0;
+        while bytes_read < buffer.len() {
+            let n = f.read_at(&mut buffer[bytes_read..], (start_offset + bytes_read) as u64).expect("Failed to read file");
+            if n == 0 {
+                break;
+            }
+            bytes_read += n;
+        }
+        bytes_read
     }
 
-    pub fn read_file_to_buffer(&self, start_offset: usize, buffer: &mut[u8]) -> usize {
-        let mut f = File::open(&self.filename).expect(&format!("Failed to open file {} for disk read", &self.filename));
-        f.seek(SeekFrom::Start(start_offset as u64)).expect("Failed to seek file");
-        f.read(&mut buffer[..]).expect("Failed to read file")
+    // bits and hash functions of the bloom filter over bf_records keys
+    pub fn bloom_filter_size(bf_records: usize) -> (usize, u32) {
+        let bf_bits = CONFIGURATION.BF_BITS_PER_ENTRY * bf_records;
+        (bf_bits, bloom::optimal_num_hashes(bf_bits, bf_records as u32))
     }
 
     // private helper function
-    fn init_bloom_filter(size: usize) -> BloomFilter {
-        let bf_records = size / CONFIGURATION.RECORD_SIZE;
-        let bf_bits = CONFIGURATION.BF_BITS_PER_ENTRY * bf_records;
-        let bf_hashes = bloom::optimal_num_hashes(bf_bits, bf_records as u32);
+    fn init_bloom_filter(bf_records: usize) -> BloomFilter {
+        let (bf_bits, bf_hashes) = DiskFile::bloom_filter_size(bf_records);
         BloomFilter::with_size(bf_bits, bf_hashes)
     }
-}
\ No newline at end of file
+}
+
+// Decompressed bytes of a block read from filename, the range deletion block if
+// block_idx is None, or an error naming the file and the block if it is damaged.
+fn read_block_bytes_of<'a>(filename: &str, block_idx: Option<usize>, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, String> {
+    decompress_block(bytes).map_err(|error| match block_idx {
+        Some(block_idx) => format!("Block {} of file {} is damaged, {}: {}", block_idx, filename, REPAIR_HINT, error),
+        None => format!("The range deletion block of file {} is damaged, {}: {}", filename, REPAIR_HINT, error),
+    })
+}
+
+// Largest key the sorted records hold or a range tombstone among them reaches,
+// so a file's key range takes in every key its tombstones delete.
+pub fn last_key_of(records: &[Record]) -> Vec<u8> {
+    records.iter().filter(|record| record.is_range_deletion()).map(|record| &record.value)
+        .fold(&records.last().unwrap().key, |last_key, end| if compare_keys(end, last_key) == Ordering::Greater { end } else { last_key })
+        .clone()
+}
+
+fn apply_global_seq(mut records: Vec<Record>, global_seq: u64) -> Vec<Record> {
+    if global_seq > 0 {
+        for record in records.iter_mut() {
+            record.seq = global_seq;
+        }
+    }
+    records
+}
+
+impl Drop for DiskFile {
+    // checkpoints and merges in progress hold on to files their runs have dropped
+    fn drop(&mut self) {
+        if self.obsolete.load(AtomicOrdering::Acquire) {
+            FILE_HANDLE_CACHE.evict(&self.filename);
+            // a panic here would abort a drop during unwinding, and repair or orphan
+            // removal may have taken the file already
+            match fs::remove_file(&self.filename) {
+                Ok(()) => (),
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                Err(error) => error!("Failed to remove obsolete file {}: {}", self.filename, error),
+            }
+        }
+    }
+}
@@ -3,13 +3,13 @@ use std::collections::HashSet;
 
 use crate::configuration::CONFIGURATION;
 
-use crate::lib_helper::{records_to_bytes};
 use crate::lib_template::{Record};
-use crate::lib_merge::{merge_from_files};
+use crate::lib_merge::{merge_from_files, CompactionContext};
 use super::lib_disk_run::{Run};
 use super::lib_disk_file::{DiskFile};
+use super::lib_manifest::{LevelEntry};
 use std::sync::{Arc};
-use parking_lot::{RwLock};
+use parking_lot::{RwLock, Mutex};
 use atomic_counter::{RelaxedCounter, AtomicCounter};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::metrics::{PUT_IO_COUNTER};
@@ -21,31 +21,72 @@ pub struct DiskLevel {
     pub capacity: AtomicUsize,
     pub runs: RwLock<Vec<Run>>,
     pub run_counter: RelaxedCounter,
+    pub dir: String, // directory of the tree the files are in
+    obsolete_runs: Mutex<Vec<Run>>, // dropped from the level, their files stay on disk until the manifest stops listing them
 }
 
 impl DiskLevel {
-    pub fn empty_level(capacity_of_run: usize, level: usize) -> DiskLevel {
+    pub fn empty_level(capacity_of_run: usize, level: usize, dir: &str) -> DiskLevel {
         DiskLevel {
             level: level,
             size: AtomicUsize::new(0),
             capacity: AtomicUsize::new(capacity_of_run * CONFIGURATION.RUNS_PER_LEVEL),
             runs: RwLock::new(Vec::new()),
             run_counter: RelaxedCounter::new(0),
+            dir: dir.to_string(),
+            obsolete_runs: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn create_level(files: Vec<Vec<Arc<DiskFile>>>, size_of_run: usize, capacity_of_run: usize, level: usize) -> DiskLevel {
-        let new_level = DiskLevel::empty_level(capacity_of_run, level);
-        new_level.flush(files, size_of_run, size_of_run, capacity_of_run);
+    pub fn create_level(files: Vec<Vec<Arc<DiskFile>>>, size_of_run: usize, capacity_of_run: usize, level: usize, dir: &str, context: &CompactionContext) -> DiskLevel {
+        let new_level = DiskLevel::empty_level(capacity_of_run, level, dir);
+        new_level.flush(files, size_of_run, size_of_run, capacity_of_run, context);
         new_level
     }
 
-    pub fn create_level_from_buffer(data: Vec<Record>, size: usize, capacity_of_run: usize, level: usize) -> DiskLevel {
-        let new_level = DiskLevel::empty_level(capacity_of_run, level);
+    pub fn create_level_from_buffer(data: Vec<Record>, size: usize, capacity_of_run: usize, level: usize, dir: &str) -> DiskLevel {
+        let new_level = DiskLevel::empty_level(capacity_of_run, level, dir);
         new_level.flush_from_buffer(data, size, capacity_of_run);
         new_level
     }
 
+    // reads back a level listed in the manifest
+    pub fn open_level(entry: &LevelEntry, dir: &str) -> Result<DiskLevel, String> {
+        let runs: Vec<Run> = entry.runs.iter().map(|run| Run::open_run(run, entry.level, dir)).collect::<Result<_, _>>()?;
+        Ok(DiskLevel {
+            level: entry.level,
+            size: AtomicUsize::new(runs.iter().map(|run| run.size).sum()),
+            capacity: AtomicUsize::new(entry.capacity),
+            runs: RwLock::new(runs),
+            run_counter: RelaxedCounter::new(entry.run_counter),
+            dir: dir.to_string(),
+            obsolete_runs: Mutex::new(Vec::new()),
+        })
+    }
+
+    // the level's entry in the manifest and the files it lists
+    pub fn manifest_entry(&self) -> (LevelEntry, Vec<Arc<DiskFile>>) {
+        let runs = self.runs.read();
+        let entry = LevelEntry {
+            level: self.level,
+            capacity: self.capacity(),
+            run_counter: self.run_counter.get(),
+            runs: runs.iter().map(|run| run.manifest_entry()).collect(),
+        };
+        (entry, runs.iter().flat_map(|run| run.files.iter().cloned()).collect())
+    }
+
+    fn retire_run(&self, run: Run) {
+        run.delete_files();
+        self.obsolete_runs.lock().push(run);
+    }
+
+    // Runs retired so far. Their files are removed once the caller drops them,
+    // which it does after writing a manifest without them.
+    pub fn take_obsolete_runs(&self) -> Vec<Run> {
+        std::mem::take(&mut *self.obsolete_runs.lock())
+    }
+
     pub fn is_full(&self) -> bool {
         let runs = self.runs.read();
         let num_runs = runs.len();
@@ -81,15 +122,48 @@ impl DiskLevel {
         self.level
     }
 
+    // bytes of the data blocks before compression over bytes on disk, for the files currently in the level
+    pub fn compression_ratio(&self) -> f64 {
+        let mut uncompressed_size = 0;
+        let mut disk_size = 0;
+        for run in self.runs.read().iter() {
+            for file in run.files.iter() {
+                uncompressed_size += file.uncompressed_size;
+                disk_size += file.disk_size;
+            }
+        }
+        if disk_size == 0 {
+            return 1.0;
+        }
+        uncompressed_size as f64 / disk_size as f64
+    }
+
     pub fn clear(&self) {
         let mut runs = self.runs.write();
         for run in runs.iter() {
             run.delete_files();
         }
         runs.clear();
+        self.take_obsolete_runs();
         self.size.store(0, Ordering::Relaxed);
     }
 
+    // runs are appended, so the oldest runs come first
+    pub fn clear_oldest_runs(&self, num_runs: usize) {
+        let mut runs = self.runs.write();
+        for run in runs.drain(..num_runs) {
+            self.dec_size(run.size);
+            self.retire_run(run);
+        }
+    }
+
+    // appends a run built outside the level, the newest one
+    pub fn add_run(&self, run: Run) {
+        let mut runs = self.runs.write();
+        self.add_size(run.size);
+        runs.push(run);
+    }
+
     pub fn get_all_files(&self) -> Vec<Vec<Arc<DiskFile>>> {
         let mut level_files = Vec::new();
         let runs = self.runs.read();
@@ -101,24 +175,33 @@ impl DiskLevel {
     }
 
     pub fn flush_from_buffer(&self, data_records: Vec<Record>, size: usize, capacity_of_run: usize) {
-        let data = records_to_bytes(&data_records);
+        let max_run_size = std::cmp::max(capacity_of_run, CONFIGURATION.FILE_SIZE);
         let mut num_flushed = 0;
+        let mut first = 0;
         let mut runs = Vec::new();
         
-        while num_flushed < size {
-            let to_flush = std::cmp::min(std::cmp::max(capacity_of_run, CONFIGURATION.FILE_SIZE), size - num_flushed);
-            let new_run = Run::create_run(to_flush, capacity_of_run, &data[num_flushed..num_flushed + to_flush], self.level, self.run_counter.get());
+        while first < data_records.len() {
+            // take records up to max_run_size bytes, but at least one, and all versions of the last key
+            let mut last = first;
+            let mut to_flush = 0;
+            while last < data_records.len() && (last == first || to_flush + data_records[last].size() <= max_run_size || data_records[last].key == data_records[last - 1].key) {
+                to_flush += data_records[last].size();
+                last += 1;
+            }
+            let new_run = Run::create_run(&data_records[first..last], capacity_of_run, self.level, self.run_counter.get(), &self.dir);
             PUT_IO_COUNTER.inc_by((to_flush as f64 / CONFIGURATION.BLOCK_SIZE as f64).ceil() as i64);
             num_flushed += to_flush;
+            first = last;
             runs.push(new_run);
             self.run_counter.inc();
         }
+        assert!(num_flushed == size);
         let mut original_runs = self.runs.write();
         self.add_size(size);
         original_runs.extend(runs);
     }
 
-    pub fn flush(&self, files: Vec<Vec<Arc<DiskFile>>>, data_size: usize, size_per_run: usize, capacity_of_run: usize) {
+    pub fn flush(&self, files: Vec<Vec<Arc<DiskFile>>>, _data_size: usize, size_per_run: usize, capacity_of_run: usize, context: &CompactionContext) {
         let runs = self.runs.read();
         let num_runs = runs.len();
         if num_runs > 0 {
@@ -130,7 +213,20 @@ impl DiskLevel {
             if size_per_run > last_run_size && !last_run.is_full() {
                 let mut all_files_merge = vec![last_run.files.clone()];
                 all_files_merge.extend(files);
-                let merged_files = merge_from_files(all_files_merge, last_run);
+                let merged_files = match num_runs {
+                    1 => merge_from_files(all_files_merge, last_run, context),
+                    _ => merge_from_files(all_files_merge, last_run, &context.above_older_runs()),
+                };
+
+                // every record was a dropped tombstone or a version it hid
+                if merged_files.is_empty() {
+                    drop(runs);
+                    let mut runs = self.runs.write();
+                    self.dec_size(last_run_size);
+                    let old_run = runs.pop().unwrap();
+                    self.retire_run(old_run);
+                    return;
+                }
 
                 let mut files_size = 0;
                 let mut merged_files_size = 0;
@@ -146,19 +242,24 @@ impl DiskLevel {
                 if merged_files_size == 0 {
                     merged_files_size = files_size;
                 }
-                let new_last_run = Run::create_run_from_files(merged_files_size, capacity_of_run, merged_files[..file_idx + 1].to_vec(), self.level, self.run_counter.get());
+                let new_last_run = Run::create_run_from_files(merged_files_size, capacity_of_run, merged_files[..file_idx + 1].to_vec(), self.level, self.run_counter.get(), &self.dir);
                 self.run_counter.inc();
                 drop(runs);
 
                 // replace last run
                 let mut runs = self.runs.write();
-                self.add_size(files_size - last_run_size);
-                runs[num_runs - 1].delete_files();
-                runs[num_runs - 1] = new_last_run;
+                // dropped versions can leave the merged run smaller than the old one
+                if files_size >= last_run_size {
+                    self.add_size(files_size - last_run_size);
+                } else {
+                    self.dec_size(last_run_size - files_size);
+                }
+                let old_run = std::mem::replace(&mut runs[num_runs - 1], new_last_run);
+                self.retire_run(old_run);
 
                 // if still have data left over, add left over data as new run to level
                 if merged_files.len() > file_idx + 1 {
-                    let new_run = Run::create_run_from_files(files_size - merged_files_size, capacity_of_run, merged_files[..file_idx + 1].to_vec(), self.level, self.run_counter.get());
+                    let new_run = Run::create_run_from_files(files_size - merged_files_size, capacity_of_run, merged_files[file_idx + 1..].to_vec(), self.level, self.run_counter.get(), &self.dir);
                     self.run_counter.inc();
                     runs.push(new_run);
                 }
@@ -174,14 +275,17 @@ impl DiskLevel {
         let mut runs_to_add = Vec::new();
         let mut size_to_add = 0;
 
-        let mut empty_run = Run::create_empty_run(capacity_of_run, self.level, self.run_counter.get());
+        let empty_run = Run::create_empty_run(capacity_of_run, self.level, self.run_counter.get(), &self.dir);
         self.run_counter.inc();
 
-        let merged_files = merge_from_files(files, &mut empty_run);
+        let merged_files = match num_runs {
+            0 => merge_from_files(files, &empty_run, context),
+            _ => merge_from_files(files, &empty_run, &context.above_older_runs()),
+        };
         while counter < merged_files.len() + 1 {
             files_size += merged_files[counter - 1].size;
             if files_size >= size_per_run || counter == merged_files.len() {
-                let new_run = Run::create_run_from_files(files_size, capacity_of_run, merged_files[last_counter..counter].to_vec(), self.level, self.run_counter.get());
+                let new_run = Run::create_run_from_files(files_size, capacity_of_run, merged_files[last_counter..counter].to_vec(), self.level, self.run_counter.get(), &self.dir);
                 last_counter = counter;
                 self.run_counter.inc();
                 runs_to_add.push(new_run);
@@ -195,17 +299,41 @@ impl DiskLevel {
         self.add_size(size_to_add);
     }
 
-    pub fn get(&self, key: &i32, record: &mut Record) -> bool {
+    pub fn get(&self, key: &[u8], seq: u64, record: &mut Record) -> Result<bool, String> {
         let runs = self.runs.read();
         for run in runs.iter().rev() {
-            if run.get(key, record) {
-                return true;
+            if run.get(key, seq, record)? {
+                return Ok(true);
             }
         }
-        return false;
+        Ok(false)
+    }
+
+    pub fn range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+        let mut records = Vec::new();
+        for run in self.runs.read().iter() {
+            records.extend(run.range(lower, upper));
+        }
+        records
+    }
+
+    pub fn overlaps(&self, lower: &[u8], upper: &[u8]) -> bool {
+        self.runs.read().iter().any(|run| run.overlaps(lower, upper))
+    }
+
+    pub fn range_tombstone_seq(&self, key: &[u8], seq: u64) -> u64 {
+        self.runs.read().iter().map(|run| run.range_tombstone_seq(key, seq)).max().unwrap_or(0)
+    }
+
+    pub fn range_tombstones(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+        let mut tombstones = Vec::new();
+        for run in self.runs.read().iter() {
+            tombstones.extend(run.range_tombstones(lower, upper));
+        }
+        tombstones
     }
 
-    pub fn print_stats(&self, distinct_keys: &mut HashSet<i32>) {
+    pub fn print_stats(&self, distinct_keys: &mut HashSet<Vec<u8>>) {
         let runs = self.runs.read();
         for run in runs.iter() {
             run.print_stats(distinct_keys);
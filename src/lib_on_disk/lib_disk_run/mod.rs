@@ -1,15 +1,13 @@
-use std::cmp;
 use std::collections::{HashSet};
 use std::sync::{Arc};
-use std::convert::TryInto;
-use std::fs;
-use std::fs::{File};
-use std::io::prelude::*;
+use std::path::{Path};
+use std::cmp::Ordering;
 use atomic_counter::{RelaxedCounter, AtomicCounter};
 use crate::configuration::CONFIGURATION;
-use crate::lib_helper::{generate_filename, bytes_to_records, binary_search_fp};
-use crate::lib_template::{Record};
+use crate::lib_helper::{generate_filename, bytes_to_string, binary_search_fp};
+use crate::lib_template::{Record, compare_keys, covering_tombstone_seq};
 use super::lib_disk_file::{DiskFile};
+use super::lib_manifest::{RunEntry};
 
 pub struct Run {
     pub level: usize, // level run is on
@@ -18,29 +16,37 @@ pub struct Run {
     pub capacity: usize, // bytes of data run can hold
     pub file_counter: RelaxedCounter, 
     pub files: Vec<Arc<DiskFile>>,
-    pub fence_pointers: Vec<i32>,
+    pub fence_pointers: Vec<Vec<u8>>,
+    pub dir: String, // directory of the tree the files are in
 }
 
 impl Run {
-	pub fn create_run(size: usize, capacity: usize, data: &[u8], level: usize, run: usize) -> Run {
-        assert!(size > 0);
-        let number_files = (size - 1) / CONFIGURATION.FILE_SIZE + 1;
-        let mut fence_pointers: Vec<i32> = Vec::new();
+	pub fn create_run(records: &[Record], capacity: usize, level: usize, run: usize, dir: &str) -> Run {
+        assert!(!records.is_empty());
+        let mut fence_pointers: Vec<Vec<u8>> = Vec::new();
         let mut files = Vec::new();
-        let mut offset: usize = 0;
-
-        for i in 0..number_files {
-            assert!(offset < size);
-            let bytes_to_write = cmp::min(CONFIGURATION.FILE_SIZE, size - offset);
-            let filename = generate_filename(level, run, i);
-            files.push(Arc::new(DiskFile::create_disk_file(filename, &data[offset..offset + bytes_to_write], bytes_to_write)));
-            
-            let key = i32::from_be_bytes(data[offset..offset + CONFIGURATION.KEY_SIZE].try_into().unwrap());
-            fence_pointers.push(key);
-
-            offset += bytes_to_write;
+        let mut size: usize = 0;
+        let mut first: usize = 0;
+        let mut file_size: usize = 0;
+
+        // a file ends before the record that would take it over FILE_SIZE bytes,
+        // unless that record is an older version of the previous key
+        for i in 0..records.len() {
+            let record_size = records[i].size();
+            if file_size > 0 && file_size + record_size > CONFIGURATION.FILE_SIZE && records[i].key != records[i - 1].key {
+                let filename = generate_filename(dir, level, run, files.len());
+                files.push(Arc::new(DiskFile::create_disk_file(filename, level, &records[first..i])));
+                fence_pointers.push(records[first].key.clone());
+                first = i;
+                file_size = 0;
+            }
+            file_size += record_size;
+            size += record_size;
         }
-        assert!(offset == size);
+        let filename = generate_filename(dir, level, run, files.len());
+        files.push(Arc::new(DiskFile::create_disk_file(filename, level, &records[first..])));
+        fence_pointers.push(records[first].key.clone());
+        let number_files = files.len();
 
         Run {
             level: level,
@@ -50,17 +56,16 @@ impl Run {
             file_counter: RelaxedCounter::new(number_files),
             fence_pointers: fence_pointers,
             files: files,
+            dir: dir.to_string(),
 		}
     }
 
-    pub fn create_run_from_files(size: usize, capacity: usize, files: Vec<Arc<DiskFile>>, level: usize, run: usize) -> Run {
+    pub fn create_run_from_files(size: usize, capacity: usize, files: Vec<Arc<DiskFile>>, level: usize, run: usize, dir: &str) -> Run {
         assert!(size > 0);
-        let mut fence_pointers: Vec<i32> = Vec::new();
-        let mut offset: usize = 0;
+        let mut fence_pointers: Vec<Vec<u8>> = Vec::new();
 
         for i in 0..files.len() {
-            assert!(offset < size);
-            fence_pointers.push(files[i].fence_pointers[0]);
+            fence_pointers.push(files[i].first_key.clone());
         }
 
         Run {
@@ -71,11 +76,34 @@ impl Run {
             file_counter: RelaxedCounter::new(files.len()),
             fence_pointers: fence_pointers,
             files: files,
+            dir: dir.to_string(),
 		}
     }
 
+    // reads back the files of a run listed in the manifest, errors if one is damaged
+    pub fn open_run(entry: &RunEntry, level: usize, dir: &str) -> Result<Run, String> {
+        let files: Vec<Arc<DiskFile>> = entry.files.iter()
+            .map(|name| DiskFile::try_open_disk_file(Path::new(dir).join(name).to_string_lossy().to_string()).map(Arc::new))
+            .collect::<Result<_, _>>()?;
+        let size = files.iter().map(|file| file.size).sum();
+        let run = Run::create_run_from_files(size, entry.capacity, files, level, entry.run, dir);
+        // names of files merged into the run carry on from its counter
+        Ok(Run {
+            file_counter: RelaxedCounter::new(std::cmp::max(entry.file_counter, run.files.len())),
+            ..run
+        })
+    }
+
+    pub fn manifest_entry(&self) -> RunEntry {
+        RunEntry {
+            run: self.run,
+            capacity: self.capacity,
+            file_counter: self.file_counter.get(),
+            files: self.files.iter().map(|file| file.name().to_string()).collect(),
+        }
+    }
 
-    pub fn create_empty_run(capacity: usize, level: usize, run: usize) -> Run {
+    pub fn create_empty_run(capacity: usize, level: usize, run: usize, dir: &str) -> Run {
         Run {
             level: level,
             run: run,
@@ -84,6 +112,7 @@ impl Run {
             file_counter: RelaxedCounter::new(0), 
             fence_pointers: Vec::new(),
             files: Vec::new(),
+            dir: dir.to_string(),
         }
     }
 
@@ -91,46 +120,69 @@ impl Run {
         return self.size as f64 >= self.capacity as f64 * CONFIGURATION.FULL_THRESHOLD;
     }
 
+    // the files are removed from disk once nothing holds them any more
     pub fn delete_files(&self) {
         for file in self.files.iter() {
-            fs::remove_file(&file.filename).expect(&format!("Failed to remove file {} in disk run, level is {}, run is {}, file counter is {}, size is {}", &file.filename, self.level, self.run, self.file_counter.get(), self.size));
+            file.mark_obsolete();
         }
     }
 
-    pub fn get(&self, key: &i32, record: &mut Record) -> bool {
-        let file_idx = match binary_search_fp(&self.fence_pointers, &key) {
+    pub fn get(&self, key: &[u8], seq: u64, record: &mut Record) -> Result<bool, String> {
+        let file_idx = match binary_search_fp(&self.fence_pointers, key) {
             Some(idx) => idx,
             None => {
-                return false;
+                return Ok(false);
             },
         };
-        return self.files[file_idx].get(key, record);
+        self.files[file_idx].get(key, seq, record)
+    }
+
+    // whether a file of the run holds keys in [lower, upper]
+    pub fn overlaps(&self, lower: &[u8], upper: &[u8]) -> bool {
+        self.files.iter().any(|file| compare_keys(&file.first_key, upper) != Ordering::Greater && compare_keys(&file.last_key, lower) != Ordering::Less)
     }
 
-    pub fn get_all_bytes(&self) -> Vec<u8> {
-        let mut offset = 0;
-        let mut buffer = vec![0; self.size];
+    // all versions of the keys in [lower, upper], without range tombstones
+    pub fn range(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+        let mut records = Vec::new();
         for file in self.files.iter() {
-            offset += file.read_file_to_buffer(0, &mut buffer[offset..offset + file.size]);
+            if compare_keys(&file.first_key, upper) == Ordering::Greater || compare_keys(&file.last_key, lower) == Ordering::Less {
+                continue;
+            }
+            records.extend(file.read_all_file_records().into_iter()
+                .filter(|record| !record.is_range_deletion())
+                .filter(|record| compare_keys(&record.key, lower) != Ordering::Less && compare_keys(&record.key, upper) != Ordering::Greater));
         }
-        assert!(offset == self.size);
-        buffer
+        records
     }
 
-    pub fn get_all_records(&self) -> Vec<Record> {
-        bytes_to_records(&self.get_all_bytes())
+    // a tombstone can cover keys of files after the one holding it, but never
+    // keys outside the key range of its own file
+    pub fn range_tombstone_seq(&self, key: &[u8], seq: u64) -> u64 {
+        let files = self.files.iter().filter(|file| !file.range_tombstones.is_empty()
+            && compare_keys(&file.first_key, key) != Ordering::Greater && compare_keys(key, &file.last_key) != Ordering::Greater);
+        covering_tombstone_seq(files.flat_map(|file| file.range_tombstones.iter()), key, seq)
+    }
+
+    pub fn range_tombstones(&self, lower: &[u8], upper: &[u8]) -> Vec<Record> {
+        self.files.iter().flat_map(|file| file.range_tombstones.iter())
+            .filter(|tombstone| tombstone.overlaps(lower, upper)).cloned().collect()
     }
 
-    pub fn print_stats(&self, distinct_keys: &mut HashSet<i32>) {    
+    pub fn get_all_records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
         for file in self.files.iter() {
-            let mut buffer = vec![0; file.size];
+            records.extend(file.read_all_file_records());
+        }
+        records
+    }
 
-            let mut f = File::open(&file.filename).expect("Failed to open file in disk run get");
-            f.read(&mut buffer[..]).expect("Failed to read file in print stats");
-            let records = bytes_to_records(&buffer);
+    pub fn print_stats(&self, distinct_keys: &mut HashSet<Vec<u8>>) {    
+        for file in self.files.iter() {
+            let records = file.read_all_file_records();
             for j in 0..records.len() {
-                print!("{}:{}:L{} ", records[j].key, records[j].value, self.level);
-                distinct_keys.insert(records[j].key);
+                print!("{}:{}:L{} ", bytes_to_string(&records[j].key), bytes_to_string(&records[j].value), self.level);
+                distinct_keys.insert(records[j].key.clone());
             }
         }
     }
@@ -0,0 +1,85 @@
+use lazy_static::lazy_static;
+use std::collections::{HashMap, BTreeMap};
+use std::fs::{File};
+use std::sync::{Arc};
+use parking_lot::{Mutex};
+
+use crate::configuration::CONFIGURATION;
+use super::lib_direct_io::{open_direct};
+
+lazy_static! {
+    // Global table of open file descriptors, shared by every DiskFile so that
+    // reads do not pay for an open/close per block.
+    pub static ref FILE_HANDLE_CACHE: FileHandleCache = FileHandleCache::new(CONFIGURATION.MAX_OPEN_FILES);
+}
+
+struct CacheState {
+    handles: HashMap<String, (Arc<File>, u64)>, // filename -> (handle, last access tick)
+    lru: BTreeMap<u64, String>, // last access tick -> filename, least recently used first
+    tick: u64,
+}
+
+/// Bounded table of read-only file handles keyed by filename.
+/// Handles are shared through `Arc`, so callers should use positional reads
+/// (`FileExt::read_at`) rather than seeking.
+pub struct FileHandleCache {
+    capacity: usize, // max number of handles kept open
+    state: Mutex<CacheState>,
+}
+
+impl FileHandleCache {
+    pub fn new(capacity: usize) -> FileHandleCache {
+        assert!(capacity > 0);
+        FileHandleCache {
+            capacity,
+            state: Mutex::new(CacheState {
+                handles: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, filename: &String) -> Arc<File> {
+        let mut state = self.state.lock();
+        let tick = state.tick;
+        state.tick += 1;
+        if let Some((handle, old_tick)) = state.handles.get_mut(filename) {
+            let handle = handle.clone();
+            let old_tick = std::mem::replace(old_tick, tick);
+            // move filename to the most recently used end
+            state.lru.remove(&old_tick);
+            state.lru.insert(tick, filename.clone());
+            return handle;
+        }
+
+        let handle = match CONFIGURATION.DIRECT_IO {
+            true => Arc::new(open_direct(filename)),
+            false => Arc::new(File::open(filename).unwrap_or_else(|error| panic!("Failed to open file {} for disk read: {}", filename, error))),
+        };
+        // readers still holding an evicted handle keep it open until they drop it
+        while state.handles.len() >= self.capacity {
+            let oldest = *state.lru.keys().next().unwrap();
+            let victim = state.lru.remove(&oldest).unwrap();
+            state.handles.remove(&victim);
+        }
+        state.handles.insert(filename.clone(), (handle.clone(), tick));
+        state.lru.insert(tick, filename.clone());
+        handle
+    }
+
+    pub fn evict(&self, filename: &String) {
+        let mut state = self.state.lock();
+        if let Some((_, tick)) = state.handles.remove(filename) {
+            state.lru.remove(&tick);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,167 @@
+use std::convert::TryInto;
+
+use crate::lib_template::{Record};
+
+// Block layout for blocks whose keys and values are all 4 bytes:
+//     base key (u32)  count (u32)  key bits (u8)  min value (u32)  value bits (u8)  min seq (u64)  seq bits (u8)
+//     packed keys  packed values  packed sequence numbers
+// Keys are stored as key - base key, where the base key is the block's fence
+// pointer, and values and sequence numbers as their difference to the block's
+// minimum (frame of reference). Each is packed into the fewest bits that hold
+// the largest difference in the block. 4-byte keys are compared as big-endian
+// u32s, which matches bytewise order.
+
+const HEADER_SIZE: usize = 23;
+
+pub fn bits_needed(max_delta: u64) -> u8 {
+    (64 - max_delta.leading_zeros()) as u8
+}
+
+fn packed_bytes(count: usize, range: u64) -> usize {
+    (count * bits_needed(range) as usize).div_ceil(8)
+}
+
+pub fn packed_size(count: usize, key_range: u32, value_range: u32, seq_range: u64) -> usize {
+    HEADER_SIZE + packed_bytes(count, key_range as u64) + packed_bytes(count, value_range as u64) + packed_bytes(count, seq_range)
+}
+
+pub fn to_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+// appends values of width bits, least significant bit first
+fn pack(bytes: &mut Vec<u8>, values: impl Iterator<Item = u64>, width: u8) {
+    let mut acc: u128 = 0;
+    let mut acc_bits = 0;
+    for value in values {
+        acc |= (value as u128) << acc_bits;
+        acc_bits += width as usize;
+        while acc_bits >= 8 {
+            bytes.push(acc as u8);
+            acc >>= 8;
+            acc_bits -= 8;
+        }
+    }
+    if acc_bits > 0 {
+        bytes.push(acc as u8);
+    }
+}
+
+// idx-th value of width bits in a packed section
+fn unpack(bytes: &[u8], idx: usize, width: u8) -> u64 {
+    if width == 0 {
+        return 0;
+    }
+    let bit = idx * width as usize;
+    let mut acc: u128 = 0;
+    for (i, byte) in bytes[bit / 8..].iter().take(9).enumerate() {
+        acc |= (*byte as u128) << (8 * i);
+    }
+    ((acc >> (bit % 8)) & ((1u128 << width) - 1)) as u64
+}
+
+pub fn encode_int_block(keys: &[u32], values: &[u32], seqs: &[u64]) -> Vec<u8> {
+    let base_key = keys[0];
+    let min_value = *values.iter().min().unwrap();
+    let min_seq = *seqs.iter().min().unwrap();
+    let key_bits = bits_needed((keys.last().unwrap() - base_key) as u64);
+    let value_bits = bits_needed((values.iter().max().unwrap() - min_value) as u64);
+    let seq_bits = bits_needed(seqs.iter().max().unwrap() - min_seq);
+    let mut block = Vec::new();
+    block.extend(&base_key.to_be_bytes());
+    block.extend(&(keys.len() as u32).to_be_bytes());
+    block.push(key_bits);
+    block.extend(&min_value.to_be_bytes());
+    block.push(value_bits);
+    block.extend(&min_seq.to_be_bytes());
+    block.push(seq_bits);
+    pack(&mut block, keys.iter().map(|key| (key - base_key) as u64), key_bits);
+    pack(&mut block, values.iter().map(|value| (value - min_value) as u64), value_bits);
+    pack(&mut block, seqs.iter().map(|seq| seq - min_seq), seq_bits);
+    block
+}
+
+struct IntBlock<'a> {
+    base_key: u32,
+    count: usize,
+    key_bits: u8,
+    min_value: u32,
+    value_bits: u8,
+    min_seq: u64,
+    seq_bits: u8,
+    keys: &'a [u8],
+    values: &'a [u8],
+    seqs: &'a [u8],
+}
+
+impl<'a> IntBlock<'a> {
+    fn parse(block: &'a [u8]) -> IntBlock<'a> {
+        let count = to_u32(&block[4..8]) as usize;
+        let key_bits = block[8];
+        let value_bits = block[13];
+        let values_start = HEADER_SIZE + (count * key_bits as usize).div_ceil(8);
+        let seqs_start = values_start + (count * value_bits as usize).div_ceil(8);
+        IntBlock {
+            base_key: to_u32(&block[0..4]),
+            count,
+            key_bits,
+            min_value: to_u32(&block[9..13]),
+            value_bits,
+            min_seq: u64::from_be_bytes(block[14..22].try_into().unwrap()),
+            seq_bits: block[22],
+            keys: &block[HEADER_SIZE..values_start],
+            values: &block[values_start..seqs_start],
+            seqs: &block[seqs_start..],
+        }
+    }
+
+    fn key_delta(&self, idx: usize) -> u32 {
+        unpack(self.keys, idx, self.key_bits) as u32
+    }
+
+    fn seq(&self, idx: usize) -> u64 {
+        self.min_seq + unpack(self.seqs, idx, self.seq_bits)
+    }
+
+    fn record(&self, idx: usize) -> Record {
+        let key = self.base_key + self.key_delta(idx);
+        let value = self.min_value + unpack(self.values, idx, self.value_bits) as u32;
+        Record::create_record(key.to_be_bytes().to_vec(), value.to_be_bytes().to_vec(), self.seq(idx))
+    }
+}
+
+pub fn decode_int_block(block: &[u8]) -> Vec<Record> {
+    let block = IntBlock::parse(block);
+    (0..block.count).map(|idx| block.record(idx)).collect()
+}
+
+// Binary search over the packed key deltas for the first version of key, then a
+// scan over its versions, unpacking only the probed entries
+pub fn search_int_block(block: &[u8], key: &[u8], seq: u64) -> Option<Record> {
+    if key.len() != 4 {
+        return None;
+    }
+    let block = IntBlock::parse(block);
+    let key = to_u32(key);
+    if key < block.base_key {
+        return None;
+    }
+    let target = key - block.base_key;
+    let mut low = 0;
+    let mut high = block.count;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if block.key_delta(mid) < target {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    while low < block.count && block.key_delta(low) == target {
+        if block.seq(low) <= seq {
+            return Some(block.record(low));
+        }
+        low += 1;
+    }
+    None
+}
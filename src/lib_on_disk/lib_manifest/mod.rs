@@ -0,0 +1,173 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind};
+use std::io::prelude::*;
+use std::collections::{HashSet};
+use std::path::{Path};
+
+use crate::lib_helper::{is_table_filename, temporary_filename, install_file, TEMPORARY_SUFFIX};
+
+pub const MANIFEST_FILENAME: &str = "MANIFEST";
+
+/// Shape of a tree on disk: the files of each run of each level, and what is
+/// needed to carry on numbering sequence numbers, runs and files after it is
+/// opened again. Written as text, one line per entry:
+///     seq <last sequence number in the files>
+///     level <level> <capacity> <run counter>
+///     run <run> <capacity> <file counter>
+///     file <name>
+///     crc <crc32 of the lines above>
+/// A run belongs to the level above it and a file to the run above it. Runs are
+/// listed oldest first, files in key order. The crc line comes last, so a
+/// manifest cut short at a line boundary is not taken for a smaller tree.
+pub struct Manifest {
+    pub last_seq: u64,
+    pub levels: Vec<LevelEntry>,
+}
+
+pub struct LevelEntry {
+    pub level: usize,
+    pub capacity: usize,
+    pub run_counter: usize,
+    pub runs: Vec<RunEntry>,
+}
+
+pub struct RunEntry {
+    pub run: usize,
+    pub capacity: usize,
+    pub file_counter: usize,
+    pub files: Vec<String>, // names within the tree directory
+}
+
+/// Files removed from a tree directory when it is opened: table files its
+/// manifest does not list, and temporary files left by a crash.
+pub struct OrphanReport {
+    pub files_removed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+impl Manifest {
+    pub fn create_manifest(last_seq: u64) -> Manifest {
+        Manifest {
+            last_seq,
+            levels: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut text = format!("seq {}\n", self.last_seq);
+        for level in self.levels.iter() {
+            text += &format!("level {} {} {}\n", level.level, level.capacity, level.run_counter);
+            for run in level.runs.iter() {
+                text += &format!("run {} {} {}\n", run.run, run.capacity, run.file_counter);
+                for file in run.files.iter() {
+                    text += &format!("file {}\n", file);
+                }
+            }
+        }
+        text += &format!("crc {}\n", crc32fast::hash(text.as_bytes()));
+        text
+    }
+
+    // None if a line is damaged or missing, say by a torn write or a bad disk
+    pub fn decode(text: &str) -> Option<Manifest> {
+        let body_len = text.trim_end_matches('\n').rfind('\n').map_or(0, |idx| idx + 1);
+        let (body, trailer) = text.split_at(body_len);
+        let checksum: u32 = trailer.trim_end().strip_prefix("crc ")?.parse().ok()?;
+        if crc32fast::hash(body.as_bytes()) != checksum {
+            return None;
+        }
+        let mut manifest = Manifest::create_manifest(0);
+        for line in body.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let number = |idx: usize| -> Option<usize> {
+                fields.get(idx).and_then(|field| field.parse().ok())
+            };
+            match fields.first() {
+                Some(&"seq") => manifest.last_seq = number(1)? as u64,
+                Some(&"level") => manifest.levels.push(LevelEntry {
+                    level: number(1)?,
+                    capacity: number(2)?,
+                    run_counter: number(3)?,
+                    runs: Vec::new(),
+                }),
+                Some(&"run") => {
+                    let run = RunEntry {
+                        run: number(1)?,
+                        capacity: number(2)?,
+                        file_counter: number(3)?,
+                        files: Vec::new(),
+                    };
+                    manifest.levels.last_mut()?.runs.push(run);
+                },
+                Some(&"file") => {
+                    let name = fields.get(1).filter(|name| is_table_filename(name))?.to_string();
+                    manifest.levels.last_mut()?.runs.last_mut()?.files.push(name);
+                },
+                None => (),
+                _ => return None,
+            }
+        }
+        Some(manifest)
+    }
+
+    // None if the directory holds no tree, or its manifest is damaged
+    pub fn read(dir: &str) -> Option<Manifest> {
+        let filename = Path::new(dir).join(MANIFEST_FILENAME);
+        match fs::read_to_string(&filename) {
+            Ok(text) => Manifest::decode(&text),
+            Err(_) => None,
+        }
+    }
+
+    // whether dir has a manifest, readable or not
+    pub fn exists(dir: &str) -> bool {
+        Path::new(dir).join(MANIFEST_FILENAME).exists()
+    }
+
+    // names of the files the manifest refers to
+    pub fn files(&self) -> Vec<String> {
+        self.levels.iter().flat_map(|level| level.runs.iter()).flat_map(|run| run.files.iter().cloned()).collect()
+    }
+
+    // Written under a temporary name and renamed, so a crash leaves either the
+    // old manifest or the new one.
+    pub fn write(&self, dir: &str) {
+        let filename = Path::new(dir).join(MANIFEST_FILENAME).to_string_lossy().to_string();
+        let tmp_filename = temporary_filename(&filename);
+        let mut file = File::create(&tmp_filename).unwrap_or_else(|error| panic!("Failed to create manifest {}: {}", tmp_filename, error));
+        file.write_all(self.encode().as_bytes()).unwrap_or_else(|error| panic!("Failed to write manifest {}: {}", tmp_filename, error));
+        file.sync_all().unwrap_or_else(|error| panic!("Failed to sync manifest {}: {}", tmp_filename, error));
+        install_file(&tmp_filename, &filename);
+    }
+
+    // Removes the table files of dir the manifest does not list: the output of
+    // merges cut short by a crash, and the inputs of merges whose files were not
+    // removed yet. Temporary files a crash left before they were installed go too.
+    // Only safe while nothing writes to the tree, and only with the manifest read
+    // from dir: any other one would take the tree's files for orphans.
+    pub fn remove_orphans(&self, dir: &str) -> OrphanReport {
+        let live: HashSet<String> = self.files().into_iter().collect();
+        let mut report = OrphanReport {
+            files_removed: 0,
+            bytes_reclaimed: 0,
+        };
+        for entry in fs::read_dir(dir).unwrap_or_else(|error| panic!("Failed to list tree directory {}: {}", dir, error)) {
+            let path = entry.unwrap_or_else(|error| panic!("Failed to list tree directory {}: {}", dir, error)).path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string();
+            let temporary = name.strip_suffix(TEMPORARY_SUFFIX).is_some_and(|name| is_table_filename(name) || name == MANIFEST_FILENAME);
+            if temporary || (is_table_filename(&name) && !live.contains(&name)) {
+                let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+                match fs::remove_file(&path) {
+                    Ok(()) => {
+                        report.files_removed += 1;
+                        report.bytes_reclaimed += size;
+                    },
+                    // removed by hand or by another process meanwhile
+                    Err(error) if error.kind() == ErrorKind::NotFound => (),
+                    Err(error) => panic!("Failed to remove orphan file {:?}: {}", path, error),
+                }
+            }
+        }
+        report
+    }
+}
@@ -0,0 +1,224 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap};
+use std::fs;
+use std::path::{Path};
+
+use crate::configuration::CONFIGURATION;
+use crate::lib_helper::{generate_filename, table_filenames, sync_directory};
+use crate::lib_template::{compare_keys};
+use super::lib_disk_file::{DiskFile};
+use super::lib_manifest::{Manifest, LevelEntry, RunEntry};
+use super::lib_file_cache::{FILE_HANDLE_CACHE};
+
+use log::{warn};
+
+// suffix damaged files are kept under, out of the tree's way
+pub const CORRUPT_SUFFIX: &str = ".corrupt";
+
+/// Data repair could not read back: a block that failed its checksum, or a
+/// whole file when its footer is damaged.
+pub struct LostData {
+    pub filename: String, // name of the file before the repair
+    pub block: Option<usize>, // index of the data block, None for the whole file or the range deletion block
+    pub bytes: usize, // on disk
+    pub after_key: Option<Vec<u8>>, // the lost keys sort after this one, None if unknown
+    pub before_key: Option<Vec<u8>>, // and before this one
+    pub range_tombstones: bool, // the range deletion block, not point records
+}
+
+impl LostData {
+    pub fn describe(&self) -> String {
+        let key = |key: &Option<Vec<u8>>| key.as_ref().map_or("?".to_string(), |key| format!("{:?}", key));
+        match (self.range_tombstones, self.block) {
+            (true, _) => format!("{}: range tombstones, {} bytes", self.filename, self.bytes),
+            (false, None) => format!("{}: whole file, {} bytes", self.filename, self.bytes),
+            (false, Some(block)) => format!("{}: block {} with keys after {} and before {}, {} bytes", self.filename, block, key(&self.after_key), key(&self.before_key), self.bytes),
+        }
+    }
+}
+
+pub struct RepairReport {
+    pub files_recovered: usize, // read back whole
+    pub files_salvaged: usize, // rewritten without their damaged blocks
+    pub files_lost: usize, // nothing could be read back
+    pub records_recovered: usize,
+    pub lost: Vec<LostData>,
+    pub levels: usize,
+    pub last_seq: u64,
+}
+
+// level and run a table file was named for, None if a number does not fit
+fn parse_table_filename(name: &str) -> Option<(usize, usize)> {
+    let parts: Vec<usize> = name.split('.').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    Some((parts[0], parts[1]))
+}
+
+fn overlaps(a: &DiskFile, b: &DiskFile) -> bool {
+    compare_keys(&a.first_key, &b.last_key) != Ordering::Greater && compare_keys(&b.first_key, &a.last_key) != Ordering::Greater
+}
+
+// Reads back the table file at path, rewriting it without its damaged blocks if
+// it has any. Damaged files are renamed with CORRUPT_SUFFIX rather than removed.
+fn recover_file(path: &str, level: usize, report: &mut RepairReport) -> Option<DiskFile> {
+    let name = Path::new(path).file_name().unwrap().to_string_lossy().to_string();
+    let set_aside = || fs::rename(path, path.to_string() + CORRUPT_SUFFIX).unwrap_or_else(|error| panic!("Failed to rename {}: {}", path, error));
+    let (blocks, range_tombstones) = match DiskFile::salvage_blocks(path) {
+        Some(salvaged) => salvaged,
+        None => {
+            report.lost.push(LostData {
+                filename: name,
+                block: None,
+                bytes: fs::metadata(path).map(|metadata| metadata.len() as usize).unwrap_or(0),
+                after_key: None,
+                before_key: None,
+                range_tombstones: false,
+            });
+            report.files_lost += 1;
+            set_aside();
+            return None;
+        },
+    };
+    let damaged = blocks.iter().chain(range_tombstones.iter()).any(|block| block.is_err());
+    if !damaged {
+        let file = DiskFile::open_disk_file(path.to_string());
+        report.records_recovered += blocks.iter().chain(range_tombstones.iter()).map(|block| block.as_ref().unwrap().len()).sum::<usize>();
+        report.files_recovered += 1;
+        return Some(file);
+    }
+
+    let mut records = Vec::new();
+    for (block_idx, block) in blocks.iter().enumerate() {
+        match block {
+            Ok(block_records) => records.extend(block_records.iter().cloned()),
+            Err(bytes) => report.lost.push(LostData {
+                filename: name.clone(),
+                block: Some(block_idx),
+                bytes: *bytes,
+                after_key: blocks[..block_idx].iter().rev().find_map(|block| block.as_ref().ok()).map(|records| records.last().unwrap().key.clone()),
+                before_key: blocks[block_idx + 1..].iter().find_map(|block| block.as_ref().ok()).map(|records| records[0].key.clone()),
+                range_tombstones: false,
+            }),
+        }
+    }
+    match range_tombstones {
+        Some(Ok(tombstones)) => records.extend(tombstones),
+        Some(Err(bytes)) => report.lost.push(LostData {
+            filename: name.clone(),
+            block: None,
+            bytes,
+            after_key: None,
+            before_key: None,
+            range_tombstones: true,
+        }),
+        None => (),
+    }
+    set_aside();
+    if records.is_empty() {
+        report.files_lost += 1;
+        return None;
+    }
+    records.sort();
+    report.records_recovered += records.len();
+    report.files_salvaged += 1;
+    // renamed into place with the others once its level is known
+    Some(DiskFile::create_disk_file(path.to_string() + ".repaired", level, &records))
+}
+
+// Rebuilds the manifest of the tree in dir from the table files there, for when
+// the manifest is lost or damaged. Files are ordered newest first by their
+// sequence numbers and stacked into runs of files that do not overlap, each run
+// after every run holding keys of its files, so reads still meet the newer
+// versions first. The runs then fill the levels from the top, going deeper
+// when a level has no room for them. The log is left alone: opening the tree
+// replays whatever is newer than the files.
+pub fn repair(dir: &str) -> RepairReport {
+    let mut report = RepairReport {
+        files_recovered: 0,
+        files_salvaged: 0,
+        files_lost: 0,
+        records_recovered: 0,
+        lost: Vec::new(),
+        levels: 0,
+        last_seq: 0,
+    };
+    let names = table_filenames(dir);
+
+    // runs are numbered past every name in use, so renaming never overwrites a file
+    let mut next_run: HashMap<usize, usize> = HashMap::new();
+    let mut files = Vec::new();
+    for name in names.iter() {
+        let (level, run) = match parse_table_filename(name) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("Skipping {}, its name is not one the tree gives", name);
+                continue;
+            },
+        };
+        let counter = next_run.entry(level).or_insert(0);
+        *counter = std::cmp::max(*counter, run + 1);
+        let path = Path::new(dir).join(name).to_string_lossy().to_string();
+        if let Some(file) = recover_file(&path, level, &mut report) {
+            files.push(file);
+        }
+    }
+
+    files.sort_by(|a, b| b.max_seq.cmp(&a.max_seq).then(b.min_seq.cmp(&a.min_seq)));
+    let mut runs: Vec<Vec<DiskFile>> = Vec::new(); // in the order reads visit them
+    for file in files {
+        let run_idx = runs.iter().rposition(|run| run.iter().any(|other| overlaps(other, &file))).map_or(0, |idx| idx + 1);
+        if run_idx == runs.len() {
+            runs.push(Vec::new());
+        }
+        runs[run_idx].push(file);
+    }
+
+    let mut manifest = Manifest::create_manifest(0);
+    let mut prev_capacity = CONFIGURATION.BUFFER_CAPACITY;
+    let mut runs = runs.into_iter().peekable();
+    while runs.peek().is_some() {
+        let level = manifest.levels.len() + 1;
+        let capacity_of_run = (prev_capacity as f64 * CONFIGURATION.T_OVER_K) as usize;
+        let mut entry = LevelEntry {
+            level,
+            capacity: capacity_of_run * CONFIGURATION.RUNS_PER_LEVEL,
+            run_counter: *next_run.get(&level).unwrap_or(&0),
+            runs: Vec::new(),
+        };
+        // a run too big for the level goes deeper, unless levels stopped growing
+        let grows = entry.capacity as f64 * CONFIGURATION.T_OVER_K > capacity_of_run as f64;
+        while entry.runs.len() < CONFIGURATION.RUNS_PER_LEVEL {
+            let fits = match runs.peek() {
+                Some(run) => !grows || run.iter().map(|file| file.size).sum::<usize>() <= capacity_of_run,
+                None => false,
+            };
+            if !fits {
+                break;
+            }
+            let mut run = runs.next().unwrap();
+            run.sort_by(|a, b| compare_keys(&a.first_key, &b.first_key));
+            let mut run_entry = RunEntry {
+                run: entry.run_counter,
+                capacity: capacity_of_run,
+                file_counter: run.len(),
+                files: Vec::new(),
+            };
+            for (idx, file) in run.iter().enumerate() {
+                let filename = generate_filename(dir, level, run_entry.run, idx);
+                fs::rename(&file.filename, &filename).unwrap_or_else(|error| panic!("Failed to rename {} to {}: {}", file.filename, filename, error));
+                FILE_HANDLE_CACHE.evict(&filename);
+                run_entry.files.push(Path::new(&filename).file_name().unwrap().to_string_lossy().to_string());
+                manifest.last_seq = std::cmp::max(manifest.last_seq, file.max_seq);
+            }
+            entry.run_counter += 1;
+            // reads visit the runs of a level newest, that is last, first
+            entry.runs.insert(0, run_entry);
+        }
+        prev_capacity = entry.capacity;
+        manifest.levels.push(entry);
+    }
+    sync_directory(Path::new(dir));
+    manifest.write(dir);
+    report.levels = manifest.levels.len();
+    report.last_seq = manifest.last_seq;
+    report
+}
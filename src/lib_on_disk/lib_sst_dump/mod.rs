@@ -0,0 +1,196 @@
+use std::fs;
+
+use crate::lib_helper::{format_bytes, json_string};
+use crate::lib_template::{Record, RecordKind};
+use super::lib_block::{decode_block};
+use super::lib_compression::{block_compressor, decompress_block, verify_block};
+use super::lib_disk_file::{DiskFile, last_key_of};
+
+pub struct BlockDump {
+    pub offset: usize,
+    pub disk_size: usize, // compressed, codec id and checksum included
+    pub codec: &'static str, // "?" if the codec id is damaged
+    pub checksum_ok: bool,
+    pub uncompressed_size: usize, // 0 if the block fails its checksum
+    pub records: Vec<Record>, // empty if the block fails its checksum
+    pub range_tombstones: bool, // the range deletion block rather than a data block
+}
+
+/// A table file as it is laid out on disk, read without opening it as part of
+/// a tree. Unlike DiskFile::open_disk_file, a damaged block is reported rather
+/// than failing the whole file, so damaged files can be inspected too.
+pub struct TableDump {
+    pub filename: String,
+    pub file_size: usize,
+    pub footer_size: usize, // block offsets and trailer
+    pub global_seq: u64,
+    pub blocks: Vec<BlockDump>, // data blocks, then the range deletion block if there is one
+}
+
+impl TableDump {
+    // None if the file cannot be read or its footer is damaged, as then the blocks cannot be found
+    pub fn read_table(filename: &str) -> Option<TableDump> {
+        let bytes = fs::read(filename).ok()?;
+        let (block_offsets, range_del_offset, disk_size, global_seq, _) = DiskFile::try_decode_footer(&bytes)?;
+        let read = |start: usize, end: usize, range_tombstones: bool| {
+            let block = &bytes[start..end];
+            let checksum_ok = verify_block(block);
+            // a block may pass its checksum and still not decompress, with an unknown codec id
+            let (uncompressed_size, records) = match decompress_block(block) {
+                Ok(raw_block) => {
+                    let mut records = decode_block(&raw_block);
+                    if global_seq > 0 {
+                        for record in records.iter_mut() {
+                            record.seq = global_seq;
+                        }
+                    }
+                    (raw_block.len(), records)
+                },
+                Err(_) => (0, Vec::new()),
+            };
+            BlockDump {
+                offset: start,
+                disk_size: end - start,
+                codec: block_compressor(block).map_or("?", |compressor| compressor.name()),
+                checksum_ok,
+                uncompressed_size,
+                records,
+                range_tombstones,
+            }
+        };
+        let mut blocks: Vec<BlockDump> = (0..block_offsets.len())
+            .map(|block_idx| read(block_offsets[block_idx], *block_offsets.get(block_idx + 1).unwrap_or(&range_del_offset), false))
+            .collect();
+        if range_del_offset < disk_size {
+            blocks.push(read(range_del_offset, disk_size, true));
+        }
+        Some(TableDump {
+            filename: filename.to_string(),
+            file_size: bytes.len(),
+            footer_size: bytes.len() - disk_size,
+            global_seq,
+            blocks,
+        })
+    }
+
+    pub fn data_blocks(&self) -> impl Iterator<Item = &BlockDump> {
+        self.blocks.iter().filter(|block| !block.range_tombstones)
+    }
+
+    // records and range tombstones of the blocks that pass their checksums, sorted
+    pub fn records(&self) -> Vec<Record> {
+        let mut records: Vec<Record> = self.blocks.iter().flat_map(|block| block.records.iter().cloned()).collect();
+        records.sort();
+        records
+    }
+
+    pub fn damaged_blocks(&self) -> usize {
+        self.blocks.iter().filter(|block| !block.checksum_ok).count()
+    }
+
+    // The bloom filter is not stored, open_disk_file builds it again over the
+    // point records. None if a damaged block hides how many there are.
+    pub fn bloom_filter_keys(&self) -> Option<usize> {
+        match self.data_blocks().all(|block| block.checksum_ok) {
+            true => Some(self.data_blocks().map(|block| block.records.len()).sum()),
+            false => None,
+        }
+    }
+
+    // Layout of the file, then every record if records is set. Keys and values
+    // are shown as the 4 byte integers the workloads write if int_keys is set.
+    pub fn print_text(&self, records: bool, int_keys: bool) {
+        let show = |bytes: &[u8]| format_bytes(bytes, int_keys);
+        let all_records = self.records();
+        let count = |kind: RecordKind| all_records.iter().filter(|record| record.kind == kind).count();
+        println!("file          {}", self.filename);
+        println!("size          {} bytes: {} in {} blocks, {} of footer", self.file_size, self.file_size - self.footer_size, self.blocks.len(), self.footer_size);
+        println!("uncompressed  {} bytes", self.blocks.iter().map(|block| block.uncompressed_size).sum::<usize>());
+        println!("records       {}: {} values, {} deletions, {} merge operands, {} range tombstones",
+            all_records.len(), count(RecordKind::Value), count(RecordKind::Deletion), count(RecordKind::Merge), count(RecordKind::RangeDeletion));
+        if let Some(first) = all_records.first() {
+            println!("key range     {} .. {}", show(&first.key), show(&last_key_of(&all_records)));
+            println!("seq range     {} .. {}", all_records.iter().map(|record| record.seq).min().unwrap(), all_records.iter().map(|record| record.seq).max().unwrap());
+        }
+        match self.global_seq {
+            0 => println!("global seq    none"),
+            seq => println!("global seq    {} (ingested)", seq),
+        }
+        match self.bloom_filter_keys() {
+            Some(keys) => {
+                let (bits, hashes) = DiskFile::bloom_filter_size(std::cmp::max(keys, 1));
+                println!("bloom filter  {} keys, {} bits, {} hashes, built when the file is opened", keys, bits, hashes);
+            },
+            None => println!("bloom filter  unknown, a data block is damaged"),
+        }
+        println!("blocks");
+        println!("  {:>5} {:>10} {:>9} {:>12} {:>8} {:>8} {:>8}  fence pointer", "block", "offset", "disk size", "uncompressed", "codec", "records", "checksum");
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            let name = match block.range_tombstones {
+                true => "range".to_string(),
+                false => block_idx.to_string(),
+            };
+            let fence_pointer = match (block.range_tombstones, block.records.first()) {
+                (false, Some(record)) => show(&record.key),
+                _ => String::new(),
+            };
+            println!("  {:>5} {:>10} {:>9} {:>12} {:>8} {:>8} {:>8}  {}", name, block.offset, block.disk_size, block.uncompressed_size, block.codec,
+                block.records.len(), if block.checksum_ok { "ok" } else { "MISMATCH" }, fence_pointer);
+        }
+        println!("checksums     {} of {} blocks ok", self.blocks.len() - self.damaged_blocks(), self.blocks.len());
+        if records {
+            println!("records");
+            for record in all_records.iter() {
+                match record.kind {
+                    RecordKind::Value if record.expire_at > 0 => println!("  {} seq {} value {} expires {}", show(&record.key), record.seq, show(&record.value), record.expire_at),
+                    RecordKind::Value => println!("  {} seq {} value {}", show(&record.key), record.seq, show(&record.value)),
+                    RecordKind::Deletion => println!("  {} seq {} deletion", show(&record.key), record.seq),
+                    RecordKind::Merge => println!("  {} seq {} merge {}", show(&record.key), record.seq, show(&record.value)),
+                    RecordKind::RangeDeletion => println!("  {} seq {} range deletion to {}", show(&record.key), record.seq, show(&record.value)),
+                }
+            }
+        }
+    }
+
+    // the same as print_text, as one JSON object
+    pub fn print_json(&self, records: bool, int_keys: bool) {
+        let show = |bytes: &[u8]| json_string(&format_bytes(bytes, int_keys));
+        let mut out = String::new();
+        out += &format!("{{\"file\":{},\"file_size\":{},\"footer_size\":{},\"global_seq\":{},",
+            json_string(&self.filename), self.file_size, self.footer_size, self.global_seq);
+        match self.bloom_filter_keys() {
+            Some(keys) => {
+                let (bits, hashes) = DiskFile::bloom_filter_size(std::cmp::max(keys, 1));
+                out += &format!("\"bloom_filter\":{{\"keys\":{},\"bits\":{},\"hashes\":{}}},", keys, bits, hashes);
+            },
+            None => out += "\"bloom_filter\":null,",
+        }
+        let blocks: Vec<String> = self.blocks.iter().map(|block| {
+            let fence_pointer = match (block.range_tombstones, block.records.first()) {
+                (false, Some(record)) => show(&record.key),
+                _ => "null".to_string(),
+            };
+            format!("{{\"offset\":{},\"disk_size\":{},\"uncompressed_size\":{},\"codec\":{},\"records\":{},\"checksum_ok\":{},\"range_tombstones\":{},\"fence_pointer\":{}}}",
+                block.offset, block.disk_size, block.uncompressed_size, json_string(block.codec), block.records.len(), block.checksum_ok, block.range_tombstones, fence_pointer)
+        }).collect();
+        out += &format!("\"blocks\":[{}],\"damaged_blocks\":{}", blocks.join(","), self.damaged_blocks());
+        if records {
+            let records: Vec<String> = self.records().iter().map(|record| {
+                let kind = match record.kind {
+                    RecordKind::Value => "value",
+                    RecordKind::Deletion => "deletion",
+                    RecordKind::Merge => "merge",
+                    RecordKind::RangeDeletion => "range_deletion",
+                };
+                let value = match record.kind {
+                    RecordKind::Deletion => "null".to_string(),
+                    _ => show(&record.value),
+                };
+                format!("{{\"key\":{},\"value\":{},\"seq\":{},\"kind\":\"{}\",\"expire_at\":{}}}", show(&record.key), value, record.seq, kind, record.expire_at)
+            }).collect();
+            out += &format!(",\"records\":[{}]", records.join(","));
+        }
+        out += "}";
+        println!("{}", out);
+    }
+}
@@ -0,0 +1,70 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path};
+
+use crate::configuration::CONFIGURATION;
+use crate::lib_template::{Record, compare_keys};
+use super::lib_disk_file::{DiskFile};
+
+/// Builds table files outside of any tree, for LSMTree::ingest. Keys are added
+/// in increasing order and each only once. A file is written as soon as it
+/// holds FILE_SIZE bytes of records, so only one file is ever in memory however
+/// much is written. The records carry no sequence numbers: the tree gives them
+/// one when the files are ingested.
+pub struct SstWriter {
+    dir: String,
+    records: Vec<Record>,
+    size: usize, // bytes of records not written yet
+    files: Vec<String>, // written so far, in key order
+    last_written: Option<Vec<u8>>, // last key of the last file written
+}
+
+impl SstWriter {
+    pub fn create_writer(dir: &str) -> SstWriter {
+        fs::create_dir_all(dir).unwrap_or_else(|error| panic!("Failed to create directory {}: {}", dir, error));
+        SstWriter {
+            dir: dir.to_string(),
+            records: Vec::new(),
+            size: 0,
+            files: Vec::new(),
+            last_written: None,
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        if let Some(last) = self.last_key() {
+            assert!(compare_keys(last, key) == Ordering::Less, "Keys must be added to an SstWriter in increasing order");
+        }
+        let record = Record::create_record(key.to_vec(), value.to_vec(), 0);
+        self.size += record.size();
+        self.records.push(record);
+        if self.size >= CONFIGURATION.FILE_SIZE {
+            self.write_file();
+        }
+    }
+
+    // names of the files written, in key order
+    pub fn finish(mut self) -> Vec<String> {
+        if !self.records.is_empty() {
+            self.write_file();
+        }
+        std::mem::take(&mut self.files)
+    }
+
+    fn last_key(&self) -> Option<&[u8]> {
+        match self.records.last() {
+            Some(record) => Some(&record.key),
+            None => self.last_written.as_ref().map(|key| &key[..]),
+        }
+    }
+
+    // ingested files usually end up deep in the tree, so they are compressed for the deepest level
+    fn write_file(&mut self) {
+        let filename = Path::new(&self.dir).join(format!("{}.sst", self.files.len())).to_string_lossy().to_string();
+        DiskFile::create_disk_file(filename.clone(), CONFIGURATION.COMPRESSION.len(), &self.records);
+        self.last_written = self.records.last().map(|record| record.key.clone());
+        self.records.clear();
+        self.size = 0;
+        self.files.push(filename);
+    }
+}
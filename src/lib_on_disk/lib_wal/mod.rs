@@ -0,0 +1,88 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::convert::TryInto;
+use parking_lot::{Mutex};
+
+use crate::configuration::CONFIGURATION;
+
+pub const WAL_FILENAME: &str = "wal.log";
+
+// Each log record is framed as [payload length (u32)][crc32 of payload (u32)][payload].
+// A crash can leave the last record torn; replay stops at the first record that
+// is short or fails its checksum, so a record is either replayed whole or not at all.
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Write-ahead log of the writes held in the memory buffer. It is emptied once
+/// the buffer has been flushed to level 1.
+pub struct WriteAheadLog {
+    filename: String,
+    file: Mutex<File>,
+}
+
+impl WriteAheadLog {
+    // starts an empty log, discarding any previous one
+    pub fn create_log(filename: &str) -> WriteAheadLog {
+        let file = File::create(filename).unwrap_or_else(|error| panic!("Failed to create log {}: {}", filename, error));
+        WriteAheadLog {
+            filename: filename.to_string(),
+            file: Mutex::new(file),
+        }
+    }
+
+    // Appends to an existing log after its records have been replayed. A torn
+    // last record is cut off first, or it would hide the records after it.
+    pub fn open_log(filename: &str) -> WriteAheadLog {
+        let valid_len: usize = WriteAheadLog::read_log(filename).iter().map(|payload| FRAME_HEADER_SIZE + payload.len()).sum();
+        let file = OpenOptions::new().create(true).append(true).open(filename).unwrap_or_else(|error| panic!("Failed to open log {}: {}", filename, error));
+        file.set_len(valid_len as u64).unwrap_or_else(|error| panic!("Failed to truncate log {}: {}", filename, error));
+        WriteAheadLog {
+            filename: filename.to_string(),
+            file: Mutex::new(file),
+        }
+    }
+
+    pub fn append(&self, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend(&(payload.len() as u32).to_be_bytes());
+        frame.extend(&crc32fast::hash(payload).to_be_bytes());
+        frame.extend(payload);
+        let mut file = self.file.lock();
+        file.write_all(&frame).unwrap_or_else(|error| panic!("Failed to append to log {}: {}", self.filename, error));
+        if CONFIGURATION.WAL_SYNC {
+            file.sync_data().unwrap_or_else(|error| panic!("Failed to sync log {}: {}", self.filename, error));
+        }
+    }
+
+    // called with the buffer's write lock held, once its contents are on disk
+    pub fn reset(&self) {
+        let mut file = self.file.lock();
+        *file = File::create(&self.filename).unwrap_or_else(|error| panic!("Failed to truncate log {}: {}", self.filename, error));
+    }
+
+    // bytes of the log, for a copy of the tree
+    pub fn contents(&self) -> Vec<u8> {
+        let _file = self.file.lock();
+        fs::read(&self.filename).unwrap_or_else(|error| panic!("Failed to read log {}: {}", self.filename, error))
+    }
+
+    // payloads of the complete records in the log, oldest first
+    pub fn read_log(filename: &str) -> Vec<Vec<u8>> {
+        let mut bytes = Vec::new();
+        if let Ok(mut file) = File::open(filename) {
+            file.read_to_end(&mut bytes).unwrap_or_else(|error| panic!("Failed to read log {}: {}", filename, error));
+        }
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        while offset + FRAME_HEADER_SIZE <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+            let start = offset + FRAME_HEADER_SIZE;
+            if start + len > bytes.len() || crc32fast::hash(&bytes[start..start + len]) != crc {
+                break;
+            }
+            payloads.push(bytes[start..start + len].to_vec());
+            offset = start + len;
+        }
+        payloads
+    }
+}
@@ -1,3 +1,13 @@
 pub mod lib_disk_file;
 pub mod lib_disk_run;
-pub mod lib_disk_level;
\ No newline at end of file
+pub mod lib_disk_level;
+pub mod lib_file_cache;
+pub mod lib_direct_io;
+pub mod lib_block;
+pub mod lib_int_block;
+pub mod lib_compression;
+pub mod lib_wal;
+pub mod lib_manifest;
+pub mod lib_sst_writer;
+pub mod lib_repair;
+pub mod lib_sst_dump;
@@ -0,0 +1,164 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{Ordering};
+
+use crate::lib_helper::{format_bytes, i32_to_bytes};
+use crate::lib_lsm_tree::LSMTree;
+use crate::lib_on_disk::lib_compression::{compressor_for_level};
+use crate::lib_on_disk::lib_manifest::{Manifest};
+
+const HELP: &str = "\
+get KEY                 value of KEY, also g KEY
+put KEY VALUE           also p KEY VALUE or b KEY VALUE
+merge KEY OPERAND       adds OPERAND to the integer at KEY, also a KEY OPERAND
+delete KEY              also d KEY
+scan LOW HIGH [LIMIT]   keys in [LOW, HIGH] with their values, at most LIMIT of them
+stats                   sequence numbers, buffer and disk usage
+levels                  levels, runs and files with their key ranges
+layout [WIDTH]          the runs of each level drawn by key range
+flush                   writes the buffer to level 1
+compact                 merges every level into the last one
+checkpoint DIR          copy of the tree in DIR
+help
+quit";
+
+/// Runs the commands read from input against tree, one per line, for looking
+/// at a tree by hand. The workload opcodes p, b, g and a work as well, so lines
+/// of a workload file can be pasted in. Keys and values are taken as 4 byte
+/// integers if int_keys is set, as text otherwise, and 0x prefixed ones as hex
+/// either way, the same as they are shown.
+pub fn run_shell(tree: &LSMTree, input: impl BufRead, prompt: bool, int_keys: bool) {
+    let show_prompt = || {
+        if prompt {
+            print!("> ");
+            io::stdout().flush().expect("Failed to write to stdout");
+        }
+    };
+    show_prompt();
+    for line in input.lines() {
+        let line = line.expect("Failed to read command");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            Some(&"quit") | Some(&"exit") => return,
+            Some(_) => {
+                if let Err(message) = run_command(tree, &words, int_keys) {
+                    println!("error: {}", message);
+                }
+            },
+            None => (),
+        }
+        show_prompt();
+    }
+}
+
+fn run_command(tree: &LSMTree, words: &[&str], int_keys: bool) -> Result<(), String> {
+    let show = |bytes: &[u8]| format_bytes(bytes, int_keys);
+    let arg = |idx: usize| -> Result<Vec<u8>, String> {
+        match words.get(idx) {
+            Some(word) => parse_bytes(word, int_keys),
+            None => Err(format!("Missing argument to {}, see help", words[0])),
+        }
+    };
+    match words[0] {
+        "get" | "g" => {
+            let mut value = Vec::new();
+            match tree.try_get(&arg(1)?, &mut value)? {
+                true => println!("{}", show(&value)),
+                false => println!("not found"),
+            }
+        },
+        "put" | "p" | "b" => tree.put(&arg(1)?, &arg(2)?),
+        "merge" | "a" => tree.merge(&arg(1)?, &arg(2)?),
+        "delete" | "d" => tree.delete(&arg(1)?),
+        "scan" => {
+            let (lower, upper) = (arg(1)?, arg(2)?);
+            let limit = match words.get(3) {
+                Some(word) => word.parse::<usize>().map_err(|_| format!("Bad limit {}", word))?,
+                None => usize::MAX,
+            };
+            let records = tree.scan(&lower, &upper, None);
+            for record in records.iter().take(limit) {
+                println!("{} {}", show(&record.key), show(&record.value));
+            }
+            println!("{} keys", records.len());
+        },
+        "stats" => print_stats(tree),
+        "levels" => print_levels(tree, int_keys),
+        "layout" => {
+            let width = match words.get(1) {
+                Some(word) => word.parse::<usize>().ok().filter(|width| *width > 0).ok_or(format!("Bad width {}", word))?,
+                None => 64,
+            };
+            print!("{}", tree.layout().to_ascii(width, int_keys));
+        },
+        "flush" => tree.flush(),
+        "compact" => {
+            tree.compact_all();
+            print_levels(tree, int_keys);
+        },
+        "checkpoint" => {
+            let dest = words.get(1).ok_or("Missing argument to checkpoint, see help")?;
+            // checkpoint panics on it, which would end the shell
+            if Manifest::exists(dest) {
+                return Err(format!("{} already holds a tree", dest));
+            }
+            tree.checkpoint(dest);
+            println!("checkpoint in {}", dest);
+        },
+        "help" => println!("{}", HELP),
+        command => return Err(format!("Unknown command {}, see help", command)),
+    }
+    Ok(())
+}
+
+// as format_bytes shows them
+fn parse_bytes(word: &str, int_keys: bool) -> Result<Vec<u8>, String> {
+    if word.starts_with("0x") && word.len() > 2 {
+        return (2..word.len()).step_by(2)
+            .map(|idx| word.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(format!("Bad hex {}", word));
+    }
+    match int_keys {
+        true => word.parse::<i32>().map(i32_to_bytes).map_err(|_| format!("Bad integer {}", word)),
+        false => Ok(word.as_bytes().to_vec()),
+    }
+}
+
+pub fn print_stats(tree: &LSMTree) {
+    println!("directory     {}", tree.dir);
+    println!("sequence      last {}, visible {}, flushed {}", tree.sequence.load(Ordering::Acquire), tree.visible_seq.load(Ordering::Acquire), tree.flushed_seq.load(Ordering::Acquire));
+    let buffer = tree.buffer.read();
+    println!("buffer        {} of {} bytes", buffer.size(), buffer.capacity());
+    drop(buffer);
+    let levels = tree.levels.read();
+    let mut files = 0;
+    let mut disk_size = 0;
+    for level in levels.iter() {
+        for run in level.runs.read().iter() {
+            files += run.files.len();
+            disk_size += run.files.iter().map(|file| file.file_size()).sum::<u64>();
+        }
+    }
+    println!("disk          {} levels, {} bytes of records, {} files, {} bytes on disk", levels.len(), levels.iter().map(|level| level.size()).sum::<usize>(), files, disk_size);
+    drop(levels);
+    if let Some(row_cache) = &tree.row_cache {
+        println!("row cache     {} of {} bytes, hit ratio {:.3}", row_cache.size(), row_cache.capacity(), row_cache.hit_ratio());
+    }
+    println!("orphans       {} files, {} bytes removed at open", tree.orphans.files_removed, tree.orphans.bytes_reclaimed);
+}
+
+pub fn print_levels(tree: &LSMTree, int_keys: bool) {
+    let show = |bytes: &[u8]| format_bytes(bytes, int_keys);
+    let levels = tree.levels.read();
+    for level in levels.iter() {
+        let runs = level.runs.read();
+        println!("level {}: {} runs, {} of {} bytes, {}, compression ratio {:.2}", level.level, runs.len(), level.size(), level.capacity(), compressor_for_level(level.level).name(), level.compression_ratio());
+        // newest run last
+        for run in runs.iter() {
+            println!("  run {}: {} files, {} of {} bytes", run.run, run.files.len(), run.size, run.capacity);
+            for file in run.files.iter() {
+                println!("    {}  {} bytes  keys {} .. {}  seqs {} .. {}", file.name(), file.size, show(&file.first_key), show(&file.last_key), file.min_seq, file.max_seq);
+            }
+        }
+    }
+}
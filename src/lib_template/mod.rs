@@ -1,25 +1,290 @@
+use lazy_static::lazy_static;
+use std::cell::{RefCell};
 use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::lib_helper::{i32_to_bytes, bytes_to_i32};
+use crate::lib_on_disk::lib_block::{entry_size};
+
+// milliseconds since the Unix epoch, the unit of Record::expire_at
+pub fn current_time_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("System time is before the Unix epoch").as_millis() as u64
+}
+
+/// Orders keys. A tree compares its keys with the comparator in its
+/// `TreeOptions`, bytewise order if it has none. The tree's operations make it
+/// the current comparator of their thread; code working on a tree's files
+/// outside of them, like repair or an SstWriter, enters a `ComparatorScope`.
+pub trait Comparator: Send + Sync {
+	fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+	fn name(&self) -> &str;
+}
+
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+	fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+		a.cmp(b)
+	}
+
+	fn name(&self) -> &str {
+		"bytewise"
+	}
+}
+
+lazy_static! {
+	static ref BYTEWISE: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+}
+
+thread_local! {
+	// comparator of the operation running on this thread, None for bytewise order
+	static CURRENT_COMPARATOR: RefCell<Option<Arc<dyn Comparator>>> = RefCell::new(None);
+}
+
+/// Makes a comparator the current one of the thread until it is dropped, then
+/// restores the one before. None stands for bytewise order.
+pub struct ComparatorScope {
+	previous: Option<Arc<dyn Comparator>>,
+}
+
+impl ComparatorScope {
+	pub fn enter(comparator: Option<&Arc<dyn Comparator>>) -> ComparatorScope {
+		ComparatorScope {
+			previous: CURRENT_COMPARATOR.with(|current| current.replace(comparator.cloned())),
+		}
+	}
+}
+
+impl Drop for ComparatorScope {
+	fn drop(&mut self) {
+		let previous = self.previous.take();
+		CURRENT_COMPARATOR.with(|current| *current.borrow_mut() = previous);
+	}
+}
+
+pub fn comparator() -> Arc<dyn Comparator> {
+	CURRENT_COMPARATOR.with(|current| current.borrow().clone()).unwrap_or_else(|| BYTEWISE.clone())
+}
+
+// whether keys are in bytewise order, so encodings may rely on it
+pub fn is_bytewise() -> bool {
+	CURRENT_COMPARATOR.with(|current| current.borrow().is_none())
+}
+
+pub fn compare_keys(a: &[u8], b: &[u8]) -> Ordering {
+	CURRENT_COMPARATOR.with(|current| match &*current.borrow() {
+		Some(comparator) => comparator.compare(a, b),
+		None => a.cmp(b),
+	})
+}
+
+/// Combines the operands written with `LSMTree::merge` with the value they
+/// apply to, so read-modify-write updates need no read. Set per tree in `TreeOptions`.
+pub trait MergeOperator: Send + Sync {
+	// existing is None if the key has no value or was deleted, operands are oldest first
+	fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8>;
+	// Combines operands, oldest first, into one operand, or None if they can only
+	// be applied to a value. Lets compaction shorten chains that have no value below.
+	fn partial_merge(&self, _key: &[u8], _operands: &[&[u8]]) -> Option<Vec<u8>> {
+		None
+	}
+	fn name(&self) -> &str;
+}
+
+// Adds i32 values encoded with lib_helper::i32_to_bytes, wrapping on overflow.
+// A missing value counts as 0.
+pub struct IntAddOperator;
+
+impl MergeOperator for IntAddOperator {
+	fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+		let operands: Vec<&[u8]> = existing.into_iter().chain(operands.iter().cloned()).collect();
+		self.partial_merge(key, &operands).unwrap()
+	}
+
+	fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Vec<u8>> {
+		let sum = operands.iter().fold(0i32, |sum, operand| sum.wrapping_add(bytes_to_i32(operand)));
+		Some(i32_to_bytes(sum))
+	}
+
+	fn name(&self) -> &str {
+		"int_add"
+	}
+}
+
+// Sequence number of the newest of the range tombstones that covers key and is
+// visible at seq, 0 if none does. Versions of key older than it are deleted.
+pub fn covering_tombstone_seq<'a>(tombstones: impl Iterator<Item = &'a Record>, key: &[u8], seq: u64) -> u64 {
+	tombstones.filter(|tombstone| tombstone.seq <= seq && tombstone.covers(key))
+		.map(|tombstone| tombstone.seq)
+		.max()
+		.unwrap_or(0)
+}
+
+// Value of key once operands, newest first, are applied to base
+pub fn apply_operands(merge_operator: Option<&dyn MergeOperator>, key: &[u8], base: Option<Vec<u8>>, operands: &[Vec<u8>]) -> Option<Vec<u8>> {
+	if operands.is_empty() {
+		return base;
+	}
+	let merge_operator = merge_operator.expect("Merge operands found, but the tree has no merge operator, see TreeOptions::merge_operator");
+	let operands: Vec<&[u8]> = operands.iter().rev().map(|operand| &operand[..]).collect();
+	Some(merge_operator.full_merge(key, base.as_ref().map(|base| &base[..]), &operands))
+}
+
+// Value of a key from its versions, newest first, or None if it is deleted or
+// expired. Only the versions down to the first one that is not a merge operand are read.
+pub fn resolve_versions(merge_operator: Option<&dyn MergeOperator>, versions: &[Record]) -> Option<Vec<u8>> {
+	let now = current_time_millis();
+	let mut operands = Vec::new();
+	for version in versions.iter() {
+		match version.kind {
+			RecordKind::Merge => operands.push(version.value.clone()),
+			RecordKind::Value if version.is_expired(now) => return apply_operands(merge_operator, &version.key, None, &operands),
+			RecordKind::Value => return apply_operands(merge_operator, &version.key, Some(version.value.clone()), &operands),
+			RecordKind::Deletion | RecordKind::RangeDeletion => return apply_operands(merge_operator, &version.key, None, &operands),
+		}
+	}
+	match versions.first() {
+		Some(version) => apply_operands(merge_operator, &version.key, None, &operands),
+		None => None,
+	}
+}
+
+/// What a version of a key holds. A deletion is kept as a tombstone with an
+/// empty value until compaction reaches the bottom of the tree. A merge holds an
+/// operand for the merge operator, applied to the versions below it. A range
+/// deletion deletes the older versions of every key from its key to its value,
+/// inclusive; it is sorted by its first key but never read as a version of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+	Value,
+	Deletion,
+	Merge,
+	RangeDeletion,
+}
+
+impl RecordKind {
+	pub fn to_byte(self) -> u8 {
+		match self {
+			RecordKind::Value => 0,
+			RecordKind::Deletion => 1,
+			RecordKind::Merge => 2,
+			RecordKind::RangeDeletion => 3,
+		}
+	}
+
+	pub fn from_byte(byte: u8) -> RecordKind {
+		match byte {
+			0 => RecordKind::Value,
+			1 => RecordKind::Deletion,
+			2 => RecordKind::Merge,
+			3 => RecordKind::RangeDeletion,
+			_ => panic!("Unknown record kind {}", byte),
+		}
+	}
+}
 
 #[allow(non_snake_case)]
-#[derive(Debug, Copy, Clone)]
-#[derive(Eq, Hash)]
+#[derive(Debug, Clone)]
+#[derive(Eq)]
 pub struct Record
 {
-	pub key: i32,
-	pub value: i32,
+	pub key: Vec<u8>,
+	pub value: Vec<u8>,
+	pub seq: u64, // sequence number of the write, later writes have higher numbers
+	pub kind: RecordKind,
+	pub expire_at: u64, // time in ms after which a value reads as deleted, 0 if it never expires
 }
 
 impl Record {
-	pub fn create_record(key: i32, value: i32) -> Record 
+	pub fn create_record(key: Vec<u8>, value: Vec<u8>, seq: u64) -> Record 
 	{
 		Record {
-			key: key,
+			key,
 			value: value,
+			seq,
+			kind: RecordKind::Value,
+			expire_at: 0,
+		}
+	}
+
+	pub fn create_expiring_record(key: Vec<u8>, value: Vec<u8>, seq: u64, expire_at: u64) -> Record {
+		Record {
+			key,
+			value,
+			seq,
+			kind: RecordKind::Value,
+			expire_at,
+		}
+	}
+
+	pub fn create_tombstone(key: Vec<u8>, seq: u64) -> Record {
+		Record {
+			key,
+			value: Vec::new(),
+			seq,
+			kind: RecordKind::Deletion,
+			expire_at: 0,
+		}
+	}
+
+	pub fn create_merge_operand(key: Vec<u8>, operand: Vec<u8>, seq: u64) -> Record {
+		Record {
+			key,
+			value: operand,
+			seq,
+			kind: RecordKind::Merge,
+			expire_at: 0,
+		}
+	}
+
+	// deletes the versions older than seq of the keys in [start, end]
+	pub fn create_range_tombstone(start: Vec<u8>, end: Vec<u8>, seq: u64) -> Record {
+		Record {
+			key: start,
+			value: end,
+			seq,
+			kind: RecordKind::RangeDeletion,
+			expire_at: 0,
 		}
 	}
+
+	pub fn is_deletion(&self) -> bool {
+		self.kind == RecordKind::Deletion
+	}
+
+	pub fn is_merge(&self) -> bool {
+		self.kind == RecordKind::Merge
+	}
+
+	pub fn is_range_deletion(&self) -> bool {
+		self.kind == RecordKind::RangeDeletion
+	}
+
+	// whether this range tombstone spans key
+	pub fn covers(&self, key: &[u8]) -> bool {
+		compare_keys(&self.key, key) != Ordering::Greater && compare_keys(key, &self.value) != Ordering::Greater
+	}
+
+	// whether this range tombstone overlaps [lower, upper]
+	pub fn overlaps(&self, lower: &[u8], upper: &[u8]) -> bool {
+		compare_keys(&self.key, upper) != Ordering::Greater && compare_keys(lower, &self.value) != Ordering::Greater
+	}
+
+	pub fn is_expired(&self, now: u64) -> bool {
+		self.expire_at != 0 && self.expire_at <= now
+	}
+
+	// bytes taken by the record once encoded in a block, before its key is
+	// prefix compressed
+	pub fn size(&self) -> usize {
+		entry_size(0, self.key.len(), self.value.len(), self.seq, self.expire_at)
+	}
 }
 
-// Don't want to compare values at all, only keys!
+// Don't want to compare values at all, only keys! Versions of the same key
+// are ordered newest first.
 impl PartialOrd for Record {
     fn partial_cmp(&self, other: &Record) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -28,13 +293,20 @@ impl PartialOrd for Record {
 
 impl Ord for Record {
     fn cmp(&self, other: &Record) -> Ordering {
-        self.key.cmp(&other.key)
+        compare_keys(&self.key, &other.key)
+            .then_with(|| other.seq.cmp(&self.seq))
     }
 }
 
 impl PartialEq for Record {
     fn eq(&self, other: &Record) -> bool {
-        self.key == other.key
+        self.key == other.key && self.seq == other.seq
+    }
+}
+
+impl Hash for Record {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
     }
 }
 
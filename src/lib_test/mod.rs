@@ -9,6 +9,97 @@ use gag::Redirect;
 use log::{info, debug};
 use threadpool::{ThreadPool};
 
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::path::{Path};
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use std::time::{Duration};
+#[cfg(test)]
+use bloom::BloomFilter;
+#[cfg(test)]
+use rand::Rng;
+#[cfg(test)]
+use super::lib_on_disk::lib_file_cache::{FileHandleCache};
+#[cfg(test)]
+use super::lib_in_memory::lib_row_cache::{RowCache};
+#[cfg(test)]
+use super::lib_in_memory::lib_skiplist::{SkipListMemtable};
+#[cfg(test)]
+use super::lib_in_memory::lib_memtable::{Memtable};
+
+#[cfg(test)]
+lazy_static! {
+	// the working directory of the tests running in_test_dir
+	static ref WORKING_DIR: Mutex<()> = Mutex::new(());
+}
+#[cfg(test)]
+use std::sync::{Arc};
+#[cfg(test)]
+use std::cmp::Ordering as KeyOrdering;
+#[cfg(test)]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(test)]
+use lazy_static::lazy_static;
+#[cfg(test)]
+use parking_lot::{Mutex};
+#[cfg(test)]
+use super::lib_helper::{i32_to_bytes, bytes_to_i32, generate_filename, table_filenames};
+#[cfg(test)]
+use super::lib_lsm_tree::LSMTree;
+#[cfg(test)]
+use super::lib_lsm_tree::lib_options::{TreeOptions};
+#[cfg(test)]
+use super::lib_template::{Record, IntAddOperator, Comparator};
+#[cfg(test)]
+use super::lib_merge::{CompactionFilter, FilterDecision};
+#[cfg(test)]
+use super::lib_on_disk::lib_sst_writer::{SstWriter};
+#[cfg(test)]
+use super::lib_lsm_tree::lib_ingest::{IngestOptions};
+#[cfg(test)]
+use super::configuration::{CONFIGURATION, Configuration, CompressionType};
+#[cfg(test)]
+use super::lib_cli;
+#[cfg(test)]
+use super::lib_on_disk::lib_repair::{repair};
+#[cfg(test)]
+use std::os::unix::fs::FileExt;
+#[cfg(test)]
+use super::lib_on_disk::lib_disk_file::{DiskFile};
+#[cfg(test)]
+use super::lib_on_disk::lib_manifest::{MANIFEST_FILENAME};
+#[cfg(test)]
+use super::lib_on_disk::lib_direct_io::{write_direct, read_direct, open_direct, DIRECT_IO_ALIGNMENT};
+#[cfg(test)]
+use super::lib_in_memory::{MemoryBuffer};
+#[cfg(test)]
+use super::lib_on_disk::lib_block::{BlockBuilder, decode_block, search_block};
+#[cfg(test)]
+use super::lib_on_disk::lib_compression::{decompress_block};
+#[cfg(test)]
+use super::lib_on_disk::lib_compression::{compress_block, compressor_by_id, block_compressor, compressor_for_level};
+#[cfg(test)]
+use super::lib_on_disk::lib_int_block::{encode_int_block, decode_int_block, search_int_block, to_u32};
+#[cfg(test)]
+#[cfg(test)]
+#[cfg(test)]
+use super::lib_on_disk::lib_disk_run::{Run};
+#[cfg(test)]
+#[cfg(test)]
+#[cfg(test)]
+use super::lib_on_disk::lib_sst_dump::{TableDump};
+#[cfg(test)]
+#[cfg(test)]
+#[cfg(test)]
+use super::lib_shell::{run_shell};
+#[cfg(test)]
+#[cfg(test)]
+#[cfg(test)]
+use std::io::Cursor;
+
 // Run with cargo test -- --nocapture
 
 #[cfg(test)]
@@ -17,7 +108,7 @@ fn test_bloom_filter_with_fpr()
 {
 	let expected_num_items: usize = 10000;
 	let false_positive_rate = 0.001;
-	let mut filter = BloomFilter::with_rate(false_positive_rate,expected_num_items);
+	let mut filter = BloomFilter::with_rate(false_positive_rate,expected_num_items as u32);
 
 	let mut vector_existing_elements: Vec<i32> = Vec::new();
 	let mut vector_non_existing_elements: Vec<i32> = Vec::new();
@@ -67,7 +158,7 @@ fn test_bloom_filter_with_bits_per_entry()
 {
 	let expected_num_items: usize = 10000;
 	let num_bits = 5;
-	let num_hashes = bloom::optimal_num_hashes(num_bits,expected_num_items);
+	let num_hashes = bloom::optimal_num_hashes(num_bits,expected_num_items as u32);
 	let mut filter = BloomFilter::with_size(num_bits,num_hashes);
 
 	let mut vector_existing_elements: Vec<i32> = Vec::new();
@@ -114,6 +205,1013 @@ fn test_bloom_filter_with_bits_per_entry()
 	drop(filter);
 }
 
+// An empty directory of its own for the files of a test, as tests run in parallel
+#[cfg(test)]
+fn test_dir(name: &str) -> String {
+	let dir = std::env::temp_dir().join(format!("hybrid_de_test_{}", name));
+	let _ = fs::remove_dir_all(&dir);
+	dir.to_string_lossy().to_string()
+}
+
+// Runs test with a test_dir as the working directory, where trees keep their files
+#[cfg(test)]
+fn in_test_dir(name: &str, test: impl FnOnce()) {
+	let _working_dir = WORKING_DIR.lock();
+	let dir = test_dir(name);
+	fs::create_dir_all(&dir).unwrap();
+	let previous = std::env::current_dir().unwrap();
+	std::env::set_current_dir(&dir).unwrap();
+	let result = panic::catch_unwind(AssertUnwindSafe(test));
+	std::env::set_current_dir(previous).unwrap();
+	if let Err(payload) = result {
+		panic::resume_unwind(payload);
+	}
+}
+
+// keys 0..n with their own values
+#[cfg(test)]
+fn fill_tree(tree: &LSMTree, n: i32) {
+	for i in 0..n {
+		tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+	}
+}
+
+// value of key read as an i32, None if not found
+#[cfg(test)]
+fn get_i32(tree: &LSMTree, key: i32) -> Option<i32> {
+	let mut value = Vec::new();
+	match tree.get(&i32_to_bytes(key), &mut value) {
+		true => Some(bytes_to_i32(&value)),
+		false => None,
+	}
+}
+
+#[test]
+fn test_file_handle_cache_evicts_least_recently_used() {
+	let dir = test_dir("file_handle_cache");
+	fs::create_dir_all(&dir).unwrap();
+	let names: Vec<String> = (0..3).map(|i| {
+		let name = Path::new(&dir).join(i.to_string()).to_string_lossy().to_string();
+		fs::write(&name, b"data").unwrap();
+		name
+	}).collect();
+	let cache = FileHandleCache::new(2);
+	cache.get(&names[0]);
+	cache.get(&names[1]);
+	cache.get(&names[0]);
+	// evicts names[1], used longest ago
+	cache.get(&names[2]);
+	assert_eq!(cache.len(), 2);
+	cache.evict(&names[0]);
+	assert_eq!(cache.len(), 1);
+	cache.evict(&names[2]);
+	assert!(cache.is_empty());
+}
+
+#[test]
+fn test_row_cache_counts_its_own_lookups() {
+	let row_cache = RowCache::create_row_cache(1 << 20);
+	let other = RowCache::create_row_cache(1 << 20);
+	row_cache.insert(&i32_to_bytes(10), &i32_to_bytes(10), row_cache.epoch());
+	other.insert(&i32_to_bytes(10), &i32_to_bytes(10), other.epoch());
+	assert_eq!(row_cache.get(&i32_to_bytes(10)), Some(i32_to_bytes(10)));
+	assert_eq!(row_cache.get(&i32_to_bytes(10)), Some(i32_to_bytes(10)));
+	assert_eq!(row_cache.get(&i32_to_bytes(11)), None);
+	assert_eq!((row_cache.hits(), row_cache.misses()), (2, 1));
+	// the metrics add up the lookups of every cache
+	assert_eq!((other.hits(), other.misses(), other.hit_ratio()), (0, 0, 0.0));
+}
+
+#[test]
+fn test_skiplist_memtable() {
+	// not the default memtable, so trees in the other tests do not cover it
+	let memtable = SkipListMemtable::create_memtable();
+	for i in (0..100).rev() {
+		memtable.put(Record::create_record(i32_to_bytes(i), i32_to_bytes(i), (100 - i) as u64));
+	}
+	memtable.put(Record::create_record(i32_to_bytes(10), i32_to_bytes(-10), 200));
+	assert_eq!(memtable.len(), 101);
+	assert_eq!(memtable.get(&i32_to_bytes(10), 300).map(|record| bytes_to_i32(&record.value)), Some(-10));
+	assert_eq!(memtable.get(&i32_to_bytes(10), 199).map(|record| bytes_to_i32(&record.value)), Some(10));
+	assert!(memtable.get(&i32_to_bytes(100), 300).is_none());
+	let keys: Vec<i32> = memtable.range(&i32_to_bytes(5), &i32_to_bytes(8)).iter().map(|record| bytes_to_i32(&record.key)).collect();
+	assert_eq!(keys, vec![5, 6, 7, 8]);
+	let records = memtable.sorted_records();
+	assert!(records.windows(2).all(|pair| pair[0].key <= pair[1].key));
+}
+
+#[cfg(test)]
+struct ReverseComparator;
+
+#[cfg(test)]
+impl Comparator for ReverseComparator {
+	fn compare(&self, a: &[u8], b: &[u8]) -> KeyOrdering {
+		b.cmp(a)
+	}
+
+	fn name(&self) -> &str {
+		"reverse"
+	}
+}
+
+#[test]
+fn test_tree_comparator() {
+	in_test_dir("tree_comparator", || {
+		let mut options = TreeOptions::create_options();
+		options.comparator = Some(Arc::new(ReverseComparator));
+		let tree = LSMTree::create_lsmtree_with_options(options);
+		fill_tree(&tree, 3000);
+		tree.put(&i32_to_bytes(10), &i32_to_bytes(-10));
+		assert_eq!(get_i32(&tree, 10), Some(-10));
+		assert_eq!(get_i32(&tree, 1500), Some(1500));
+		assert_eq!(get_i32(&tree, 2999), Some(2999));
+		assert_eq!(get_i32(&tree, 3000), None);
+		let keys: Vec<i32> = tree.scan(&i32_to_bytes(20), &i32_to_bytes(10), None).iter().map(|record| bytes_to_i32(&record.key)).collect();
+		assert_eq!(keys, (10..=20).rev().collect::<Vec<i32>>());
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_snapshot_isolation() {
+	in_test_dir("snapshot_isolation", || {
+		let tree = LSMTree::create_lsmtree();
+		fill_tree(&tree, 3000);
+		let snapshot = tree.snapshot();
+		tree.put(&i32_to_bytes(10), &i32_to_bytes(-10));
+		tree.put(&i32_to_bytes(5000), &i32_to_bytes(5000));
+		// the old versions have to survive flushes and compactions while the snapshot lives
+		for i in 10000..40000 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+		}
+
+		let mut value = Vec::new();
+		assert!(tree.get_with_snapshot(&i32_to_bytes(10), &mut value, &snapshot) && bytes_to_i32(&value) == 10);
+		assert!(!tree.get_with_snapshot(&i32_to_bytes(5000), &mut value, &snapshot));
+		assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(5000), Some(&snapshot)).len(), 3000);
+
+		assert_eq!(get_i32(&tree, 10), Some(-10));
+		assert_eq!(get_i32(&tree, 5000), Some(5000));
+		assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(5000), None).len(), 3001);
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_recover_replays_log() {
+	in_test_dir("recover_replays_log", || {
+		let tree = LSMTree::create_lsmtree();
+		// left in the buffer and the log only
+		for i in 0..100 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(-i));
+		}
+		tree.delete(&i32_to_bytes(50));
+		drop(tree);
+
+		// twice, as the first recovery must leave the replayed writes replayable
+		for _ in 0..2 {
+			let tree = LSMTree::recover();
+			assert_eq!(get_i32(&tree, 10), Some(-10));
+			assert_eq!(get_i32(&tree, 50), None);
+			assert_eq!(get_i32(&tree, 100), None);
+		}
+	});
+}
+
+#[test]
+fn test_transaction_write_conflict() {
+	in_test_dir("transaction_write_conflict", || {
+		let tree = LSMTree::create_lsmtree();
+		tree.put(&i32_to_bytes(1), &i32_to_bytes(100));
+		tree.put(&i32_to_bytes(2), &i32_to_bytes(200));
+
+		let mut value = Vec::new();
+		let mut conflicting = tree.begin();
+		assert!(conflicting.get_for_update(&i32_to_bytes(1), &mut value));
+		conflicting.put(&i32_to_bytes(1), &i32_to_bytes(bytes_to_i32(&value) + 1));
+		let mut independent = tree.begin();
+		assert!(independent.get_for_update(&i32_to_bytes(2), &mut value));
+		independent.put(&i32_to_bytes(2), &i32_to_bytes(bytes_to_i32(&value) + 1));
+
+		// written after both transactions began
+		tree.put(&i32_to_bytes(1), &i32_to_bytes(150));
+		assert!(!conflicting.commit());
+		assert!(independent.commit());
+		assert_eq!(get_i32(&tree, 1), Some(150));
+		assert_eq!(get_i32(&tree, 2), Some(201));
+
+		// a transaction sees its own writes
+		let mut transaction = tree.begin();
+		transaction.delete(&i32_to_bytes(2));
+		assert!(!transaction.get_for_update(&i32_to_bytes(2), &mut value));
+		assert!(transaction.commit());
+		assert_eq!(get_i32(&tree, 2), None);
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_merge_operands() {
+	in_test_dir("merge_operands", || {
+		let mut options = TreeOptions::create_options();
+		options.merge_operator = Some(Arc::new(IntAddOperator));
+		let tree = LSMTree::create_lsmtree_with_options(options);
+		fill_tree(&tree, 3000);
+		tree.merge(&i32_to_bytes(7), &i32_to_bytes(10));
+		tree.merge(&i32_to_bytes(5000), &i32_to_bytes(1));
+		tree.flush_buffer_with_guard(tree.buffer.write());
+		tree.merge(&i32_to_bytes(7), &i32_to_bytes(100));
+		tree.merge(&i32_to_bytes(5000), &i32_to_bytes(2));
+		assert_eq!(get_i32(&tree, 7), Some(117));
+		// operands with no value below add to 0
+		assert_eq!(get_i32(&tree, 5000), Some(3));
+		// enough writes to flush and compact the operands
+		for i in 10000..40000 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+		}
+		assert_eq!(get_i32(&tree, 7), Some(117));
+		assert_eq!(get_i32(&tree, 5000), Some(3));
+		tree.delete(&i32_to_bytes(7));
+		tree.merge(&i32_to_bytes(7), &i32_to_bytes(1));
+		assert_eq!(get_i32(&tree, 7), Some(1));
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_ttl_expiry() {
+	in_test_dir("ttl_expiry", || {
+		let tree = LSMTree::create_lsmtree();
+		fill_tree(&tree, 3000);
+		tree.put_with_ttl(&i32_to_bytes(1), &i32_to_bytes(-1), Duration::from_millis(200));
+		tree.put_with_ttl(&i32_to_bytes(2), &i32_to_bytes(-2), Duration::from_secs(3600));
+		assert_eq!(get_i32(&tree, 1), Some(-1));
+		std::thread::sleep(Duration::from_millis(300));
+		// the expired value hides the older one rather than uncovering it
+		assert_eq!(get_i32(&tree, 1), None);
+		assert_eq!(get_i32(&tree, 2), Some(-2));
+		// enough writes to flush and compact the expired value
+		for i in 10000..40000 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+		}
+		assert_eq!(get_i32(&tree, 1), None);
+		assert_eq!(get_i32(&tree, 2), Some(-2));
+		assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(10), None).len(), 10);
+		tree.delete_files();
+	});
+}
+
+// Once enabled, removes odd values and negates positive even ones, so that
+// filtering a value again leaves it as it is
+#[cfg(test)]
+struct ParityFilter {
+	enabled: AtomicBool,
+}
+
+#[cfg(test)]
+impl CompactionFilter for ParityFilter {
+	fn filter(&self, _level: usize, _key: &[u8], value: &[u8]) -> FilterDecision {
+		if !self.enabled.load(Ordering::Acquire) {
+			return FilterDecision::Keep;
+		}
+		match bytes_to_i32(value) {
+			value if value % 2 != 0 => FilterDecision::Remove,
+			value if value > 0 => FilterDecision::ChangeValue(i32_to_bytes(-value)),
+			_ => FilterDecision::Keep,
+		}
+	}
+
+	fn name(&self) -> &str {
+		"parity"
+	}
+}
+
+#[test]
+fn test_compaction_filter_invalidates_row_cache() {
+	in_test_dir("compaction_filter_row_cache", || {
+		let filter = Arc::new(ParityFilter { enabled: AtomicBool::new(false) });
+		let mut options = TreeOptions::create_options();
+		options.compaction_filter = Some(filter.clone());
+		let mut tree = LSMTree::create_lsmtree_with_options(options);
+		tree.row_cache = Some(RowCache::create_row_cache(1 << 20));
+		// fits in the buffer, so the first flush writes it to level 1 unfiltered
+		fill_tree(&tree, 500);
+		tree.flush_buffer_with_guard(tree.buffer.write());
+		// cached from disk
+		assert_eq!(get_i32(&tree, 10), Some(10));
+		assert_eq!(get_i32(&tree, 11), Some(11));
+
+		filter.enabled.store(true, Ordering::Release);
+		// enough writes to compact level 1
+		for i in 10000..40000 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+		}
+		assert_eq!(get_i32(&tree, 10), Some(-10));
+		assert_eq!(get_i32(&tree, 11), None);
+		assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(499), None).len(), 250);
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_range_tombstone_across_flush() {
+	in_test_dir("range_tombstone_across_flush", || {
+		let tree = LSMTree::create_lsmtree();
+		fill_tree(&tree, 3000);
+		tree.delete_range(&i32_to_bytes(100), &i32_to_bytes(199));
+		// newer than the tombstone
+		tree.put(&i32_to_bytes(150), &i32_to_bytes(-150));
+
+		let check = |tree: &LSMTree| {
+			assert_eq!(get_i32(tree, 99), Some(99));
+			assert_eq!(get_i32(tree, 100), None);
+			assert_eq!(get_i32(tree, 150), Some(-150));
+			assert_eq!(get_i32(tree, 199), None);
+			assert_eq!(get_i32(tree, 200), Some(200));
+			assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(2999), None).len(), 2901);
+		};
+		check(&tree);
+		// the tombstone now sits in a file above the keys it hides
+		tree.flush_buffer_with_guard(tree.buffer.write());
+		check(&tree);
+		// enough writes to compact the tombstone
+		for i in 10000..40000 {
+			tree.put(&i32_to_bytes(i), &i32_to_bytes(i));
+		}
+		check(&tree);
+		tree.delete_files();
+	});
+}
+
+#[test]
+fn test_drop_obsolete_file_removed_already() {
+	let dir = test_dir("drop_obsolete_file");
+	fs::create_dir_all(&dir).unwrap();
+	let records: Vec<Record> = (0..100).map(|i| Record::create_record(i32_to_bytes(i), i32_to_bytes(i), 1)).collect();
+	let file = DiskFile::create_disk_file(generate_filename(&dir, 1, 0, 0), 1, &records);
+	file.mark_obsolete();
+	fs::remove_file(&file.filename).unwrap();
+	drop(file);
+}
+
+#[test]
+fn test_backup_of_recreated_tree() {
+	let dir = test_dir("backup_of_recreated_tree");
+	let backup_dir = test_dir("backup_of_recreated_tree_backup");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	let report = tree.backup(&backup_dir);
+	assert!(report.files_copied > 0);
+	assert_eq!(tree.backup(&backup_dir).files_copied, 0);
+	// the checksums backups go by are those of the footers
+	for name in table_filenames(&dir) {
+		let filename = Path::new(&dir).join(name).to_string_lossy().to_string();
+		let file = DiskFile::open_disk_file(filename.clone());
+		assert_eq!(file.checksum, crc32fast::hash(&fs::read(&filename).unwrap()[..file.disk_size]));
+	}
+	drop(tree);
+
+	// the same names and sizes as before, but other values
+	fs::remove_dir_all(&dir).unwrap();
+	let tree = LSMTree::open(&dir);
+	for i in 0..3000 {
+		tree.put(&i32_to_bytes(i), &i32_to_bytes(-i));
+	}
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	let report = tree.backup(&backup_dir);
+	assert_eq!(report.files_skipped, 0);
+	drop(tree);
+	let backup = LSMTree::open(&backup_dir);
+	assert_eq!(get_i32(&backup, 10), Some(-10));
+}
+
+#[cfg(test)]
+fn write_sst(dir: &str, keys: std::ops::Range<i32>, value: i32) -> Vec<String> {
+	let mut writer = SstWriter::create_writer(dir);
+	for i in keys {
+		writer.put(&i32_to_bytes(i), &i32_to_bytes(value));
+	}
+	writer.finish()
+}
+
+#[test]
+fn test_ingest_overlapping_files() {
+	let dir = test_dir("ingest_overlapping_files");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	tree.put(&i32_to_bytes(1500), &i32_to_bytes(7));
+
+	let sst_dir = test_dir("ingest_overlapping_files_sst");
+	let files = write_sst(&sst_dir, 1000..2000, -1);
+	let contents: Vec<Vec<u8>> = files.iter().map(|file| fs::read(file).unwrap()).collect();
+	tree.ingest(&files);
+	assert_eq!(get_i32(&tree, 999), Some(999));
+	assert_eq!(get_i32(&tree, 1000), Some(-1));
+	// newer than the write left in the buffer
+	assert_eq!(get_i32(&tree, 1500), Some(-1));
+	assert_eq!(get_i32(&tree, 2000), Some(2000));
+	// the caller's files are left as they were
+	for (file, content) in files.iter().zip(contents.iter()) {
+		assert_eq!(&fs::read(file).unwrap(), content);
+	}
+
+	let moved = write_sst(&sst_dir, 1500..2500, -2);
+	tree.ingest_with_options(&moved, &IngestOptions { move_files: true });
+	assert!(moved.iter().all(|file| !Path::new(file).exists()));
+	drop(tree);
+	let tree = LSMTree::open(&dir);
+	assert_eq!(get_i32(&tree, 1000), Some(-1));
+	assert_eq!(get_i32(&tree, 1500), Some(-2));
+	assert_eq!(get_i32(&tree, 2999), Some(2999));
+}
+
+#[test]
+fn test_ingest_keeps_runs_per_level() {
+	let dir = test_dir("ingest_keeps_runs_per_level");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	let sst_dir = test_dir("ingest_keeps_runs_per_level_sst");
+	// past every key in the tree, so each goes to the last level
+	for i in 0..4 {
+		tree.ingest(&write_sst(&sst_dir, 3000 + i * 10..3010 + i * 10, i));
+		for level in tree.levels.read().iter() {
+			let num_runs = level.runs.read().len();
+			assert!(num_runs <= CONFIGURATION.RUNS_PER_LEVEL, "level {} has {} runs", level.level, num_runs);
+		}
+	}
+	for i in 0..4 {
+		assert_eq!(get_i32(&tree, 3005 + i * 10), Some(i));
+	}
+	assert_eq!(get_i32(&tree, 10), Some(10));
+}
+
+#[test]
+fn test_recreated_tree_reads_new_files() {
+	let dir = test_dir("recreated_tree_reads_new_files");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	// leaves the handles of the files open in the cache
+	assert_eq!(get_i32(&tree, 10), Some(10));
+	drop(tree);
+
+	fs::remove_dir_all(&dir).unwrap();
+	let tree = LSMTree::open(&dir);
+	for i in 0..3000 {
+		tree.put(&i32_to_bytes(i), &i32_to_bytes(-i));
+	}
+	// written under the same names as before
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	assert_eq!(get_i32(&tree, 10), Some(-10));
+}
+
+#[test]
+fn test_open_without_manifest_keeps_table_files() {
+	let dir = test_dir("open_without_manifest");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 5000);
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	drop(tree);
+	let names = table_filenames(&dir);
+	assert!(!names.is_empty());
+
+	fs::remove_file(Path::new(&dir).join(MANIFEST_FILENAME)).unwrap();
+	assert!(LSMTree::try_open(&dir).is_err());
+	assert_eq!(table_filenames(&dir), names);
+}
+
+#[test]
+fn test_open_with_damaged_manifest_keeps_table_files() {
+	let dir = test_dir("open_with_damaged_manifest");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 5000);
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	drop(tree);
+	let names = table_filenames(&dir);
+	let manifest = Path::new(&dir).join(MANIFEST_FILENAME);
+	let text = fs::read_to_string(&manifest).unwrap();
+	// torn in the middle of a line
+	fs::write(&manifest, &text[..text.len() - 3]).unwrap();
+	assert!(LSMTree::try_open(&dir).is_err());
+	assert_eq!(table_filenames(&dir), names);
+}
+
+#[cfg(test)]
+fn corrupt_byte(filename: &str, offset: u64) {
+	let file = fs::OpenOptions::new().read(true).write(true).open(filename).unwrap();
+	let mut byte = [0u8; 1];
+	file.read_exact_at(&mut byte, offset).unwrap();
+	file.write_all_at(&[byte[0] ^ 0xff], offset).unwrap();
+}
+
+#[test]
+fn test_repair_after_deleting_manifest() {
+	let dir = test_dir("repair_after_deleting_manifest");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	tree.delete(&i32_to_bytes(20));
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	drop(tree);
+
+	fs::remove_file(Path::new(&dir).join(MANIFEST_FILENAME)).unwrap();
+	assert!(LSMTree::try_open(&dir).is_err());
+	let report = repair(&dir);
+	assert!(report.lost.is_empty());
+	assert!(report.files_recovered > 0);
+	let tree = LSMTree::open(&dir);
+	assert_eq!(get_i32(&tree, 10), Some(10));
+	assert_eq!(get_i32(&tree, 20), None);
+	assert_eq!(get_i32(&tree, 2999), Some(2999));
+}
+
+#[test]
+fn test_corrupt_block_detected() {
+	let dir = test_dir("corrupt_block_detected");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 3000);
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	let file = tree.levels.read()[0].runs.read()[0].files[0].clone();
+	// inside the first data block of the file
+	corrupt_byte(&file.filename, 10);
+	let mut value = Vec::new();
+	let error = tree.try_get(&file.first_key, &mut value).unwrap_err();
+	assert!(error.contains("repair"), "{}", error);
+	drop(file);
+	// only in the log
+	tree.put(&i32_to_bytes(5000), &i32_to_bytes(5000));
+	drop(tree);
+
+	let error = LSMTree::try_open(&dir).err().unwrap();
+	assert!(error.contains("repair"), "{}", error);
+	let report = repair(&dir);
+	assert_eq!(report.lost.len(), 1);
+	let tree = LSMTree::open(&dir);
+	assert_eq!(get_i32(&tree, 5000), Some(5000));
+}
+
+#[test]
+fn test_configuration_from_flags() {
+	let matches = lib_cli::app().get_matches_from(vec!["hybrid_de", "stats", "dir", "--size-ratio", "6", "--runs-per-level", "2"]);
+	let configuration = Configuration::from_matches(&matches);
+	assert_eq!(configuration.RUNS_PER_LEVEL, 2);
+	assert_eq!(configuration.T_OVER_K, 3.0);
+	// flags left out keep their defaults
+	assert_eq!(configuration.FULL_THRESHOLD, Configuration::default().FULL_THRESHOLD);
+	let default = Configuration::default();
+	assert_eq!(default.T_OVER_K, default.SIZE_RATIO as f64 / default.RUNS_PER_LEVEL as f64);
+	// the optional features are off unless their flags turn them on
+	assert!(!default.WAL_ENABLED && !default.INT_BLOCK_ENCODING && default.COMPRESSION == vec![CompressionType::NONE]);
+	let matches = lib_cli::app().get_matches_from(vec!["hybrid_de", "stats", "dir", "--wal", "true", "--int-block-encoding", "true", "--compression", "none,lz4,deflate"]);
+	let configuration = Configuration::from_matches(&matches);
+	assert!(configuration.WAL_ENABLED && configuration.INT_BLOCK_ENCODING);
+	assert_eq!(configuration.COMPRESSION, vec![CompressionType::NONE, CompressionType::LZ4, CompressionType::DEFLATE]);
+	assert!(lib_cli::app().get_matches_from_safe(vec!["hybrid_de", "stats", "dir", "--runs-per-level", "two"]).is_err());
+}
+
+#[test]
+#[should_panic(expected = "Merge requires a merge operator")]
+fn test_merge_without_merge_operator() {
+	let dir = test_dir("merge_without_merge_operator");
+	let tree = LSMTree::open(&dir);
+	tree.merge(&i32_to_bytes(7), &i32_to_bytes(10));
+}
+
+#[test]
+fn test_row_cache_with_merge_operands_across_flush() {
+	let dir = test_dir("row_cache_merge_operands_flush");
+	let mut options = TreeOptions::create_options();
+	options.merge_operator = Some(Arc::new(IntAddOperator));
+	let mut tree = LSMTree::open_with_options(&dir, options);
+	tree.row_cache = Some(RowCache::create_row_cache(1 << 20));
+	fill_tree(&tree, 100);
+	tree.flush();
+	tree.merge(&i32_to_bytes(7), &i32_to_bytes(10));
+	assert_eq!(get_i32(&tree, 7), Some(17));
+	// the flush folds the operand into the value on disk
+	tree.flush();
+	assert_eq!(get_i32(&tree, 7), Some(17));
+}
+
+#[test]
+fn test_row_cache_with_concurrent_writes_across_flush() {
+	let dir = test_dir("row_cache_concurrent_writes_flush");
+	let mut tree = LSMTree::open(&dir);
+	tree.row_cache = Some(RowCache::create_row_cache(1 << 20));
+	fill_tree(&tree, 500);
+	tree.flush();
+	let tree = Arc::new(tree);
+	let done = Arc::new(AtomicBool::new(false));
+	let readers: Vec<_> = (0..4).map(|_| {
+		let tree = tree.clone();
+		let done = done.clone();
+		std::thread::spawn(move || {
+			while !done.load(Ordering::Acquire) {
+				for key in 0..500 {
+					get_i32(&tree, key);
+				}
+			}
+		})
+	}).collect();
+	for round in 1..=20 {
+		for key in 0..500 {
+			tree.put(&i32_to_bytes(key), &i32_to_bytes(key + round * 1000));
+		}
+		// reads racing the writes must not have cached the values they replaced
+		tree.flush();
+		for key in 0..500 {
+			assert_eq!(get_i32(&tree, key), Some(key + round * 1000));
+		}
+	}
+	done.store(true, Ordering::Release);
+	for reader in readers {
+		reader.join().unwrap();
+	}
+}
+
+#[test]
+fn test_direct_io_round_trip() {
+	let dir = test_dir("direct_io_round_trip");
+	fs::create_dir_all(&dir).unwrap();
+	let filename = Path::new(&dir).join("direct").to_string_lossy().to_string();
+	// not a whole number of aligned blocks
+	let data: Vec<u8> = (0..2 * DIRECT_IO_ALIGNMENT + 100).map(|i| (i % 251) as u8).collect();
+	write_direct(&filename, &data);
+	assert_eq!(fs::read(&filename).unwrap(), data);
+
+	let file = open_direct(&filename);
+	let mut buffer = vec![0; 300];
+	// across an aligned boundary
+	let start = DIRECT_IO_ALIGNMENT - 100;
+	assert_eq!(read_direct(&file, start, &mut buffer), 300);
+	assert_eq!(buffer, &data[start..start + 300]);
+	// only the bytes before the end of the file
+	let start = data.len() - 50;
+	assert_eq!(read_direct(&file, start, &mut buffer), 50);
+	assert_eq!(&buffer[..50], &data[start..]);
+	assert_eq!(read_direct(&file, data.len() + 10, &mut buffer), 0);
+}
+
+#[test]
+fn test_skiplist_memtable_concurrent_puts() {
+	let memtable = Arc::new(SkipListMemtable::create_memtable());
+	let writers: Vec<_> = (0..4).map(|writer| {
+		let memtable = memtable.clone();
+		std::thread::spawn(move || {
+			// every writer puts a version of every key, interleaved with the others
+			for i in 0..1000 {
+				let seq = (i * 4 + writer + 1) as u64;
+				memtable.put(Record::create_record(i32_to_bytes(i), i32_to_bytes(writer), seq));
+			}
+		})
+	}).collect();
+	for writer in writers {
+		writer.join().unwrap();
+	}
+	assert_eq!(memtable.len(), 4000);
+	let records = memtable.sorted_records();
+	assert_eq!(records.len(), 4000);
+	// by key, then newest version first
+	assert!(records.windows(2).all(|pair| pair[0] < pair[1]));
+	assert_eq!(memtable.get(&i32_to_bytes(10), u64::MAX).map(|record| bytes_to_i32(&record.value)), Some(3));
+}
+
+#[test]
+fn test_buffer_merge_interleaves_range_tombstones() {
+	let buffer = MemoryBuffer::create_buffer();
+	for i in 0..100 {
+		buffer.put(Record::create_record(i32_to_bytes(i), i32_to_bytes(i), i as u64 + 1));
+	}
+	buffer.put(Record::create_range_tombstone(i32_to_bytes(50), i32_to_bytes(60), 200));
+	buffer.put(Record::create_range_tombstone(i32_to_bytes(10), i32_to_bytes(20), 201));
+	let records = buffer.merge();
+	assert_eq!(records.len(), 102);
+	assert!(records.windows(2).all(|pair| pair[0] < pair[1]));
+	// newer than the record of its start key, so before it
+	assert!(records[10].is_range_deletion() && records[10].key == i32_to_bytes(10));
+}
+
+#[test]
+fn test_block_search_over_restart_points() {
+	// string keys sharing most of their bytes, so the prefix layout rather than the bit-packed one
+	let key = |i: usize| format!("user:{:06}", i).into_bytes();
+	let mut records = Vec::new();
+	for i in 0..100 {
+		records.push(Record::create_record(key(i * 2), i.to_string().into_bytes(), 1000 + i as u64));
+		// an older version of every tenth key
+		if i % 10 == 0 {
+			records.push(Record::create_record(key(i * 2), b"old".to_vec(), i as u64 + 1));
+		}
+	}
+	let mut builder = BlockBuilder::new();
+	for record in records.iter() {
+		builder.add(record);
+	}
+	let block = builder.finish();
+	assert!(records.len() > 2 * CONFIGURATION.BLOCK_RESTART_INTERVAL);
+	let decoded = decode_block(&block);
+	assert!(decoded.iter().zip(records.iter()).all(|(a, b)| a.key == b.key && a.value == b.value && a.seq == b.seq));
+	assert_eq!(decoded.len(), records.len());
+
+	for i in 0..100 {
+		assert_eq!(search_block(&block, &key(i * 2), u64::MAX).unwrap().value, i.to_string().into_bytes());
+		// between two keys of the block
+		assert!(search_block(&block, &key(i * 2 + 1), u64::MAX).is_none());
+	}
+	assert_eq!(search_block(&block, &key(20), 500).unwrap().value, b"old".to_vec());
+	assert!(search_block(&block, &key(20), 0).is_none());
+	// before the first key and after the last one
+	assert!(search_block(&block, b"user:", u64::MAX).is_none());
+	assert!(search_block(&block, b"user:999999", u64::MAX).is_none());
+}
+
+#[test]
+fn test_disk_file_blocks_hold_whole_records() {
+	let dir = test_dir("disk_file_blocks_hold_whole_records");
+	fs::create_dir_all(&dir).unwrap();
+	// values of varying sizes, and versions of some keys that could straddle a block boundary
+	let mut records = Vec::new();
+	for i in 0..2000 {
+		let versions = if i % 50 == 0 { 3 } else { 1 };
+		for version in 0..versions {
+			records.push(Record::create_record(format!("key{:08}", i).into_bytes(), vec![b'v'; i % 97], 10 - version));
+		}
+	}
+	let file = DiskFile::create_disk_file(generate_filename(&dir, 1, 0, 0), 1, &records);
+	assert!(file.fence_pointers.len() > 1);
+	let mut read: Vec<Record> = Vec::new();
+	for block_idx in 0..file.fence_pointers.len() {
+		let raw_len = decompress_block(&file.read_block_bytes(block_idx)).unwrap().len();
+		let block = file.read_block(block_idx);
+		// only to keep the versions of its last key with it does a block run over
+		let last_key_versions = block.iter().filter(|record| record.key == block.last().unwrap().key).count();
+		assert!(raw_len <= CONFIGURATION.BLOCK_SIZE || last_key_versions > 1);
+		assert_eq!(block[0].key, file.fence_pointers[block_idx]);
+		// the versions of a key stay together
+		if let Some(last) = read.last() {
+			assert!(last.key != block[0].key);
+		}
+		read.extend(block);
+	}
+	assert_eq!(read.len(), records.len());
+	assert!(read.iter().zip(records.iter()).all(|(a, b)| a.key == b.key && a.value == b.value && a.seq == b.seq));
+}
+
+#[test]
+fn test_block_compression_round_trip() {
+	// compressible, so every codec keeps its own output
+	let block: Vec<u8> = (0..4096).map(|i| (i % 16) as u8).collect();
+	for id in 0..3 {
+		let compressed = compress_block(&block, compressor_by_id(id).unwrap());
+		assert_eq!(block_compressor(&compressed).unwrap().id(), id);
+		assert_eq!(&decompress_block(&compressed).unwrap()[..], &block[..]);
+	}
+	// stored as it is when the codec does not shrink it
+	let mut rng = rand::thread_rng();
+	let random: Vec<u8> = (0..4096).map(|_| rng.gen()).collect();
+	let compressed = compress_block(&random, compressor_by_id(2).unwrap());
+	assert_eq!(block_compressor(&compressed).unwrap().id(), 0);
+	assert_eq!(&decompress_block(&compressed).unwrap()[..], &random[..]);
+	assert!(compressor_by_id(3).is_none());
+}
+
+#[test]
+fn test_compression_ratio_per_level() {
+	let dir = test_dir("compression_ratio_per_level");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 40000);
+	let levels = tree.levels.read();
+	assert!(levels.len() > 1);
+	for level in levels.iter() {
+		let files: Vec<Arc<DiskFile>> = level.runs.read().iter().flat_map(|run| run.files.iter().cloned()).collect();
+		if files.is_empty() {
+			assert_eq!(level.compression_ratio(), 1.0);
+			continue;
+		}
+		let uncompressed_size: usize = files.iter().map(|file| file.uncompressed_size).sum();
+		let disk_size: usize = files.iter().map(|file| file.disk_size).sum();
+		assert_eq!(level.compression_ratio(), uncompressed_size as f64 / disk_size as f64);
+		// blocks the level's codec does not shrink are stored uncompressed
+		let codec = block_compressor(&files[0].read_block_bytes(0)).unwrap().id();
+		assert!(codec == compressor_for_level(level.level).id() || codec == 0);
+	}
+}
+
+// Encodes the i32 keys and values, in order, as an int block, checks it decodes
+// back and that every key is found in it
+#[cfg(test)]
+fn check_int_block(entries: &[(i32, i32, u64)]) -> Vec<u8> {
+	let keys: Vec<u32> = entries.iter().map(|entry| to_u32(&i32_to_bytes(entry.0))).collect();
+	let values: Vec<u32> = entries.iter().map(|entry| to_u32(&i32_to_bytes(entry.1))).collect();
+	let seqs: Vec<u64> = entries.iter().map(|entry| entry.2).collect();
+	let block = encode_int_block(&keys, &values, &seqs);
+	let decoded = decode_int_block(&block);
+	assert_eq!(decoded.len(), entries.len());
+	for (record, entry) in decoded.iter().zip(entries.iter()) {
+		assert_eq!((bytes_to_i32(&record.key), bytes_to_i32(&record.value), record.seq), *entry);
+		let found = search_int_block(&block, &record.key, u64::MAX).unwrap();
+		assert_eq!(found.key, record.key);
+	}
+	block
+}
+
+#[test]
+fn test_int_block_dense_and_sparse_keys() {
+	// dense, with two versions of 0
+	let mut dense: Vec<(i32, i32, u64)> = (-500..500).map(|i| (i, -i, 10)).collect();
+	dense.insert(501, (0, 7, 5));
+	let block = check_int_block(&dense);
+	assert_eq!(bytes_to_i32(&search_int_block(&block, &i32_to_bytes(0), 10).unwrap().value), 0);
+	assert_eq!(bytes_to_i32(&search_int_block(&block, &i32_to_bytes(0), 9).unwrap().value), 7);
+	assert!(search_int_block(&block, &i32_to_bytes(0), 4).is_none());
+	assert!(search_int_block(&block, &i32_to_bytes(-501), u64::MAX).is_none());
+	assert!(search_int_block(&block, &i32_to_bytes(500), u64::MAX).is_none());
+
+	// sparse, across the whole range of keys and values
+	let sparse = vec![
+		(i32::MIN, i32::MAX, 1),
+		(-1_000_000_000, 0, u64::MAX),
+		(0, i32::MIN, 3),
+		(1_000_000_000, -1, 4),
+		(i32::MAX, i32::MIN, 5),
+	];
+	let block = check_int_block(&sparse);
+	assert!(search_int_block(&block, &i32_to_bytes(1), u64::MAX).is_none());
+	assert!(search_int_block(&block, &i32_to_bytes(i32::MAX - 1), u64::MAX).is_none());
+	// keys are 4 bytes wide
+	assert!(search_int_block(&block, &[0, 0], u64::MAX).is_none());
+
+	// a single key
+	check_int_block(&[(i32::MAX, i32::MAX, 0)]);
+}
+
+#[test]
+fn test_range_tombstone_bounded_by_file_key_range() {
+	let dir = test_dir("range_tombstone_key_range");
+	fs::create_dir_all(&dir).unwrap();
+	let mut records: Vec<Record> = (0..100).map(|i| Record::create_record(i32_to_bytes(i), i32_to_bytes(i), 1)).collect();
+	records.push(Record::create_range_tombstone(i32_to_bytes(50), i32_to_bytes(150), 10));
+	records.sort();
+	let first = DiskFile::create_disk_file(generate_filename(&dir, 1, 0, 0), 1, &records);
+	// the key range takes in the keys the tombstone reaches past the last record
+	assert_eq!(first.last_key, i32_to_bytes(150));
+	assert_eq!(DiskFile::open_disk_file(first.filename.clone()).last_key, i32_to_bytes(150));
+	let records: Vec<Record> = (200..300).map(|i| Record::create_record(i32_to_bytes(i), i32_to_bytes(i), 1)).collect();
+	let second = DiskFile::create_disk_file(generate_filename(&dir, 1, 0, 1), 1, &records);
+	let size = first.size + second.size;
+	let run = Run::create_run_from_files(size, size, vec![Arc::new(first), Arc::new(second)], 1, 0, &dir);
+	assert_eq!(run.range_tombstone_seq(&i32_to_bytes(120), u64::MAX), 10);
+	assert_eq!(run.range_tombstone_seq(&i32_to_bytes(120), 9), 0);
+	assert_eq!(run.range_tombstone_seq(&i32_to_bytes(49), u64::MAX), 0);
+	assert_eq!(run.range_tombstone_seq(&i32_to_bytes(250), u64::MAX), 0);
+	assert!(run.overlaps(&i32_to_bytes(140), &i32_to_bytes(160)));
+}
+
+#[test]
+fn test_checkpoint_while_writing() {
+	let dir = test_dir("checkpoint_while_writing");
+	let checkpoint_dir = test_dir("checkpoint_while_writing_checkpoint");
+	let tree = Arc::new(LSMTree::open(&dir));
+	fill_tree(&tree, 3000);
+	let done = Arc::new(AtomicBool::new(false));
+	let writer = {
+		let tree = tree.clone();
+		let done = done.clone();
+		std::thread::spawn(move || {
+			let mut key = 100000;
+			while !done.load(Ordering::Acquire) {
+				tree.put(&i32_to_bytes(key), &i32_to_bytes(-key));
+				key += 1;
+			}
+			key - 100000
+		})
+	};
+	while get_i32(&tree, 101000).is_none() {
+		std::thread::yield_now();
+	}
+	tree.checkpoint(&checkpoint_dir);
+	done.store(true, Ordering::Release);
+	let written = writer.join().unwrap();
+
+	let checkpoint = LSMTree::open(&checkpoint_dir);
+	for key in 0..3000 {
+		assert_eq!(get_i32(&checkpoint, key), Some(key));
+	}
+	// the writes up to the checkpoint, in the order they were made, and none after
+	let taken = (0..written).take_while(|i| get_i32(&checkpoint, 100000 + i) == Some(-100000 - i)).count() as i32;
+	assert!(taken > 1000);
+	assert!((taken..written).all(|i| get_i32(&checkpoint, 100000 + i).is_none()));
+	// the checkpoint is a tree of its own
+	checkpoint.put(&i32_to_bytes(7), &i32_to_bytes(-7));
+	assert_eq!(get_i32(&tree, 7), Some(7));
+}
+
+#[test]
+fn test_open_with_truncated_manifest() {
+	let dir = test_dir("open_with_truncated_manifest");
+	let tree = LSMTree::open(&dir);
+	fill_tree(&tree, 5000);
+	tree.flush_buffer_with_guard(tree.buffer.write());
+	drop(tree);
+	let manifest = Path::new(&dir).join(MANIFEST_FILENAME);
+	let text = fs::read_to_string(&manifest).unwrap();
+	// whole lines lost, every line left still reads
+	let lines: Vec<&str> = text.lines().collect();
+	assert!(lines.len() > 3);
+	fs::write(&manifest, lines[..lines.len() - 2].join("\n") + "\n").unwrap();
+	let message = LSMTree::try_open(&dir).err().unwrap();
+	assert!(message.contains("repair"));
+	let report = repair(&dir);
+	assert!(report.lost.is_empty());
+	let tree = LSMTree::open(&dir);
+	assert_eq!(get_i32(&tree, 10), Some(10));
+	assert_eq!(get_i32(&tree, 4999), Some(4999));
+}
+
+#[test]
+fn test_table_dump_of_clean_and_damaged_file() {
+	let dir = test_dir("table_dump");
+	fs::create_dir_all(&dir).unwrap();
+	let records: Vec<Record> = (0..2000).map(|i| Record::create_record(format!("key{:08}", i).into_bytes(), vec![b'v'; 16], 1)).collect();
+	let file = DiskFile::create_disk_file(generate_filename(&dir, 1, 0, 0), 1, &records);
+	assert!(file.block_offsets.len() > 2);
+
+	let dump = TableDump::read_table(&file.filename).unwrap();
+	assert_eq!(dump.damaged_blocks(), 0);
+	assert_eq!(dump.data_blocks().count(), file.block_offsets.len());
+	assert_eq!(dump.footer_size, dump.file_size - file.disk_size);
+	assert_eq!(dump.bloom_filter_keys(), Some(2000));
+	assert!(dump.records().iter().zip(records.iter()).all(|(a, b)| a.key == b.key && a.value == b.value && a.seq == b.seq));
+	dump.print_text(true, true);
+
+	// a damaged block is reported, the others still read
+	let damaged_records = file.read_block(1).len();
+	corrupt_byte(&file.filename, file.block_offsets[1] as u64 + 1);
+	let dump = TableDump::read_table(&file.filename).unwrap();
+	assert_eq!(dump.damaged_blocks(), 1);
+	assert!(!dump.blocks[1].checksum_ok);
+	assert!(dump.blocks[1].records.is_empty());
+	assert_eq!(dump.bloom_filter_keys(), None);
+	assert_eq!(dump.records().len(), 2000 - damaged_records);
+	dump.print_text(true, true);
+	dump.print_json(true, true);
+
+	// without a footer the blocks cannot be found
+	fs::OpenOptions::new().write(true).open(&file.filename).unwrap().set_len(file.disk_size as u64).unwrap();
+	assert!(TableDump::read_table(&file.filename).is_none());
+}
+
+#[test]
+fn test_shell_script() {
+	let dir = test_dir("shell_script");
+	let tree = LSMTree::open_with_options(&dir, lib_helper::workload_options());
+	let script = "put 1 10\nput 2 20\np 3 30\n\nmerge 1 5\ndelete 2\nget 1\nscan 0 10\nscan 0 10 x\nfrobnicate 1\nget\nflush\nput 4 40\nquit\nput 5 50\n";
+	run_shell(&tree, Cursor::new(script), false, true);
+	assert_eq!(get_i32(&tree, 1), Some(15));
+	assert_eq!(get_i32(&tree, 2), None);
+	assert_eq!(get_i32(&tree, 3), Some(30));
+	// errors are reported and the script goes on, up to quit
+	assert_eq!(get_i32(&tree, 4), Some(40));
+	assert_eq!(get_i32(&tree, 5), None);
+	assert_eq!(tree.scan(&i32_to_bytes(0), &i32_to_bytes(10), None).len(), 3);
+}
+
+#[test]
+fn test_layout_of_two_level_tree() {
+	let dir = test_dir("layout_two_levels");
+	let tree = LSMTree::open(&dir);
+	assert_eq!(tree.layout().to_ascii(40, true).lines().count(), 1);
+	fill_tree(&tree, 20000);
+	tree.flush();
+	let layout = tree.layout();
+	let with_files = layout.levels.iter().filter(|level| level.runs.iter().any(|run| !run.files.is_empty())).count();
+	assert!(with_files >= 2);
+	let files: usize = layout.levels.iter().flat_map(|level| level.runs.iter()).map(|run| run.files.len()).sum();
+	let runs: usize = layout.levels.iter().map(|level| level.runs.len()).sum();
+	let (lower, upper) = layout.key_range().unwrap();
+	assert_eq!((bytes_to_i32(lower), bytes_to_i32(upper)), (0, 19999));
+
+	let json = layout.to_json(true);
+	assert!(json.starts_with(&format!("{{\"last_seq\":{},", layout.last_seq)));
+	assert!(json.contains("\"level\":1,") && json.contains("\"level\":2,"));
+	assert_eq!(json.matches("\"name\":").count(), files);
+	assert_eq!(json.matches("\"run\":").count(), runs);
+	assert_eq!(json.matches('{').count(), json.matches('}').count());
+	assert!(json.contains("\"first_key\":\"0\""));
+
+	// the buffer, each level and its runs, then the key range
+	let ascii = layout.to_ascii(40, true);
+	let lines: Vec<&str> = ascii.lines().collect();
+	assert_eq!(lines.len(), 2 + layout.levels.len() + runs);
+	assert!(lines[0].starts_with("buffer"));
+	for line in lines.iter().filter(|line| line.trim_start().starts_with("run")) {
+		let start = line.find('|').unwrap();
+		let end = line.rfind('|').unwrap();
+		assert_eq!(end - start - 1, 40);
+	}
+	let labels: Vec<&str> = lines.last().unwrap().split_whitespace().collect();
+	assert_eq!(labels, vec!["0", "19999"]);
+}
+
 //#[test]
 // can't be run from cargo test (times out)
 pub fn test_lsm_tree(bulkwrite_file: &String, workload_file: &String, pool: ThreadPool) {
@@ -1,9 +1,25 @@
 use lazy_static::lazy_static;
-use prometheus::{self, IntCounter, register_int_counter};
+use prometheus::{self, IntCounter, IntCounterVec, IntGauge, register_int_counter, register_int_counter_vec, register_int_gauge};
 
 lazy_static! {
     pub static ref GET_IO_COUNTER: IntCounter =
         register_int_counter!("getIOs", "Number of get disk IOs (by page)").unwrap();
     pub static ref PUT_IO_COUNTER: IntCounter =
         register_int_counter!("putIOs", "Number of get disk IOs (by page)").unwrap();
-}
+    pub static ref ROW_CACHE_HITS: IntCounter =
+        register_int_counter!("rowCacheHits", "Number of point lookups answered by the row cache").unwrap();
+    pub static ref ROW_CACHE_MISSES: IntCounter =
+        register_int_counter!("rowCacheMisses", "Number of point lookups that missed the row cache").unwrap();
+    pub static ref ROW_CACHE_CAPACITY: IntGauge =
+        register_int_gauge!("rowCacheCapacityBytes", "Memory budget of the row cache (bytes)").unwrap();
+    pub static ref ROW_CACHE_USAGE: IntGauge =
+        register_int_gauge!("rowCacheUsageBytes", "Memory currently charged to the row cache (bytes)").unwrap();
+    pub static ref BLOCK_BYTES_UNCOMPRESSED: IntCounterVec =
+        register_int_counter_vec!("blockBytesUncompressed", "Bytes of data blocks written before compression, by level", &["level"]).unwrap();
+    pub static ref BLOCK_BYTES_COMPRESSED: IntCounterVec =
+        register_int_counter_vec!("blockBytesCompressed", "Bytes of data blocks written after compression, by level", &["level"]).unwrap();
+    pub static ref COMPACTION_FILTER_DECISIONS: IntCounterVec =
+        register_int_counter_vec!("compactionFilterDecisions", "Values the compaction filter kept, removed or changed, by decision", &["decision"]).unwrap();
+    pub static ref COMPACTION_FILES_SKIPPED: IntCounter =
+        register_int_counter!("compactionFilesSkipped", "Number of files a compaction dropped unread because a range tombstone covers them").unwrap();
+}
\ No newline at end of file